@@ -0,0 +1,454 @@
+// Batch mode: many object files, or every function symbol of them, analyzed on a thread pool.
+// Each analysis keeps its state in thread locals (architecture, timing model, output), its output is
+// collected and printed in the order of the inputs, so the report doesn't depend on the number of threads.
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::Write;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::{Component, Path, PathBuf};
+
+use object::{Object, ObjectSection, ObjectSymbol, SectionIndex, SectionKind, SymbolKind};
+use rayon::prelude::*;
+
+use crate::incremental::ADDRESS_PREFIXES;
+use crate::printwarning;
+
+// env variable format: BATCH_INPUTS=file.o,dir (comma-separated object files or directories of .o files)
+// BATCH_FUNCTIONS=true analyzes every function symbol of the inputs from its own entry
+// BATCH_THREADS=n threads of the pool (default: one for each cpu)
+// BATCH_OUTPUT_DIR=dir directory of the debug files of every analysis and of the report (default: batch)
+
+const DEFAULT_OUTPUT_DIR: &str = "batch";
+
+thread_local! {
+    // the output of the current analysis in batch mode, printed directly otherwise
+    static OUTPUT: RefCell<Option<String>> = const { RefCell::new(None) };
+    // the directory of the debug files of the current analysis
+    static OUTPUT_DIR: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+    // the warnings, errors and debug files of the current analysis while they are recorded, for the analysis cache
+    static RECORDING: RefCell<Option<Recording>> = const { RefCell::new(None) };
+}
+
+#[macro_export]
+macro_rules! printline {
+    ($($arg:tt)*) => {
+        $crate::batch::write_output(&format!("{}\n", format_args!($($arg)*)))
+    };
+}
+
+pub fn write_output(text: &str) {
+    if text.starts_with("WARNING: ") || text.starts_with("ERROR: ") {
+        RECORDING.with(|recording| {
+            if let Some(recording) = recording.borrow_mut().as_mut() {
+                recording.diagnostics.push(text.trim_end().to_string());
+            }
+        });
+    }
+    OUTPUT.with(|output| match output.borrow_mut().as_mut() {
+        Some(output) => output.push_str(text),
+        None => print!("{text}"),
+    });
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Recording {
+    pub diagnostics: Vec<String>,
+    pub files: BTreeMap<String, PathBuf>, // name -> path of the debug file
+}
+
+// the next warnings, errors and debug files are recorded until take_recording
+pub fn start_recording() {
+    RECORDING.with(|recording| *recording.borrow_mut() = Some(Recording::default()));
+}
+
+pub fn take_recording() -> Recording {
+    RECORDING.with(|recording| recording.borrow_mut().take().unwrap_or_default())
+}
+
+// the output and the debug files of the next analysis on this thread go to the job directory, until take_job_output
+pub fn start_job_output(job_dir: PathBuf) {
+    OUTPUT.with(|output| *output.borrow_mut() = Some(String::new()));
+    OUTPUT_DIR.with(|dir| *dir.borrow_mut() = Some(job_dir));
+}
+
+pub fn take_job_output() -> String {
+    OUTPUT_DIR.with(|dir| dir.borrow_mut().take());
+    OUTPUT.with(|output| output.borrow_mut().take().unwrap_or_default())
+}
+
+// a debug file of the analysis (graph.dot, values.txt, ...), in the directory of the job in batch mode
+pub fn create_output_file(name: &str) -> std::fs::File {
+    let path = OUTPUT_DIR.with(|output_dir| match output_dir.borrow().as_ref() {
+        Some(output_dir) => output_dir.join(name),
+        None => PathBuf::from(name),
+    });
+    RECORDING.with(|recording| {
+        if let Some(recording) = recording.borrow_mut().as_mut() {
+            recording.files.insert(name.to_string(), path.clone());
+        }
+    });
+    std::fs::File::create(path).expect("Unable to create file")
+}
+
+#[derive(Debug, Clone)]
+pub struct BatchJob {
+    pub input: PathBuf,
+    pub function: Option<BatchFunction>, // entry symbol, ENTRY_SYMBOL if None
+}
+
+impl std::fmt::Display for BatchJob {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.input.display())?;
+        if let Some(function) = &self.function {
+            write!(f, ":{function}")?;
+        }
+        Ok(())
+    }
+}
+
+// a function symbol of an input, found by section and address: the static functions can have the same name
+#[derive(Debug, Clone)]
+pub struct BatchFunction {
+    pub name: String,
+    pub section: SectionIndex,
+    pub address: u64,      // address of the symbol in the object
+    pub occurrence: usize, // 1 + the functions of the input with the same name before it
+}
+
+// name#2, name#3, ... for the functions with the same name
+impl std::fmt::Display for BatchFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)?;
+        if self.occurrence > 1 {
+            write!(f, "#{}", self.occurrence)?;
+        }
+        Ok(())
+    }
+}
+
+// the results of an analysis that go into the batch report
+#[derive(Debug, Clone, Default)]
+pub struct AnalysisSummary {
+    pub wcet: Option<u64>, // None if the formula has missing parameters
+    pub bcet: Option<u64>,
+    pub formula: Option<String>, // with symbolic loop bounds
+    pub hybrid: bool,            // with the measured clock cycles of a trace
+    pub cached: bool,            // read from the analysis cache
+}
+
+struct JobResult {
+    job: BatchJob,
+    output: String,
+    summary: Result<AnalysisSummary, String>, // the panic message of a failed analysis
+}
+
+// the jobs of the BATCH_INPUTS env var, None if it is not set
+pub fn jobs_from_env() -> Option<Vec<BatchJob>> {
+    let inputs = std::env::var("BATCH_INPUTS").ok()?;
+    let functions = std::env::var("BATCH_FUNCTIONS").is_ok_and(|value| value == "true");
+
+    let mut files = Vec::new();
+    for input in inputs.split(',').filter(|input| !input.is_empty()) {
+        let path = PathBuf::from(input);
+        if path.is_dir() {
+            let mut dir_files = std::fs::read_dir(&path)
+                .unwrap_or_else(|_| panic!("Unable to read the directory {input} of BATCH_INPUTS"))
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.extension().is_some_and(|extension| extension == "o"))
+                .collect::<Vec<_>>();
+            dir_files.sort();
+            files.extend(dir_files);
+        } else if path.is_file() {
+            files.push(path);
+        } else {
+            panic!("The input {input} of BATCH_INPUTS is not a file or a directory");
+        }
+    }
+    // a file listed twice (also inside a listed directory) is analyzed once
+    let mut seen = BTreeSet::new();
+    files.retain(|file| seen.insert(file.clone()));
+
+    let mut jobs = Vec::new();
+    for file in files {
+        if !functions {
+            jobs.push(BatchJob {
+                input: file,
+                function: None,
+            });
+            continue;
+        }
+
+        for function in function_symbols(&file) {
+            jobs.push(BatchJob {
+                input: file.clone(),
+                function: Some(function),
+            });
+        }
+    }
+    Some(jobs)
+}
+
+// the defined functions of the code sections, in address order
+fn function_symbols(file: &Path) -> Vec<BatchFunction> {
+    let file_bytes = std::fs::read(file)
+        .unwrap_or_else(|_| panic!("Unable to read the input {}", file.display()));
+    let Ok(obj_file) = object::File::parse(file_bytes.as_slice()) else {
+        printwarning!(
+            "The input {} is not an object file -> it is not analyzed",
+            file.display()
+        );
+        return Vec::new();
+    };
+
+    let mut symbols = obj_file
+        .symbols()
+        .filter(|symbol| symbol.kind() == SymbolKind::Text && symbol.is_definition())
+        .filter_map(|symbol| {
+            // the symbols of the analyzed code sections, the plt is not analyzed
+            let section = obj_file.section_by_index(symbol.section_index()?).ok()?;
+            let section_name = section.name().unwrap_or_default();
+            if section.kind() != SectionKind::Text || section_name.starts_with(".plt") {
+                return None;
+            }
+            let name = symbol.name().ok().filter(|name| !name.is_empty())?;
+            Some((section.index().0, symbol.address(), name.to_string()))
+        })
+        .collect::<Vec<_>>();
+    symbols.sort();
+    symbols.dedup();
+
+    let mut occurrences = HashMap::<String, usize>::new();
+    symbols
+        .into_iter()
+        .map(|(section, address, name)| {
+            let occurrence = occurrences.entry(name.clone()).or_insert(0);
+            *occurrence += 1;
+            BatchFunction {
+                name,
+                section: SectionIndex(section),
+                address,
+                occurrence: *occurrence,
+            }
+        })
+        .collect()
+}
+
+// the directory of the debug files of a job: BATCH_OUTPUT_DIR/<input path>[/<function>], with the directories
+// of the input so that the inputs with the same file name (a/x.o and b/x.o) don't share it
+fn job_output_dir(output_dir: &Path, job: &BatchJob) -> PathBuf {
+    let mut dir = output_dir.to_path_buf();
+    for component in job.input.components() {
+        match component {
+            Component::Normal(name) => dir.push(name),
+            Component::ParentDir => dir.push("@parent"),
+            Component::RootDir | Component::Prefix(_) | Component::CurDir => {}
+        }
+    }
+    if let Some(function) = &job.function {
+        dir.push(function.to_string());
+    }
+    dir
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+pub fn run_batch(jobs: Vec<BatchJob>, analyze: fn(&BatchJob) -> AnalysisSummary) {
+    let output_dir =
+        PathBuf::from(std::env::var("BATCH_OUTPUT_DIR").unwrap_or(DEFAULT_OUTPUT_DIR.to_string()));
+    let threads = match std::env::var("BATCH_THREADS") {
+        Ok(threads) => threads
+            .parse::<usize>()
+            .expect("The environment variable BATCH_THREADS is not a valid number"),
+        Err(_) => 0, // one for each cpu
+    };
+
+    // the number of threads is not printed, the output is the same for any number
+    println!("Batch: {} analyses", jobs.len());
+
+    // the env vars of the addresses are not bound to an input, the same addresses are other code in another input
+    let inputs = jobs.iter().map(|job| &job.input).collect::<BTreeSet<_>>();
+    let address_vars = std::env::vars()
+        .map(|(name, _)| name)
+        .filter(|name| {
+            ADDRESS_PREFIXES
+                .iter()
+                .any(|prefix| name.starts_with(prefix))
+        })
+        .collect::<BTreeSet<_>>();
+    if inputs.len() > 1 && !address_vars.is_empty() {
+        printwarning!(
+            "The env vars {} apply to the same addresses of every input -> run a batch with one input for them",
+            address_vars.into_iter().collect::<Vec<_>>().join(", ")
+        );
+    }
+
+    let results = run_jobs(jobs, threads, &output_dir, analyze);
+    let (output, report) = batch_output(&output_dir, &results);
+    print!("{output}");
+
+    std::fs::create_dir_all(&output_dir).expect("Unable to create the output directory");
+    let mut report_file =
+        std::fs::File::create(output_dir.join("report.txt")).expect("Unable to create file");
+    write!(report_file, "{report}").expect("Unable to write report file");
+}
+
+// the analyses of the jobs on a pool of threads (0 for one for each cpu), the results keep the order of the jobs
+fn run_jobs(
+    jobs: Vec<BatchJob>,
+    threads: usize,
+    output_dir: &Path,
+    analyze: fn(&BatchJob) -> AnalysisSummary,
+) -> Vec<JobResult> {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .expect("Unable to create the thread pool");
+
+    pool.install(|| {
+        jobs.into_par_iter()
+            .map(|job| {
+                let job_dir = job_output_dir(output_dir, &job);
+                std::fs::create_dir_all(&job_dir).expect("Unable to create the output directory");
+                start_job_output(job_dir);
+
+                let summary = catch_unwind(AssertUnwindSafe(|| analyze(&job)))
+                    .map_err(|payload| panic_message(payload.as_ref()));
+
+                let output = take_job_output();
+                // a failed analysis can stop while recording
+                take_recording();
+                JobResult {
+                    job,
+                    output,
+                    summary,
+                }
+            })
+            .collect::<Vec<_>>()
+    })
+}
+
+// the output of every analysis and the report with one line for each of them
+fn batch_output(output_dir: &Path, results: &[JobResult]) -> (String, String) {
+    let mut output = String::new();
+    let mut report = String::new();
+    for result in results {
+        output.push_str(&format!("=== {} ===\n", result.job));
+        output.push_str(&result.output);
+        if let Err(message) = &result.summary {
+            output.push_str(&format!(
+                "ERROR: The analysis of {} failed: {message}\n",
+                result.job
+            ));
+        }
+
+        let line = match &result.summary {
+            Ok(summary) => {
+                let wcet = summary
+                    .wcet
+                    .map_or("-".to_string(), |wcet| wcet.to_string());
+                let bcet = summary
+                    .bcet
+                    .map_or("-".to_string(), |bcet| bcet.to_string());
+                let hybrid = if summary.hybrid { "Hybrid " } else { "" };
+                let mut line = format!("{}: {hybrid}WCET {wcet}, BCET {bcet}", result.job);
+                if let Some(formula) = &summary.formula {
+                    line.push_str(&format!(", WCET formula {formula}"));
+                }
+                if summary.cached {
+                    line.push_str(" (cached)");
+                }
+                line
+            }
+            Err(message) => format!("{}: failed ({message})", result.job),
+        };
+        report.push_str(&line);
+        report.push('\n');
+    }
+
+    let failed = results
+        .iter()
+        .filter(|result| result.summary.is_err())
+        .count();
+    output.push_str(&format!(
+        "Batch report ({}): {} analyses, {failed} failed\n",
+        output_dir.join("report.txt").display(),
+        results.len()
+    ));
+    output.push_str(&report);
+
+    (output, report)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    // the later jobs finish first, the job 3 fails
+    fn analyze_job(job: &BatchJob) -> AnalysisSummary {
+        let number = job
+            .input
+            .file_stem()
+            .and_then(|stem| stem.to_str()?.parse::<u64>().ok())
+            .unwrap();
+        std::thread::sleep(Duration::from_millis((8 - number) * 5));
+        printline!("analysis {number}");
+        printwarning!("job {number}");
+        if number == 3 {
+            panic!("job {number} failed");
+        }
+        let mut file = create_output_file("values.txt");
+        writeln!(file, "{number}").unwrap();
+        AnalysisSummary {
+            wcet: Some(number * 10),
+            bcet: number.is_multiple_of(2).then_some(number),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn same_output_for_any_number_of_threads() {
+        let output_dir = std::env::temp_dir().join("asm-analyzer-batch-threads");
+        let jobs = (0..8)
+            .map(|number| BatchJob {
+                input: PathBuf::from(format!("inputs/{number}.o")),
+                function: None,
+            })
+            .collect::<Vec<_>>();
+
+        let mut outputs = Vec::new();
+        for threads in [1, 4] {
+            let _ = std::fs::remove_dir_all(&output_dir);
+            let results = run_jobs(jobs.clone(), threads, &output_dir, analyze_job);
+            let files = (0..8)
+                .map(|number| {
+                    std::fs::read_to_string(
+                        output_dir.join(format!("inputs/{number}.o/values.txt")),
+                    )
+                    .ok()
+                })
+                .collect::<Vec<_>>();
+            outputs.push((batch_output(&output_dir, &results), files));
+        }
+
+        assert_eq!(outputs[0], outputs[1]);
+        let ((output, report), files) = &outputs[0];
+        assert!(output
+            .starts_with("=== inputs/0.o ===\nanalysis 0\nWARNING: job 0\n=== inputs/1.o ==="));
+        assert!(output.contains("ERROR: The analysis of inputs/3.o failed: job 3 failed\n"));
+        assert!(output.contains("): 8 analyses, 1 failed\n"));
+        assert!(report.starts_with("inputs/0.o: WCET 0, BCET 0\ninputs/1.o: WCET 10, BCET -\n"));
+        assert!(report.contains("inputs/3.o: failed (job 3 failed)\n"));
+        assert_eq!(files[3], None);
+        assert_eq!(files[7].as_deref(), Some("7\n"));
+    }
+}
@@ -0,0 +1,384 @@
+use std::collections::HashMap;
+
+use capstone::arch::x86::X86OperandType;
+use capstone::arch::ArchOperand;
+use capstone::Capstone;
+use object::read::elf::{FileHeader, SectionHeader};
+use object::{
+    elf, Architecture, Object, ObjectKind, ObjectSection, ObjectSymbol, ObjectSymbolTable,
+    RelocationKind, RelocationTarget, SectionIndex, SectionKind, SymbolKind,
+};
+
+use crate::printwarning;
+
+// address where the code of a relocatable object is placed, its sections all start at 0
+const RELOCATABLE_BASE_ADDRESS: u64 = 0x1000;
+
+// the symbol the analysis starts from: by name (ENTRY_SYMBOL), or also by section and address (the functions
+// of a batch job, the static functions of an object can have the same name)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntrySymbol {
+    pub name: String,
+    pub location: Option<(SectionIndex, u64)>, // section, address of the symbol in the object
+}
+
+#[derive(Debug, Clone)]
+pub struct CodeSection {
+    pub name: String,
+    pub address: u64,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PltSection {
+    pub section: CodeSection,
+    pub entry_size: u64, // bytes
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CodeReference {
+    Internal(u64),
+    External(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Binary {
+    pub kind: ObjectKind,
+    pub code_sections: Vec<CodeSection>,
+    pub section_addresses: HashMap<SectionIndex, u64>, // section -> address of its first instruction
+    pub plt_sections: Vec<PltSection>,
+    pub plt_symbols: HashMap<u64, String>, // plt entry address -> imported symbol
+    pub relocations: HashMap<u64, CodeReference>, // relocated address -> referenced code
+}
+
+impl Binary {
+    pub fn new(obj_file: &object::File, file_data: &[u8], cs: &Capstone) -> Self {
+        let kind = obj_file.kind();
+
+        let mut code_sections = Vec::new();
+        let mut section_addresses = HashMap::new();
+        let mut plt_sections = Vec::new();
+
+        // a relocatable object has all the sections at address 0, so we join them in one
+        let mut joined_section = CodeSection {
+            name: ".text".to_string(),
+            address: RELOCATABLE_BASE_ADDRESS,
+            data: Vec::new(),
+        };
+
+        for section in obj_file.sections() {
+            if section.kind() != SectionKind::Text {
+                continue;
+            }
+
+            let name = section.name().unwrap_or_default().to_string();
+            let data = section.data().unwrap_or_default().to_vec();
+
+            if name.starts_with(".plt") {
+                let entry_size = section_entry_size(obj_file, file_data, section.index())
+                    .filter(|entry_size| *entry_size > 0)
+                    .unwrap_or_else(|| default_plt_entry_size(obj_file.architecture(), &name));
+                plt_sections.push(PltSection {
+                    section: CodeSection {
+                        name,
+                        address: section.address(),
+                        data,
+                    },
+                    entry_size,
+                });
+            } else if kind == ObjectKind::Relocatable {
+                section_addresses.insert(
+                    section.index(),
+                    joined_section.address + joined_section.data.len() as u64,
+                );
+                joined_section.data.extend_from_slice(&data);
+            } else {
+                section_addresses.insert(section.index(), section.address());
+                code_sections.push(CodeSection {
+                    name,
+                    address: section.address(),
+                    data,
+                });
+            }
+        }
+
+        if kind == ObjectKind::Relocatable {
+            code_sections.push(joined_section);
+        }
+
+        code_sections.sort_by_key(|section| section.address);
+
+        let plt_symbols = get_plt_symbols(obj_file, cs, &plt_sections);
+        let relocations = get_code_relocations(obj_file, &section_addresses);
+
+        Binary {
+            kind,
+            code_sections,
+            section_addresses,
+            plt_sections,
+            plt_symbols,
+            relocations,
+        }
+    }
+
+    pub fn symbol_address(&self, obj_file: &object::File, name: &str) -> Option<u64> {
+        let symbol = obj_file
+            .symbols()
+            .find(|symbol| symbol.name() == Ok(name) && symbol.is_definition())?;
+        self.section_symbol_address(symbol.section_index()?, symbol.address())
+    }
+
+    // the analyzed address of a symbol address of the section, None if it is not a code section
+    pub fn section_symbol_address(&self, section_index: SectionIndex, address: u64) -> Option<u64> {
+        let section_address = self.section_addresses.get(&section_index)?;
+
+        match self.kind {
+            ObjectKind::Relocatable => Some(section_address + address),
+            _ => Some(address),
+        }
+    }
+
+    // the address the analysis starts from: the entry symbol (ENTRY_SYMBOL or the function of a batch job)
+    // if set, otherwise the entry point of an executable
+    pub fn entry_address(
+        &self,
+        obj_file: &object::File,
+        entry_symbol: Option<&EntrySymbol>,
+    ) -> Option<u64> {
+        if let Some(symbol) = entry_symbol {
+            let address = match symbol.location {
+                Some((section_index, address)) => {
+                    self.section_symbol_address(section_index, address)
+                }
+                None => self.symbol_address(obj_file, &symbol.name),
+            };
+            match address {
+                Some(address) => return Some(address),
+                None => panic!(
+                    "The entry symbol {} is not defined in a code section",
+                    symbol.name
+                ),
+            }
+        }
+
+        match self.kind {
+            ObjectKind::Executable | ObjectKind::Dynamic if obj_file.entry() != 0 => {
+                Some(obj_file.entry())
+            }
+            _ => None,
+        }
+    }
+
+    pub fn is_plt_address(&self, address: u64) -> bool {
+        self.plt_sections.iter().any(|PltSection { section, .. }| {
+            address >= section.address && address < section.address + section.data.len() as u64
+        })
+    }
+
+    pub fn is_code_address(&self, address: u64) -> bool {
+        self.code_sections.iter().any(|section| {
+            address >= section.address && address < section.address + section.data.len() as u64
+        })
+    }
+
+    // the function called by the instruction at address, using its relocation if it has one
+    pub fn resolve_call(&self, address: u64, size: usize, target: u64) -> CodeReference {
+        if let Some(reference) =
+            (address..address + size as u64).find_map(|address| self.relocations.get(&address))
+        {
+            reference.clone()
+        } else if let Some(symbol) = self.plt_symbols.get(&target) {
+            CodeReference::External(symbol.clone())
+        } else if self.is_plt_address(target) {
+            CodeReference::External(format!("plt_0x{target:x}"))
+        } else if self.is_code_address(target) {
+            CodeReference::Internal(target)
+        } else {
+            CodeReference::External(format!("0x{target:x}"))
+        }
+    }
+}
+
+// relocations of the code of a relocatable object, to find the functions called by each call
+fn get_code_relocations(
+    obj_file: &object::File,
+    section_addresses: &HashMap<SectionIndex, u64>,
+) -> HashMap<u64, CodeReference> {
+    let mut relocations = HashMap::new();
+
+    if obj_file.kind() != ObjectKind::Relocatable {
+        return relocations;
+    }
+
+    let is_x86 = matches!(
+        obj_file.architecture(),
+        object::Architecture::X86_64
+            | object::Architecture::X86_64_X32
+            | object::Architecture::I386
+    );
+
+    for section in obj_file.sections() {
+        let Some(section_address) = section_addresses.get(&section.index()) else {
+            continue;
+        };
+
+        for (offset, relocation) in section.relocations() {
+            let RelocationTarget::Symbol(index) = relocation.target() else {
+                continue;
+            };
+            let Ok(symbol) = obj_file.symbol_by_index(index) else {
+                continue;
+            };
+
+            let reference = if symbol.is_undefined() {
+                CodeReference::External(symbol.name().unwrap_or_default().to_string())
+            } else if let Some(symbol_section_address) = symbol
+                .section_index()
+                .and_then(|index| section_addresses.get(&index))
+            {
+                if relocation.has_implicit_addend() && symbol.kind() == SymbolKind::Section {
+                    // the offset in the section is encoded in the instruction
+                    continue;
+                }
+                let addend = if relocation.has_implicit_addend() {
+                    0
+                } else {
+                    relocation.addend()
+                };
+                // on x86 the addend also counts the bytes between the relocation and the next instruction
+                let pc_bias = match relocation.kind() {
+                    RelocationKind::Relative | RelocationKind::PltRelative if is_x86 => {
+                        relocation.size() as i64 / 8
+                    }
+                    _ => 0,
+                };
+                CodeReference::Internal(
+                    (symbol_section_address + symbol.address())
+                        .wrapping_add((addend + pc_bias) as u64),
+                )
+            } else {
+                continue;
+            };
+
+            // a RISC-V call is an auipc + jalr pair, the relocation is on the auipc
+            if matches!(
+                relocation.kind(),
+                RelocationKind::Elf(elf::R_RISCV_CALL | elf::R_RISCV_CALL_PLT)
+            ) {
+                relocations.insert(section_address + offset + 4, reference.clone());
+            }
+
+            relocations.insert(section_address + offset, reference);
+        }
+    }
+
+    relocations
+}
+
+// sh_entsize of an ELF section, None for the other formats
+fn section_entry_size(
+    obj_file: &object::File,
+    file_data: &[u8],
+    section_index: SectionIndex,
+) -> Option<u64> {
+    fn entry_size<Elf: FileHeader>(file_data: &[u8], section_index: SectionIndex) -> Option<u64> {
+        let header = Elf::parse(file_data).ok()?;
+        let endian = header.endian().ok()?;
+        let sections = header.sections(endian, file_data).ok()?;
+        Some(
+            sections
+                .section(section_index)
+                .ok()?
+                .sh_entsize(endian)
+                .into(),
+        )
+    }
+
+    match (obj_file.format(), obj_file.is_64()) {
+        (object::BinaryFormat::Elf, true) => {
+            entry_size::<elf::FileHeader64<object::Endianness>>(file_data, section_index)
+        }
+        (object::BinaryFormat::Elf, false) => {
+            entry_size::<elf::FileHeader32<object::Endianness>>(file_data, section_index)
+        }
+        _ => None,
+    }
+}
+
+// the plt entry size of the architecture, for the sections without sh_entsize
+fn default_plt_entry_size(arch: Architecture, section_name: &str) -> u64 {
+    match (arch, section_name) {
+        (Architecture::X86_64 | Architecture::X86_64_X32, ".plt.got") => 8,
+        (Architecture::Arm, _) => 12,
+        _ => 16,
+    }
+}
+
+// map every plt entry to the imported symbol of the GOT slot it jumps through
+fn get_plt_symbols(
+    obj_file: &object::File,
+    cs: &Capstone,
+    plt_sections: &[PltSection],
+) -> HashMap<u64, String> {
+    let mut plt_symbols = HashMap::new();
+
+    let mut got_symbols = HashMap::<u64, String>::new(); // GOT slot -> symbol
+    if let (Some(relocations), Some(symbol_table)) = (
+        obj_file.dynamic_relocations(),
+        obj_file.dynamic_symbol_table(),
+    ) {
+        for (offset, relocation) in relocations {
+            if let RelocationTarget::Symbol(index) = relocation.target() {
+                if let Ok(name) = symbol_table.symbol_by_index(index).and_then(|s| s.name()) {
+                    got_symbols.insert(offset, name.to_string());
+                }
+            }
+        }
+    }
+
+    if obj_file.architecture() != object::Architecture::X86_64 {
+        if !plt_sections.is_empty() {
+            printwarning!(
+                "PLT entries are resolved only on x86_64, calls through the PLT are named by their address"
+            );
+        }
+        return plt_symbols;
+    }
+
+    // x86_64 plt entries end with jmp qword ptr [rip + disp]
+    for PltSection {
+        section,
+        entry_size,
+    } in plt_sections
+    {
+        let Ok(instructions) = cs.disasm_all(&section.data, section.address) else {
+            continue;
+        };
+
+        for insn in instructions.iter() {
+            let Ok(insn_detail) = cs.insn_detail(insn) else {
+                continue;
+            };
+
+            for op in insn_detail.arch_detail().operands() {
+                if let ArchOperand::X86Operand(op) = op {
+                    if let X86OperandType::Mem(mem) = op.op_type {
+                        if cs.reg_name(mem.base()).as_deref() != Some("rip") {
+                            continue;
+                        }
+                        let next_address = insn.address() + insn.bytes().len() as u64;
+                        let got_slot = next_address.wrapping_add(mem.disp() as u64);
+
+                        if let Some(symbol) = got_symbols.get(&got_slot) {
+                            let entry = section.address
+                                + (insn.address() - section.address) / entry_size * entry_size;
+                            plt_symbols.insert(entry, symbol.clone());
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    plt_symbols
+}
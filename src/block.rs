@@ -1,5 +1,7 @@
 use crate::instruction::Instruction;
 use crate::jump::ExitJump;
+use crate::source::source_location;
+use crate::timing;
 
 #[derive(Default, Clone, Hash, PartialEq, Eq)]
 pub struct Block {
@@ -48,6 +50,10 @@ impl Block {
                 ExitJump::Ret(ret_targets) => {
                     targets.push(*ret_targets);
                 }
+                ExitJump::ConditionalRet { ret, not_taken } => {
+                    targets.push(*ret);
+                    targets.push(*not_taken);
+                }
                 ExitJump::Call(target, _) => {
                     targets.push(*target);
                 }
@@ -63,10 +69,7 @@ impl Block {
     pub fn modify_targets(&mut self, new_target: u64, target: u64) {
         if let Some(exit_jump) = &mut self.clone().exit_jump {
             match exit_jump {
-                ExitJump::ConditionalRelative {
-                    taken,
-                    not_taken,
-                } => {
+                ExitJump::ConditionalRelative { taken, not_taken } => {
                     if taken == &target {
                         self.set_exit_jump(ExitJump::ConditionalRelative {
                             taken: new_target,
@@ -82,10 +85,7 @@ impl Block {
                 ExitJump::UnconditionalRelative(_) => {
                     self.set_exit_jump(ExitJump::UnconditionalRelative(new_target));
                 }
-                ExitJump::ConditionalAbsolute {
-                    taken,
-                    not_taken,
-                } => {
+                ExitJump::ConditionalAbsolute { taken, not_taken } => {
                     if taken == &target {
                         self.set_exit_jump(ExitJump::ConditionalAbsolute {
                             taken: new_target,
@@ -105,6 +105,19 @@ impl Block {
                 ExitJump::Ret(_) => {
                     self.set_exit_jump(ExitJump::Ret(new_target));
                 }
+                ExitJump::ConditionalRet { ret, not_taken } => {
+                    if ret == &target {
+                        self.set_exit_jump(ExitJump::ConditionalRet {
+                            ret: new_target,
+                            not_taken: *not_taken,
+                        });
+                    } else if not_taken == &target {
+                        self.set_exit_jump(ExitJump::ConditionalRet {
+                            ret: *ret,
+                            not_taken: new_target,
+                        });
+                    }
+                }
                 ExitJump::Call(_, ret) => {
                     self.set_exit_jump(ExitJump::Call(new_target, *ret));
                 }
@@ -116,12 +129,29 @@ impl Block {
     }
 
     pub fn get_latency(&self) -> u32 {
-        self.instructions.iter().map(|i| i.latency).sum()
+        timing::block_latency(self, None)
+    }
+
+    // lower bound of the latency, without any pipeline or cache effect
+    pub fn get_best_case_latency(&self) -> u32 {
+        self.instructions.iter().map(|i| i.bcet_latency).sum()
+    }
+
+    // latency of the block when it is executed right after the predecessor
+    pub fn get_latency_from(&self, predecessor: &Block) -> u32 {
+        timing::block_latency(self, Some(predecessor))
     }
 }
 
 impl std::fmt::Display for Block {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(location) = self
+            .instructions
+            .first()
+            .and_then(|instruction| source_location(instruction.address))
+        {
+            writeln!(f, "{location}")?;
+        }
         for insn in self.instructions.iter() {
             writeln!(f, "{insn}")?;
         }
@@ -136,6 +166,13 @@ impl std::fmt::Display for Block {
 
 impl std::fmt::Debug for Block {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(location) = self
+            .instructions
+            .first()
+            .and_then(|instruction| source_location(instruction.address))
+        {
+            writeln!(f, "{location}")?;
+        }
         for insn in self.instructions.iter() {
             writeln!(f, "{insn}")?;
         }
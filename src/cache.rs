@@ -0,0 +1,504 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use petgraph::Direction::{Incoming, Outgoing};
+
+use crate::block::Block;
+use crate::graph::MappedGraph;
+
+// env variable format: ICACHE_SIZE, ICACHE_ASSOCIATIVITY, ICACHE_LINE_SIZE (in bytes)
+// and ICACHE_MISS_PENALTY (in cycles). The cache analysis runs only if ICACHE_SIZE is set.
+// The data cache uses the same variables with the DCACHE prefix.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheConfig {
+    pub size: u64, // bytes
+    pub associativity: u64,
+    pub line_size: u64, // bytes
+    pub miss_penalty: u32,
+}
+
+impl CacheConfig {
+    pub fn from_env(prefix: &str) -> Option<Self> {
+        let get_var = |key: &str, default: Option<u64>| {
+            let env_var_key = format!("{prefix}_{key}");
+            match std::env::var(&env_var_key) {
+                Ok(value) => match value.parse::<u64>() {
+                    Ok(value) if value > 0 => Some(value),
+                    _ => panic!("The environment variable {env_var_key} is not a valid number"),
+                },
+                Err(_) => default,
+            }
+        };
+
+        let config = CacheConfig {
+            size: get_var("SIZE", None)?,
+            associativity: get_var("ASSOCIATIVITY", Some(1)).unwrap(),
+            line_size: get_var("LINE_SIZE", Some(32)).unwrap(),
+            miss_penalty: u32::try_from(get_var("MISS_PENALTY", Some(10)).unwrap()).unwrap_or_else(
+                |_| panic!("The environment variable {prefix}_MISS_PENALTY is not a valid number"),
+            ),
+        };
+
+        if !config
+            .size
+            .is_multiple_of(config.line_size * config.associativity)
+        {
+            panic!("The {prefix} size must be a multiple of line size * associativity");
+        }
+
+        Some(config)
+    }
+
+    pub fn sets(&self) -> u64 {
+        self.size / (self.line_size * self.associativity)
+    }
+
+    pub fn line(&self, address: u64) -> u64 {
+        address / self.line_size
+    }
+
+    fn set(&self, line: u64) -> usize {
+        (line % self.sets()) as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CacheClassification {
+    AlwaysHit,
+    AlwaysMiss,
+    FirstMiss,
+    NotClassified,
+}
+
+impl std::fmt::Display for CacheClassification {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CacheClassification::AlwaysHit => write!(f, "always-hit"),
+            CacheClassification::AlwaysMiss => write!(f, "always-miss"),
+            CacheClassification::FirstMiss => write!(f, "first-miss"),
+            CacheClassification::NotClassified => write!(f, "not-classified"),
+        }
+    }
+}
+
+// abstract LRU cache: for every set the lines it may contain with a bound on their age
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct AbstractCache {
+    sets: Vec<BTreeMap<u64, u64>>, // line -> age
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Analysis {
+    Must,        // upper bound of the age, the lines are surely in the cache
+    May,         // lower bound of the age, the lines not here are surely not in the cache
+    Persistence, // upper bound of the age, age == associativity means the line may have been evicted
+}
+
+impl AbstractCache {
+    fn new(config: &CacheConfig) -> Self {
+        AbstractCache {
+            sets: vec![BTreeMap::new(); config.sets() as usize],
+        }
+    }
+
+    fn access(&mut self, config: &CacheConfig, analysis: Analysis, line: u64) {
+        let set = &mut self.sets[config.set(line)];
+        let old_age = set.get(&line).copied().unwrap_or(config.associativity);
+
+        for (other, age) in set.iter_mut() {
+            let younger = match analysis {
+                Analysis::Must | Analysis::Persistence => *age < old_age,
+                Analysis::May => *age <= old_age,
+            };
+            if *other != line && younger {
+                *age += 1;
+            }
+        }
+        set.insert(line, 0);
+
+        match analysis {
+            Analysis::Must | Analysis::May => set.retain(|_, age| *age < config.associativity),
+            // evicted lines are kept with the special age to remember they are not persistent
+            Analysis::Persistence => set.values_mut().for_each(|age| {
+                *age = (*age).min(config.associativity);
+            }),
+        }
+    }
+
+    // an access to an unknown line may age every line of every set, but may also be a hit
+    fn access_unknown(&mut self, config: &CacheConfig, analysis: Analysis) {
+        match analysis {
+            Analysis::Must => {
+                for set in self.sets.iter_mut() {
+                    set.values_mut().for_each(|age| *age += 1);
+                    set.retain(|_, age| *age < config.associativity);
+                }
+            }
+            Analysis::Persistence => {
+                for set in self.sets.iter_mut() {
+                    set.values_mut()
+                        .for_each(|age| *age = (*age + 1).min(config.associativity));
+                }
+            }
+            Analysis::May => {}
+        }
+    }
+
+    fn join(&self, other: &AbstractCache, analysis: Analysis) -> AbstractCache {
+        let sets = self
+            .sets
+            .iter()
+            .zip(other.sets.iter())
+            .map(|(a, b)| match analysis {
+                Analysis::Must => a
+                    .iter()
+                    .filter_map(|(line, age)| b.get(line).map(|other| (*line, *age.max(other))))
+                    .collect(),
+                Analysis::May => {
+                    let mut set = a.clone();
+                    for (line, age) in b {
+                        let entry = set.entry(*line).or_insert(*age);
+                        *entry = (*entry).min(*age);
+                    }
+                    set
+                }
+                Analysis::Persistence => {
+                    let mut set = a.clone();
+                    for (line, age) in b {
+                        let entry = set.entry(*line).or_insert(*age);
+                        *entry = (*entry).max(*age);
+                    }
+                    set
+                }
+            })
+            .collect();
+
+        AbstractCache { sets }
+    }
+
+    fn contains(&self, config: &CacheConfig, line: u64) -> bool {
+        self.sets[config.set(line)].contains_key(&line)
+    }
+
+    fn is_persistent(&self, config: &CacheConfig, line: u64) -> bool {
+        self.sets[config.set(line)]
+            .get(&line)
+            .is_none_or(|age| *age < config.associativity)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CacheAnalysis {
+    // (block_leader, instruction_address) -> classification of the fetch of a new line
+    pub classifications: BTreeMap<(u64, u64), CacheClassification>,
+    pub first_miss_lines: BTreeMap<u64, u64>, // line -> first instruction address
+}
+
+impl CacheAnalysis {
+    // one extra miss for each first-miss line, independently of the path
+    pub fn first_miss_penalty(&self, config: &CacheConfig) -> u32 {
+        first_miss_penalty(self.first_miss_lines.len(), config.miss_penalty)
+    }
+
+    // add the miss penalty to the instructions that fetch a line that may not be in the cache
+    pub fn apply(&self, blocks: &mut BTreeMap<u64, Block>, config: &CacheConfig) {
+        for ((leader, address), classification) in &self.classifications {
+            if matches!(
+                classification,
+                CacheClassification::AlwaysMiss | CacheClassification::NotClassified
+            ) {
+                if let Some(block) = blocks.get_mut(leader) {
+                    if let Some(instruction) = block
+                        .instructions
+                        .iter_mut()
+                        .find(|i| i.address == *address)
+                    {
+                        instruction.add_latency(config.miss_penalty);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// one miss for each line, the penalty is u32 as the latencies
+pub fn first_miss_penalty(lines: usize, miss_penalty: u32) -> u32 {
+    u32::try_from(lines)
+        .ok()
+        .and_then(|lines| lines.checked_mul(miss_penalty))
+        .unwrap_or_else(|| {
+            panic!("The first miss penalty overflows u32: {lines} * {miss_penalty} clock cycles")
+        })
+}
+
+// the lines accessed by a block, in order, with the instruction that accesses them (None if the line is unknown)
+pub type CacheAccesses<'a> = &'a dyn Fn(&Block) -> Vec<(Option<u64>, u64)>;
+
+// the lines fetched by the block, in order, with the instruction that fetches them
+fn get_fetches(block: &Block, config: &CacheConfig) -> Vec<(Option<u64>, u64)> {
+    let mut fetches: Vec<(Option<u64>, u64)> = Vec::new();
+
+    for instruction in &block.instructions {
+        let first_line = config.line(instruction.address);
+        let last_line = config.line(instruction.address + instruction.size.max(1) as u64 - 1);
+        for line in first_line..=last_line {
+            if fetches.last().map(|(last, _)| *last) != Some(Some(line)) {
+                fetches.push((Some(line), instruction.address));
+            }
+        }
+    }
+
+    fetches
+}
+
+fn fixpoint(
+    graph: &MappedGraph,
+    config: &CacheConfig,
+    analysis: Analysis,
+    accesses: CacheAccesses,
+) -> HashMap<u64, AbstractCache> {
+    let mut in_states = HashMap::<u64, AbstractCache>::new(); // block_leader -> state at the block entry
+    let mut out_states = HashMap::<u64, AbstractCache>::new();
+
+    let nodes = graph.get_nodes();
+    let mut worklist = nodes.iter().cloned().collect::<VecDeque<Block>>();
+
+    while let Some(block) = worklist.pop_front() {
+        let predecessors = graph.neighbors_directed(&block, Incoming);
+        let in_state = predecessors
+            .iter()
+            .filter_map(|predecessor| out_states.get(&predecessor.leader))
+            .fold(None::<AbstractCache>, |state, out_state| match state {
+                None => Some(out_state.clone()),
+                Some(state) => Some(state.join(out_state, analysis)),
+            })
+            .unwrap_or_else(|| AbstractCache::new(config));
+
+        let mut out_state = in_state.clone();
+        for (line, _) in accesses(&block) {
+            match line {
+                Some(line) => out_state.access(config, analysis, line),
+                None => out_state.access_unknown(config, analysis),
+            }
+        }
+        in_states.insert(block.leader, in_state);
+
+        if out_states.get(&block.leader) != Some(&out_state) {
+            out_states.insert(block.leader, out_state);
+            for successor in graph.neighbors_directed(&block, Outgoing) {
+                if !worklist.iter().any(|b| b.leader == successor.leader) {
+                    worklist.push_back(successor);
+                }
+            }
+        }
+    }
+
+    in_states
+}
+
+// must/may/persistence analysis of the instruction cache over the control flow graph
+pub fn analyze_instruction_cache(graph: &MappedGraph, config: &CacheConfig) -> CacheAnalysis {
+    analyze_cache(graph, config, &|block| get_fetches(block, config))
+}
+
+// must/may/persistence analysis of a cache given the accesses of every block
+pub fn analyze_cache(
+    graph: &MappedGraph,
+    config: &CacheConfig,
+    accesses: CacheAccesses,
+) -> CacheAnalysis {
+    let must = fixpoint(graph, config, Analysis::Must, accesses);
+    let may = fixpoint(graph, config, Analysis::May, accesses);
+    let persistence = fixpoint(graph, config, Analysis::Persistence, accesses);
+
+    let mut result = CacheAnalysis::default();
+
+    for block in graph.get_nodes() {
+        let mut must_state = must[&block.leader].clone();
+        let mut may_state = may[&block.leader].clone();
+        let mut persistence_state = persistence[&block.leader].clone();
+
+        for (line, address) in accesses(&block) {
+            let Some(line) = line else {
+                result
+                    .classifications
+                    .insert((block.leader, address), CacheClassification::NotClassified);
+                must_state.access_unknown(config, Analysis::Must);
+                persistence_state.access_unknown(config, Analysis::Persistence);
+                continue;
+            };
+
+            let classification = if must_state.contains(config, line) {
+                CacheClassification::AlwaysHit
+            } else if !may_state.contains(config, line) {
+                CacheClassification::AlwaysMiss
+            } else if persistence_state.is_persistent(config, line) {
+                CacheClassification::FirstMiss
+            } else {
+                CacheClassification::NotClassified
+            };
+
+            if classification == CacheClassification::FirstMiss {
+                result.first_miss_lines.entry(line).or_insert(address);
+            }
+
+            let entry = result
+                .classifications
+                .entry((block.leader, address))
+                .or_insert(classification);
+            // an instruction accessing two lines keeps the worst classification
+            if *entry == CacheClassification::AlwaysHit {
+                *entry = classification;
+            }
+
+            must_state.access(config, Analysis::Must, line);
+            may_state.access(config, Analysis::May, line);
+            persistence_state.access(config, Analysis::Persistence, line);
+        }
+    }
+
+    result
+}
+
+impl std::fmt::Display for CacheAnalysis {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for ((leader, address), classification) in &self.classifications {
+            writeln!(
+                f,
+                "block 0x{leader:x} access 0x{address:x}: {classification}"
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jump::ExitJump;
+    use crate::testing::{graph, nop_block};
+
+    // 2 sets of 2 lines of 16 bytes: the even lines go to the set 0
+    const CONFIG: CacheConfig = CacheConfig {
+        size: 64,
+        associativity: 2,
+        line_size: 16,
+        miss_penalty: 10,
+    };
+
+    // the accesses of a block are the lines given for its leader (none if not given),
+    // at the addresses leader, leader + 1, ...
+    fn classify(graph: &MappedGraph, lines: &[(u64, &[u64])]) -> CacheAnalysis {
+        let lines = lines
+            .iter()
+            .map(|(leader, lines)| (*leader, lines.to_vec()))
+            .collect::<HashMap<_, _>>();
+        analyze_cache(graph, &CONFIG, &|block| {
+            lines
+                .get(&block.leader)
+                .into_iter()
+                .flatten()
+                .zip(block.leader..)
+                .map(|(line, address)| (Some(*line), address))
+                .collect()
+        })
+    }
+
+    #[test]
+    fn must_and_may_after_a_branch() {
+        let graph = graph(&[
+            nop_block(0x10, Some(ExitJump::Next(0x20))),
+            nop_block(
+                0x20,
+                Some(ExitJump::ConditionalRelative {
+                    taken: 0x30,
+                    not_taken: 0x40,
+                }),
+            ),
+            nop_block(0x30, Some(ExitJump::UnconditionalRelative(0x50))),
+            nop_block(0x40, Some(ExitJump::Next(0x50))),
+            nop_block(0x50, None),
+        ]);
+        let analysis = classify(
+            &graph,
+            &[
+                (0x10, &[2]),
+                (0x30, &[4]),
+                (0x40, &[6]),
+                (0x50, &[2, 4, 8, 2]),
+            ],
+        );
+
+        // the line 2 is in the cache after both branches, the line 4 only after one of them,
+        // the line 8 is never accessed before, the second access to 2 follows the eviction by 4 and 8
+        assert_eq!(
+            analysis.classifications,
+            BTreeMap::from([
+                ((0x10, 0x10), CacheClassification::AlwaysMiss),
+                ((0x30, 0x30), CacheClassification::AlwaysMiss),
+                ((0x40, 0x40), CacheClassification::AlwaysMiss),
+                ((0x50, 0x50), CacheClassification::AlwaysHit),
+                ((0x50, 0x51), CacheClassification::FirstMiss),
+                ((0x50, 0x52), CacheClassification::AlwaysMiss),
+                ((0x50, 0x53), CacheClassification::AlwaysMiss),
+            ])
+        );
+    }
+
+    #[test]
+    fn persistence_in_a_loop() {
+        let conditional =
+            |taken, not_taken| Some(ExitJump::ConditionalRelative { taken, not_taken });
+        // the line 10 stays in the cache in the first loop, in the second one 12 and 14 may evict it
+        let graph = graph(&[
+            nop_block(0x58, Some(ExitJump::Next(0x60))),
+            nop_block(0x60, conditional(0x60, 0x70)),
+            nop_block(0x70, None),
+            nop_block(0x78, Some(ExitJump::Next(0x80))),
+            nop_block(0x80, conditional(0x80, 0x88)),
+            nop_block(0x88, conditional(0x80, 0x90)),
+            nop_block(0x90, None),
+        ]);
+        let analysis = classify(&graph, &[(0x60, &[10]), (0x80, &[10]), (0x88, &[12, 14])]);
+
+        assert_eq!(
+            analysis.classifications[&(0x60, 0x60)],
+            CacheClassification::FirstMiss
+        );
+        assert_eq!(
+            analysis.classifications[&(0x80, 0x80)],
+            CacheClassification::NotClassified
+        );
+        assert_eq!(analysis.first_miss_lines, BTreeMap::from([(10, 0x60)]));
+        assert_eq!(analysis.first_miss_penalty(&CONFIG), 10);
+    }
+
+    #[test]
+    fn unknown_access_ages_the_must_lines() {
+        let graph = graph(&[nop_block(0xa0, None)]);
+        let analysis = analyze_cache(&graph, &CONFIG, &|_| {
+            vec![
+                (Some(1), 0xa0),
+                (Some(1), 0xa1),
+                (None, 0xa2),
+                (Some(1), 0xa3),
+                (None, 0xa4),
+                (Some(1), 0xa5),
+            ]
+        });
+
+        // one unknown access keeps the line in the 2 ways, the second one may evict it
+        let classifications = analysis.classifications.into_values().collect::<Vec<_>>();
+        assert_eq!(
+            classifications,
+            [
+                CacheClassification::AlwaysMiss,
+                CacheClassification::AlwaysHit,
+                CacheClassification::NotClassified,
+                CacheClassification::AlwaysHit,
+                CacheClassification::NotClassified,
+                CacheClassification::AlwaysHit,
+            ]
+        );
+    }
+}
@@ -1,400 +1,835 @@
+use petgraph::algo::toposort;
+use petgraph::graphmap::DiGraphMap;
 use petgraph::Direction::{Incoming, Outgoing};
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::io::Write;
-use std::sync::atomic::{AtomicU32, Ordering};
 
+use crate::batch::create_output_file;
 use crate::block::Block;
-use crate::graph::{MappedCondensedGraph, MappedGraph};
+use crate::formula::{parameters_from_env, Formula};
+use crate::graph::{add_cycles, MappedCondensedGraph, MappedGraph};
 use crate::jump::ExitJump;
-use crate::printwarning;
+use crate::loops::LoopForest;
+use crate::source::source_suffix;
+use crate::{printline, printwarning};
 
-static COUNTER: AtomicU32 = AtomicU32::new(0);
+// an irreducible cycle with n entries is split n - 1 times, this bounds the total number of splits
+const MAX_CYCLE_SPLITS: usize = 100;
 
-pub fn condensate_graph(
-    mut original_graph: MappedGraph,
-    entry_node_latency_map: &mut HashMap<u64, u32>,
+// the blocks of the cycle that are targeted from outside of it, the analysis entry is entered at the start
+// of the run: it is an entry too, the first one so that it keeps the original cycle
+fn cycle_entries(
     blocks: &BTreeMap<u64, Block>,
-    recursive_functions: &HashMap<u64, u64>, // function_address -> ret_address
-    latency_map: &mut HashMap<u64, u32>,     // ret_address -> latency
-    fictious_map: &mut HashMap<u64, u64>,    // fictious_address -> real_address
-) -> MappedCondensedGraph {
-    let mut condensed_graph = original_graph.condense_cycles();
+    members: &BTreeSet<u64>,
+    entry_address: Option<u64>,
+) -> Vec<u64> {
+    let mut entries = BTreeSet::new();
+    for block in blocks.values() {
+        if members.contains(&block.leader) {
+            continue;
+        }
+        for target in block.get_targets() {
+            if members.contains(&target) {
+                entries.insert(target);
+            }
+        }
+    }
 
-    for condensed_node in condensed_graph.get_condensed_nodes() {
-        COUNTER.fetch_add(1, Ordering::Relaxed);
+    let mut entries = entries.into_iter().collect::<Vec<_>>();
+    if let Some(entry_address) = entry_address.filter(|address| members.contains(address)) {
+        entries.retain(|entry| *entry != entry_address);
+        entries.insert(0, entry_address);
+    }
+    entries
+}
 
-        // create new graph with the blocks of the condensed node, acyclic
-        let mut cycle_graph = MappedGraph::new();
+// An irreducible cycle can be entered at more than one block, so it has no single header to bound.
+// Node splitting: the cycle is copied once for every entry after the first one, the copy is entered
+// only from the outside predecessors of its entry. Every copy is then a cycle with a single entry,
+// bounded by the CYCLE_ env var of that entry
+pub fn split_irreducible_cycles(
+    blocks: &mut BTreeMap<u64, Block>,
+    entry_address: Option<u64>,
+    fictious_map: &mut HashMap<u64, u64>, // fictious_address -> real_address
+) {
+    for splits in 0..=MAX_CYCLE_SPLITS {
+        let graph = MappedGraph::from_blocks(blocks);
+        let Some((members, entries)) = graph.loops().into_iter().find_map(|(_, members)| {
+            let entries = cycle_entries(blocks, &members, entry_address);
+            (entries.len() > 1).then_some((members, entries))
+        }) else {
+            return;
+        };
+        if splits == MAX_CYCLE_SPLITS {
+            printwarning!(
+                "Too many irreducible cycles, only {MAX_CYCLE_SPLITS} of them have been split: the wcet is not reliable"
+            );
+            return;
+        }
 
-        // add edges to the cycle_graph
-        for block in condensed_node.iter() {
-            for target in block.get_targets() {
-                if !condensed_node
-                    .iter()
-                    .filter(|node| node.leader == target)
-                    .collect::<Vec<_>>()
-                    .is_empty()
-                {
-                    let target_block = blocks.get(&target).unwrap();
-                    cycle_graph.add_edge(
-                        block.clone(),
-                        target_block.clone(),
-                        target_block.get_latency() as f32,
-                    );
+        let real_address = |fictious_map: &HashMap<u64, u64>, leader: u64| {
+            *fictious_map.get(&leader).unwrap_or(&leader)
+        };
+        let entry_names = entries
+            .iter()
+            .map(|entry| format!("0x{:x}", real_address(fictious_map, *entry)))
+            .collect::<Vec<_>>()
+            .join(", ");
+        printwarning!(
+            "Found an irreducible cycle with the entries {entry_names} -> the cycle is split into one cycle for each entry, \
+            the env var CYCLE_ of every entry bounds its iterations"
+        );
+
+        for entry in &entries[1..] {
+            let mut next_leader = blocks.keys().next_back().map_or(0, |leader| leader + 1);
+            let copies = members
+                .iter()
+                .map(|member| {
+                    next_leader += 1;
+                    (*member, next_leader)
+                })
+                .collect::<HashMap<_, _>>();
+
+            for member in &members {
+                let mut copy = blocks[member].clone();
+                copy.leader = copies[member];
+                for target in copy.get_targets() {
+                    if let Some(copy_target) = copies.get(&target) {
+                        copy.modify_targets(*copy_target, target);
+                    }
+                }
+                fictious_map.insert(copy.leader, real_address(fictious_map, *member));
+                blocks.insert(copy.leader, copy);
+            }
+
+            // the outside predecessors of the entry go to its copy
+            let predecessors = blocks
+                .values()
+                .filter(|block| {
+                    !members.contains(&block.leader)
+                        && !copies.values().any(|copy| *copy == block.leader)
+                        && block.get_targets().contains(entry)
+                })
+                .map(|block| block.leader)
+                .collect::<Vec<_>>();
+            for predecessor in predecessors {
+                if let Some(block) = blocks.get_mut(&predecessor) {
+                    block.modify_targets(copies[entry], *entry);
                 }
             }
         }
+    }
+}
 
-        // find the entry node of the cycle
-        let mut pre_cycle_blocks = condensed_node.clone();
+// a latency as a formula of the parameters and its clock cycles with the values of the PARAM_ env vars,
+// the clock cycles are integers so that an overflow stops the analysis instead of losing precision
+#[derive(Debug, Clone)]
+pub struct Latency {
+    pub formula: Formula,
+    pub cycles: u64,
+}
 
-        if let Some(incomings) = condensed_graph
-            .neighbors_directed(&condensed_node, Incoming)
-            .get(0)
-        {
-            pre_cycle_blocks = incomings.to_owned();
-            // it is not important which block we take, we just need one
+impl Latency {
+    fn constant(cycles: u64) -> Self {
+        Latency {
+            formula: Formula::constant(cycles as f64),
+            cycles,
         }
+    }
 
-        let mut entry_block = &condensed_node[0]; // to initialize the variable
+    fn zero() -> Self {
+        Latency::constant(0)
+    }
 
-        // handling case where pre_cycle_block has more than one block --> it is a condensed node
-        for block in &pre_cycle_blocks {
-            for inner_block in &condensed_node {
-                if block.get_targets().contains(&inner_block.leader) {
-                    entry_block = inner_block;
-                }
-            }
+    // a loop bound, its clock cycles are the bound rounded up
+    fn bound(formula: Formula, parameters: &BTreeMap<String, f64>) -> Self {
+        let cycles = formula.value(parameters);
+        Latency { formula, cycles }
+    }
+
+    fn add(&self, other: &Latency) -> Latency {
+        Latency {
+            formula: self.formula.add(&other.formula),
+            cycles: add_cycles(self.cycles, other.cycles),
         }
+    }
 
-        let mut max_cycles = 1;
-        if let Some(real_entry_address) = fictious_map.get(&entry_block.leader) {
-            let env_var_key = format!("CYCLE_0x{real_entry_address:x}");
-            if let Ok(cycle_var) = std::env::var(&env_var_key) {
-                match cycle_var.parse::<u32>() {
-                    Ok(cycle_var) => max_cycles = cycle_var,
-                    Err(_) => {
-                        panic!(
-                            "The environment variable {} is not a valid number",
-                            env_var_key
-                        );
-                    }
-                }
-            };
-        } else {
-            let env_var_key = format!("CYCLE_0x{:x}",entry_block.leader);
-            if let Ok(cycle_var) = std::env::var(&env_var_key) {
-                match cycle_var.parse::<u32>() {
-                    Ok(cycle_var) => max_cycles = cycle_var,
-                    Err(_) => {
-                        panic!(
-                            "The environment variable {} is not a valid number",
-                            env_var_key
-                        );
-                    }
-                }
+    fn mul(&self, other: &Latency) -> Latency {
+        let (a, b) = (self.cycles, other.cycles);
+        Latency {
+            formula: self.formula.mul(&other.formula),
+            cycles: a
+                .checked_mul(b)
+                .unwrap_or_else(|| panic!("The wcet overflows u64: {a} * {b} clock cycles")),
+        }
+    }
+
+    fn max(&self, other: &Latency) -> Latency {
+        Latency {
+            formula: self.formula.max(&other.formula),
+            cycles: self.cycles.max(other.cycles),
+        }
+    }
+
+    fn saturating_sub(&self, other: &Latency) -> Latency {
+        Latency {
+            formula: self.formula.saturating_sub(&other.formula),
+            cycles: self.cycles.saturating_sub(other.cycles),
+        }
+    }
+}
+
+// a loop bound from the env var: a number or an expression of the parameters, never negative
+// (e.g. len/4-1 is 0 for len < 4)
+pub fn bound_from_env(env_var_key: &str) -> Option<Formula> {
+    let bound_var = std::env::var(env_var_key).ok()?;
+    match Formula::parse(&bound_var) {
+        Some(bound) => Some(bound.max(&Formula::zero())),
+        None => {
+            panic!(
+                "The environment variable {} is not a valid number or expression",
+                env_var_key
+            );
+        }
+    }
+}
+
+// iterations of the cycle from the CYCLE_ env var of its header, the copies use the one of the real address
+fn cycle_bound(
+    header: u64,
+    fictious_map: &HashMap<u64, u64>,
+    parameters: &BTreeMap<String, f64>,
+) -> Latency {
+    let real_address = fictious_map.get(&header).copied().unwrap_or(header);
+    let env_var_key = format!("CYCLE_0x{real_address:x}");
+
+    let max_cycles = bound_from_env(&env_var_key).unwrap_or(Formula::constant(1.0));
+    if !fictious_map.contains_key(&header) {
+        printwarning!("Found a cycle at address {:x}{} -> {max_cycles} cycle iterations considered for the wcet calculation. \
+        If you want to change the value, please set the env var CYCLE_0x{:x}", header, source_suffix(header), header);
+    }
+
+    Latency::bound(max_cycles, parameters)
+}
+
+// total iterations of an inner cycle for each entry of its outer cycle, from the CYCLE_TOTAL_ env var of its header
+// (e.g. for j in 0..i inside for i in 0..n it is n*(n+1)/2, instead of n iterations for every outer iteration)
+fn cycle_total_bound(
+    header: u64,
+    fictious_map: &HashMap<u64, u64>,
+    parameters: &BTreeMap<String, f64>,
+) -> Option<Latency> {
+    let real_address = fictious_map.get(&header).copied().unwrap_or(header);
+    bound_from_env(&format!("CYCLE_TOTAL_0x{real_address:x}"))
+        .map(|bound| Latency::bound(bound, parameters))
+}
+
+// a cycle entered at the return of a recursive function is repeated for every recursive call
+fn record_recursion(
+    entry_block: &Block,
+    cycle_node_latency: &Latency,
+    entry_node_latency: u32,
+    recursive_functions: &HashMap<u64, u64>, // function_address -> ret_address
+    latency_map: &mut HashMap<u64, Latency>, // ret_address -> latency
+    parameters: &BTreeMap<String, f64>,
+) {
+    let Some(current_ret_address) = entry_block
+        .exit_jump
+        .as_ref()
+        .and_then(ExitJump::ret_target)
+    else {
+        return;
+    };
+
+    let mut max_cycles = Formula::constant(1.0);
+    for (recursive_address, ret_address) in recursive_functions {
+        if current_ret_address == *ret_address {
+            let env_var_key = format!("RECURSIVE_0x{recursive_address:x}");
+            if let Some(recursive_var) = bound_from_env(&env_var_key) {
+                max_cycles = recursive_var;
             };
-            printwarning!("Found a cycle at address {:x} -> {max_cycles} cycle iterations considered for the wcet calculation. \
-            If you want to change the value, please set the env var CYCLE_0x{:x}", entry_block.leader, entry_block.leader);
+            printwarning!(
+                "Found a recursive function at address 0x{recursive_address:x}{} -> {max_cycles} function iterations \
+                considered for the wcet calculation. If you want to change this value, set the environment \
+                variable {env_var_key}",
+                source_suffix(*recursive_address)
+                );
         }
+    }
+    let latency = cycle_node_latency.mul(&Latency::bound(max_cycles, parameters));
+    latency_map.insert(
+        current_ret_address,
+        Latency {
+            formula: latency
+                .formula
+                .add(&Formula::constant(-(entry_node_latency as f64))),
+            cycles: latency.cycles.saturating_sub(u64::from(entry_node_latency)),
+        },
+    );
+}
 
-        let outer_nodes = condensed_graph
-            .neighbors_directed(&condensed_node, Outgoing)
-            .to_owned();
+// latencies of a loop seen from the outside, with all its iterations
+#[derive(Debug, Clone)]
+struct LoopCost {
+    entry: Latency, // on the edges entering the loop, it covers the paths that end in the loop
+    entry_without_iterations: Latency, // the same, when the iterations are bounded in total by the outer loop
+    exits: BTreeMap<u64, Latency>,     // exit target -> latency added on the exit edge
+    terminal: bool,                    // some path ends in the loop
+    iteration: Latency,                // latency of one iteration
+}
 
-        let mut exit_block = entry_block.clone();
+impl LoopCost {
+    fn exit(&self, target: u64) -> Latency {
+        self.exits.get(&target).cloned().unwrap_or(Latency::zero())
+    }
 
-        let mut false_outer_blocks = HashMap::<Block, Vec<Block>>::new(); // exit_block -> outer_blocks
+    fn worst(&self) -> Latency {
+        self.exits.values().fold(self.entry.clone(), |worst, exit| {
+            worst.max(&self.entry.add(exit))
+        })
+    }
+}
 
-        let mut normal_cycle = false;
-        // handling case where there are more than one outer block
-        for outer_blocks in outer_nodes {
-            // handle case where outer block has more than one block --> it is a condensed node
-            for outer_block in outer_blocks.clone() {
-                if let Some(cycle_block) = cycle_graph
-                    .get_nodes()
-                    .iter()
-                    .find(|node| node.get_targets().contains(&outer_block.clone().leader))
-                {
-                    if cycle_block.leader == entry_block.leader {
-                        normal_cycle = true;
-                    } else {
-                        false_outer_blocks.insert(cycle_block.clone(), outer_blocks.clone());
-                    }
-                    break;
-                }
-            }
+// latencies of the condensed graph as formulas of the parameters, the graph has their numeric values
+#[derive(Debug, Default)]
+pub struct CondensedFormulas {
+    pub edges: HashMap<(u64, u64), Formula>, // (source, target) -> latency, [0] as reference of the condensed nodes
+    pub entries: HashMap<u64, Formula>,      // entry node -> latency
+}
+
+// longest distances from the source in an acyclic graph, None if there is a cycle
+fn longest_distances(
+    nodes: &BTreeSet<u64>,
+    edges: &BTreeMap<(u64, u64), Latency>,
+    source: u64,
+) -> Option<BTreeMap<u64, Latency>> {
+    let mut graph = DiGraphMap::<u64, ()>::new();
+    for node in nodes {
+        graph.add_node(*node);
+    }
+    for (source, target) in edges.keys() {
+        graph.add_edge(*source, *target, ());
+    }
+
+    let mut distances = BTreeMap::from([(source, Latency::zero())]);
+    for node in toposort(&graph, None).ok()? {
+        let Some(distance) = distances.get(&node).cloned() else {
+            continue;
+        };
+        for target in graph.neighbors_directed(node, Outgoing) {
+            let target_distance = distance.add(&edges[&(node, target)]);
+            distances
+                .entry(target)
+                .and_modify(|distance| *distance = distance.max(&target_distance))
+                .or_insert(target_distance);
         }
+    }
+    Some(distances)
+}
 
-        // if the entry and exit nodes are the same
-        if normal_cycle {
-            // if the outer block is not the normal outer block, we need to remove it
-            for outer_blocks in false_outer_blocks.values() {
-                condensed_graph.remove_node(outer_blocks);
-                printwarning!(
-                    "We are not considering the exit block {:x} as exit from the cycle {:x}",
-                    outer_blocks[0].leader,
-                    entry_block.leader
-                );
-            }
-        } else {
-            if false_outer_blocks.len() == 0 {
-                printwarning!(
-                    "There is no outer block for the cycle {:x}",
-                    entry_block.leader
-                );
-            } else {
-                if false_outer_blocks.len() > 1 {
-                    printwarning!(
-                        "There are more than one outer block for the cycle {:x} and we are using {:x}",
-                        entry_block.leader, exit_block.leader
-                    );
-                } else {
-                    exit_block = false_outer_blocks.keys().next().unwrap().clone();
+// The latency of every loop is computed bottom-up over the loop nesting forest: in the acyclic graph of a loop
+// (without its back edges) the inner loops are a single node, entered with the latency of their iterations.
+// Every exit edge costs the iterations so far plus the path to the exit, the part above the cost of
+// entering the loop is added on the exit edge. The outermost loops are the cycles of the condensed graph.
+// The latencies are formulas of the parameters of the loop bounds, evaluated with the PARAM_ env vars
+#[allow(clippy::too_many_arguments)]
+pub fn condensate_graph(
+    mut original_graph: MappedGraph,
+    loop_forest: &LoopForest,
+    entry_node_latency_map: &mut HashMap<u64, u64>,
+    formulas: &mut CondensedFormulas,
+    blocks: &BTreeMap<u64, Block>,
+    recursive_functions: &HashMap<u64, u64>, // function_address -> ret_address
+    latency_map: &mut HashMap<u64, Latency>, // ret_address -> latency
+    fictious_map: &mut HashMap<u64, u64>,    // fictious_address -> real_address
+) -> MappedCondensedGraph {
+    let parameters = parameters_from_env();
+    let mut condensed_graph = original_graph.condense_cycles();
+    let mut loop_costs = HashMap::<u64, LoopCost>::new(); // header -> cost
+    let mut graph_number = 0; // of the cycle_graph_N.dot files
+
+    for natural_loop in loop_forest.post_order() {
+        graph_number += 1;
+
+        let entry_block = &blocks[&natural_loop.header];
+
+        let max_cycles = cycle_bound(natural_loop.header, fictious_map, &parameters);
+        if natural_loop.parent.is_none()
+            && cycle_total_bound(natural_loop.header, fictious_map, &parameters).is_some()
+        {
+            printwarning!(
+                "The cycle {:x}{} is not inside another cycle -> its env var CYCLE_TOTAL_ is not considered",
+                natural_loop.header,
+                source_suffix(natural_loop.address)
+            );
+        }
+
+        // the blocks of an inner loop are represented by its header
+        let representative = |leader: u64| {
+            loop_forest
+                .child_containing(natural_loop, leader)
+                .unwrap_or(leader)
+        };
+        let child_cost = |node: u64| {
+            natural_loop
+                .children
+                .contains(&node)
+                .then(|| &loop_costs[&node])
+        };
+
+        // the inner loops with a total bound are entered without their iterations, they are added once below
+        let total_bounds = natural_loop
+            .children
+            .iter()
+            .filter_map(|child| {
+                Some((
+                    *child,
+                    cycle_total_bound(*child, fictious_map, &parameters)?,
+                ))
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        // create new graph with the blocks of the loop, acyclic
+        let nodes = natural_loop
+            .blocks
+            .iter()
+            .map(|leader| representative(*leader))
+            .collect::<BTreeSet<_>>();
+        let mut edges = BTreeMap::<(u64, u64), Latency>::new();
+        for leader in &natural_loop.blocks {
+            let block = &blocks[leader];
+            for target in block.get_targets() {
+                // the back edges are removed
+                if !natural_loop.blocks.contains(&target) || target == natural_loop.header {
+                    continue;
+                }
+
+                let source_node = representative(*leader);
+                let target_node = representative(target);
+                if source_node == target_node {
+                    continue;
                 }
+
+                let mut latency = match child_cost(target_node) {
+                    Some(cost) if total_bounds.contains_key(&target_node) => {
+                        cost.entry_without_iterations.clone()
+                    }
+                    Some(cost) => cost.entry.clone(),
+                    None => Latency::constant(u64::from(blocks[&target].get_latency_from(block))),
+                };
+                if let Some(cost) = child_cost(source_node) {
+                    latency = latency.add(&cost.exit(target));
+                }
+                let edge = edges
+                    .entry((source_node, target_node))
+                    .or_insert(latency.clone());
+                *edge = edge.max(&latency);
             }
         }
-
-        // make the cycle acyclic
-        for (source, target, _) in cycle_graph.edges_directed(entry_block, Incoming) {
-            cycle_graph.remove_edge(&source, &target);
+        let mut cycle_graph = MappedGraph::new();
+        for node in &nodes {
+            cycle_graph.add_node(blocks[node].clone());
+        }
+        for ((source, target), latency) in &edges {
+            cycle_graph.add_edge(
+                blocks[source].clone(),
+                blocks[target].clone(),
+                latency.cycles,
+            );
         }
 
         let digraph = cycle_graph.to_dot_graph();
-        let graph_number = COUNTER.load(Ordering::Relaxed);
-        let mut dot_file = std::fs::File::create(format!("cycle_graph_{graph_number}.dot"))
-            .expect("Unable to create file");
+        let mut dot_file = create_output_file(&format!("cycle_graph_{graph_number}.dot"));
         dot_file
             .write_all(digraph.as_bytes())
             .expect("Unable to write dot file");
 
-        println!(
+        printline!(
             "cycle_graph_{graph_number}.dot created",
             graph_number = graph_number
         );
 
         let entry_node_latency = entry_block.get_latency();
+        let header_latency = Latency::constant(u64::from(entry_node_latency));
+        let distances = match longest_distances(&nodes, &edges, natural_loop.header) {
+            Some(distances) => distances,
+            None => {
+                printline!(
+                    "ERROR: The cycle {:x}{} contains a cycle without a header -> the wcet is not reliable",
+                    entry_block.leader,
+                    source_suffix(natural_loop.address)
+                );
+                BTreeMap::from([(natural_loop.header, Latency::zero())])
+            }
+        };
+
+        // latency from the start of an iteration to the jump of the block to the target
+        let leave = |leader: u64, target: u64| {
+            let node = representative(leader);
+            let exit = child_cost(node).map_or(Latency::zero(), |cost| cost.exit(target));
+            distances
+                .get(&node)
+                .map(|distance| header_latency.add(distance).add(&exit))
+        };
+
+        let iteration = natural_loop
+            .back_edges
+            .iter()
+            .filter_map(|(source, header)| leave(*source, *header))
+            .fold(header_latency.clone(), |iteration, latency| {
+                iteration.max(&latency)
+            });
+        let inner_iterations =
+            total_bounds
+                .iter()
+                .fold(Latency::zero(), |iterations, (child, total_bound)| {
+                    iterations.add(&loop_costs[child].iteration.mul(total_bound))
+                });
+        let iterations = iteration.mul(&max_cycles).add(&inner_iterations);
+
+        // latencies of the last iteration, to the exit targets
+        let mut exit_latencies = BTreeMap::<u64, Latency>::new();
+        for (source, target) in &natural_loop.exit_edges {
+            if let Some(latency) = leave(*source, *target) {
+                exit_latencies
+                    .entry(*target)
+                    .and_modify(|exit_latency| *exit_latency = exit_latency.max(&latency))
+                    .or_insert(latency);
+            }
+        }
 
-        match cycle_graph.reconstruct_longest_path(
+        // the paths that end in the loop, at a block without successors or in an inner loop
+        let terminal_latency = distances
+            .iter()
+            .filter(|(node, _)| match child_cost(**node) {
+                Some(child) => child.terminal,
+                None => blocks[*node]
+                    .get_targets()
+                    .iter()
+                    .all(|target| !blocks.contains_key(target)),
+            })
+            .map(|(_, distance)| header_latency.add(distance))
+            .reduce(|latency, other| latency.max(&other));
+
+        let terminal = terminal_latency.is_some();
+        let min_exit_latency = exit_latencies
+            .values()
+            .all(|latency| latency.formula.as_constant().is_some())
+            .then(|| exit_latencies.values().min_by_key(|latency| latency.cycles))
+            .flatten()
+            .cloned();
+        let entry_latency = if let Some(terminal_latency) = terminal_latency {
+            terminal_latency
+        } else if !exit_latencies.is_empty() {
+            // with symbolic latencies the whole exit latency goes on the exit edge
+            min_exit_latency.unwrap_or(Latency::zero())
+        } else {
+            printwarning!(
+                "There is no outer block for the cycle {:x}{}",
+                entry_block.leader,
+                source_suffix(natural_loop.address)
+            );
+            // e.g. the returns of a recursive function, the last one leaves from the header
+            header_latency.clone()
+        };
+
+        let cost = LoopCost {
+            entry: iterations.add(&entry_latency),
+            entry_without_iterations: inner_iterations.add(&entry_latency),
+            exits: exit_latencies
+                .into_iter()
+                .map(|(target, latency)| (target, latency.saturating_sub(&entry_latency)))
+                .collect(),
+            terminal,
+            iteration,
+        };
+
+        record_recursion(
             entry_block,
-            &exit_block,
-            entry_node_latency as f32,
-            max_cycles,
-        ) {
-            Ok(cycle_node_latency) => {
-                let node_incoming_edges = condensed_graph.edges_directed(&condensed_node, Incoming);
-
-                let mut max_cycles = 1;
-
-                // check if it is a ret
-                if let Some(ExitJump::Ret(current_ret_address)) = entry_block.exit_jump {
-                    for (recursive_address, ret_address) in recursive_functions {
-                        if current_ret_address == *ret_address {
-                            let env_var_key = format!("RECURSIVE_0x{recursive_address:x}");
-                            if let Ok(recursive_var) = std::env::var(&env_var_key) {
-                                match recursive_var.parse::<u32>() {
-                                    Ok(recursive_var) => max_cycles = recursive_var,
-                                    Err(_) => {
-                                        panic!(
-                                            "The environment variable {} is not a valid number",
-                                            env_var_key
-                                        );
-                                    }
-                                }
-                            };
-                            printwarning!(
-                                "Found a recursive function at address 0x{recursive_address:x} -> {max_cycles} function iterations \
-                                considered for the wcet calculation. If you want to change this value, set the environment \
-                                variable {env_var_key}"
-                                );
-                        }
-                    }
-                    latency_map.insert(
-                        current_ret_address,
-                        cycle_node_latency as u32 * max_cycles - entry_node_latency,
-                    );
-                }
+            &cost.worst(),
+            entry_node_latency,
+            recursive_functions,
+            latency_map,
+            &parameters,
+        );
+        loop_costs.insert(natural_loop.header, cost);
+    }
 
-                if node_incoming_edges.is_empty() {
-                    // if the condensed node has no incoming edges, it is the entry node
-                    entry_node_latency_map
-                        .insert(condensed_node[0].leader, cycle_node_latency as u32);
-                // we choose [0] as reference for the condensed node for simplicity
-                } else {
-                    // if the condensed node has incoming edges, we need to update the edges
-                    for (source, target, _) in node_incoming_edges {
-                        condensed_graph.update_edge(&source, &target, cycle_node_latency);
-                    }
-                    // we use the entry_node_latency_map to save the latency of the entry node if it is a condensed node
-                    entry_node_latency_map
-                        .insert(condensed_node[0].leader, condensed_node[0].get_latency());
-                }
-            }
-            Err(_) => {
-                let mut condensed_cycle_graph = condensate_graph(
-                    cycle_graph.clone(),
-                    entry_node_latency_map,
-                    blocks,
-                    recursive_functions,
-                    latency_map,
-                    fictious_map,
+    // the outermost loops are condensed nodes: the cost of the loop goes on the incoming and outgoing edges
+    let condensed_cost = |node: &[Block]| {
+        node.iter().find_map(|block| {
+            loop_forest
+                .loops
+                .get(&block.leader)
+                .filter(|natural_loop| natural_loop.parent.is_none())
+                .map(|natural_loop| &loop_costs[&natural_loop.header])
+        })
+    };
+    for (source, target, weight) in condensed_graph.get_edges() {
+        let mut latency =
+            condensed_cost(&target).map_or(Latency::constant(weight), |cost| cost.entry.clone());
+        if let Some(cost) = condensed_cost(&source) {
+            let exit = target
+                .iter()
+                .map(|block| cost.exit(block.leader))
+                .fold(Latency::zero(), |exit, other| exit.max(&other));
+            latency = latency.add(&exit);
+        }
+        condensed_graph.update_edge(&source, &target, latency.cycles);
+        formulas
+            .edges
+            .insert((source[0].leader, target[0].leader), latency.formula);
+    }
+
+    for condensed_node in condensed_graph.get_nodes() {
+        let Some(cost) = condensed_cost(&condensed_node) else {
+            // a cycle of the condensed graph without a loop has no dominating header
+            if condensed_node.len() > 1 {
+                printline!(
+                    "ERROR: The cycle {:x}{} has no header -> the wcet is not reliable",
+                    condensed_node[0].leader,
+                    source_suffix(condensed_node[0].leader)
                 );
+            }
+            continue;
+        };
+
+        // if the condensed node has no incoming edges, it is an entry node
+        // we choose [0] as reference for the condensed node for simplicity
+        if condensed_graph
+            .edges_directed(&condensed_node, Incoming)
+            .is_empty()
+        {
+            entry_node_latency_map.insert(condensed_node[0].leader, cost.entry.cycles);
+            formulas
+                .entries
+                .insert(condensed_node[0].leader, cost.entry.formula.clone());
+        }
+    }
 
-                let condensed_cycle_graph_nodes = condensed_cycle_graph.get_nodes();
+    condensed_graph
+}
 
-                let entry_nodes = condensed_cycle_graph_nodes
-                    .iter()
-                    .filter(|node| {
-                        condensed_cycle_graph
-                            .edges_directed(node, Incoming)
-                            .is_empty()
-                    })
-                    .collect::<Vec<_>>();
-
-                let condensed_cycle_entry_node = entry_nodes[0].clone(); // as this is a cycle we are sure that it has only one entry node
-
-                let mut max_cycles = 1;
-
-                if let Some(real_entry_address) =
-                    fictious_map.get(&condensed_cycle_entry_node[0].leader)
-                {
-                    let env_var_key = format!("CYCLE_0x{real_entry_address:x}");
-                    if let Ok(cycle_var) = std::env::var(&env_var_key) {
-                        match cycle_var.parse::<u32>() {
-                            Ok(cycle_var) => max_cycles = cycle_var,
-                            Err(_) => {
-                                panic!(
-                                    "The environment variable {} is not a valid number",
-                                    env_var_key
-                                );
-                            }
-                        }
-                    };
-                }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch::{start_job_output, take_job_output};
+    use crate::testing::{block, disassemble, nop_block, x86_64};
 
-                let entry_node_latency =
-                    match entry_node_latency_map.get(&condensed_cycle_entry_node[0].leader) {
-                        // if the entry node is condensed, its latency is already in the map
-                        Some(latency) => *latency,
-                        None => condensed_cycle_entry_node[0].get_latency(),
-                    };
-
-                let outer_nodes = condensed_graph
-                    .neighbors_directed(&condensed_node, Outgoing)
-                    .to_owned();
-
-                let mut condensed_cycle_exit_node = condensed_cycle_entry_node.clone();
-
-                let mut false_outer_nodes = HashMap::<Vec<Block>, Vec<Block>>::new(); // exit_node -> outer_blocks
-
-                let mut normal_cycle = false;
-                // handling case where there are more than one outer block
-                for outer_blocks in outer_nodes {
-                    // handle case where outer block has more than one block --> it is a condensed node
-                    for outer_block in outer_blocks.clone() {
-                        if let Some(cycle_node) =
-                            condensed_cycle_graph.get_nodes().iter().find(|node| {
-                                // we assume that the exit_node is not condensed
-                                node[0].get_targets().contains(&outer_block.clone().leader)
-                            })
-                        {
-                            // we assume that the exit_node is not condensed
-                            if cycle_node[0].leader == condensed_cycle_entry_node[0].leader {
-                                normal_cycle = true;
-                            } else {
-                                false_outer_nodes.insert(cycle_node.clone(), outer_blocks.clone());
-                            }
-
-                            break;
-                        }
-                    }
-                }
+    fn conditional(taken: u64, not_taken: u64) -> Option<ExitJump> {
+        Some(ExitJump::ConditionalRelative { taken, not_taken })
+    }
 
-                // if the entry and exit nodes are the same
-                if normal_cycle {
-                    // if the outer block is not the normal outer block, we need to remove it
-                    for outer_blocks in false_outer_blocks.values() {
-                        condensed_cycle_graph.remove_node(outer_blocks);
-                        println!(
-                            "We are not considering the exit block {:x} as exit from the cycle {:x}",
-                            outer_blocks[0].leader,
-                            condensed_cycle_entry_node[0].leader
-                        );
-                    }
-                } else {
-                    //TODO
-                    if false_outer_blocks.len() == 0 || false_outer_blocks.len() > 1 {
-                        println!("check this case");
-                    } else {
-                        condensed_cycle_exit_node =
-                            false_outer_nodes.keys().next().unwrap().clone();
-                    }
-                }
+    fn blocks(blocks: Vec<Block>) -> BTreeMap<u64, Block> {
+        blocks
+            .into_iter()
+            .map(|block| (block.leader, block))
+            .collect()
+    }
 
-                let cycle_node_latency = condensed_cycle_graph
-                    .reconstruct_longest_path(
-                        &condensed_cycle_entry_node,
-                        &condensed_cycle_exit_node,
-                        entry_node_latency as f32,
-                        max_cycles,
-                    )
-                    .unwrap();
-
-                let mut max_rec_cycles = 1;
-
-                // check if it is a ret
-                if let Some(ExitJump::Ret(current_ret_address)) = entry_block.exit_jump {
-                    for (recursive_address, ret_address) in recursive_functions {
-                        if current_ret_address == *ret_address {
-                            let env_var_key = format!("RECURSIVE_0x{recursive_address:x}");
-                            if let Ok(recursive_var) = std::env::var(&env_var_key) {
-                                match recursive_var.parse::<u32>() {
-                                    Ok(recursive_var) => max_rec_cycles = recursive_var,
-                                    Err(_) => {
-                                        panic!(
-                                            "The environment variable {} is not a valid number",
-                                            env_var_key
-                                        );
-                                    }
-                                }
-                            };
-                            printwarning!(
-                                "Found a recursive function at address 0x{recursive_address:x} -> {max_rec_cycles} function iterations \
-                                considered for the wcet calculation. If you want to change this value, set the environment \
-                                variable {env_var_key}"
-                                );
-                        }
-                    }
-                    latency_map.insert(
-                        current_ret_address,
-                        cycle_node_latency as u32 * max_rec_cycles - entry_node_latency,
-                    );
-                }
+    fn targets(blocks: &BTreeMap<u64, Block>, leader: u64) -> Vec<u64> {
+        blocks[&leader].get_targets()
+    }
 
-                let node_incoming_edges = condensed_graph.edges_directed(&condensed_node, Incoming);
-                if node_incoming_edges.is_empty() {
-                    // if the node has no incoming edges, it is an entry node
-                    entry_node_latency_map
-                        .insert(condensed_node[0].leader, cycle_node_latency as u32);
-                // we chose [0] as reference for the condensed node for simplicity
-                } else {
-                    for (source, target, _) in node_incoming_edges {
-                        condensed_graph.update_edge(&source, &target, cycle_node_latency);
-                    }
-                    entry_node_latency_map
-                        .insert(condensed_node[0].leader, condensed_node[0].get_latency());
-                }
+    // a block of nops, one clock cycle each
+    fn nops(leader: u64, count: usize, exit_jump: Option<ExitJump>) -> Block {
+        block(
+            disassemble(&x86_64(), leader, &vec![0x90; count]),
+            exit_jump,
+        )
+    }
 
-                let digraph = condensed_cycle_graph.to_dot_graph();
-                let mut dot_file =
-                    std::fs::File::create(format!("condensed_cycle_graph_{graph_number}.dot"))
-                        .expect("Unable to create file");
-                dot_file
-                    .write_all(digraph.as_bytes())
-                    .expect("Unable to write dot file");
-            }
+    // the wcet from the entry and its formula as in the analysis, the debug files go to a temporary directory
+    fn wcet(blocks: &BTreeMap<u64, Block>, entry: u64) -> (u64, String) {
+        let dir = std::env::temp_dir().join(format!("asm-analyzer-cycle-0x{entry:x}"));
+        std::fs::create_dir_all(&dir).unwrap();
+        start_job_output(dir);
+        let graph = MappedGraph::from_blocks(blocks);
+        let loop_forest = LoopForest::from_graph(&graph);
+        let mut entry_latencies = HashMap::new();
+        let mut formulas = CondensedFormulas::default();
+        let condensed_graph = condensate_graph(
+            graph,
+            &loop_forest,
+            &mut entry_latencies,
+            &mut formulas,
+            blocks,
+            &HashMap::new(),
+            &mut HashMap::new(),
+            &mut HashMap::new(),
+        );
+        take_job_output();
+
+        let entry_node = condensed_graph
+            .get_nodes()
+            .into_iter()
+            .find(|node| node[0].leader == entry)
+            .unwrap();
+        let entry_latency = entry_latencies
+            .get(&entry)
+            .copied()
+            .unwrap_or(u64::from(blocks[&entry].get_latency()));
+        let formula = formulas
+            .entries
+            .get(&entry)
+            .cloned()
+            .unwrap_or(Formula::constant(entry_latency as f64))
+            .add(&condensed_graph.longest_path_formulas(&formulas.edges)[&entry]);
+        let wcet = add_cycles(
+            entry_latency,
+            condensed_graph.longest_path(&entry_node).unwrap(),
+        );
+        (wcet, formula.to_string())
+    }
+
+    #[test]
+    fn two_entry_cycle_is_split() {
+        // the cycle 0x20 <-> 0x30 is entered at both blocks from 0x10
+        let mut blocks = blocks(vec![
+            nop_block(0x10, conditional(0x20, 0x30)),
+            nop_block(0x20, Some(ExitJump::UnconditionalRelative(0x30))),
+            nop_block(0x30, conditional(0x20, 0x40)),
+            nop_block(0x40, None),
+        ]);
+        let mut fictious_map = HashMap::new();
+        split_irreducible_cycles(&mut blocks, Some(0x10), &mut fictious_map);
+
+        // the copy of the cycle starts after the last leader and is entered only at the copy of 0x30
+        assert_eq!(fictious_map, HashMap::from([(0x42, 0x20), (0x43, 0x30)]));
+        assert_eq!(targets(&blocks, 0x10), [0x20, 0x43]);
+        assert_eq!(targets(&blocks, 0x20), [0x30]);
+        assert_eq!(targets(&blocks, 0x30), [0x20, 0x40]);
+        assert_eq!(targets(&blocks, 0x42), [0x43]);
+        assert_eq!(targets(&blocks, 0x43), [0x42, 0x40]);
+
+        let loops = MappedGraph::from_blocks(&blocks).loops();
+        assert_eq!(loops.len(), 2);
+        for (_, members) in loops {
+            assert_eq!(cycle_entries(&blocks, &members, Some(0x10)).len(), 1);
         }
     }
 
-    condensed_graph
+    #[test]
+    fn analysis_entry_keeps_the_original_cycle() {
+        // the analysis starts at 0x50 inside the cycle, 0x60 is entered from 0x70
+        let mut blocks = blocks(vec![
+            nop_block(0x50, Some(ExitJump::UnconditionalRelative(0x60))),
+            nop_block(0x60, conditional(0x50, 0x80)),
+            nop_block(0x70, Some(ExitJump::UnconditionalRelative(0x60))),
+            nop_block(0x80, None),
+        ]);
+        let mut fictious_map = HashMap::new();
+        split_irreducible_cycles(&mut blocks, Some(0x50), &mut fictious_map);
+
+        assert_eq!(fictious_map, HashMap::from([(0x82, 0x50), (0x83, 0x60)]));
+        assert_eq!(targets(&blocks, 0x50), [0x60]);
+        assert_eq!(targets(&blocks, 0x70), [0x83]);
+        assert_eq!(targets(&blocks, 0x83), [0x82, 0x80]);
+    }
+
+    #[test]
+    fn reducible_cycle_is_not_split() {
+        let mut blocks = blocks(vec![
+            nop_block(0x90, Some(ExitJump::Next(0xa0))),
+            nop_block(0xa0, conditional(0xa0, 0xb0)),
+            nop_block(0xb0, None),
+        ]);
+        let mut fictious_map = HashMap::new();
+        split_irreducible_cycles(&mut blocks, Some(0x90), &mut fictious_map);
+
+        assert!(fictious_map.is_empty());
+        assert_eq!(blocks.len(), 3);
+    }
+
+    #[test]
+    fn each_exit_of_a_loop_has_its_own_cost() {
+        // the cheap path leaves from the header 0x1020 to the long block 0x1050,
+        // the expensive path leaves after the body 0x1030 to the short block 0x1060
+        std::env::set_var("CYCLE_0x1020", "3");
+        let blocks = blocks(vec![
+            nops(0x1010, 1, Some(ExitJump::Next(0x1020))),
+            nops(0x1020, 1, conditional(0x1030, 0x1050)),
+            nops(0x1030, 4, conditional(0x1020, 0x1060)),
+            nops(0x1050, 6, None),
+            nops(0x1060, 1, None),
+        ]);
+
+        // 1 + 3 iterations of 5 + max(1 + 6, 1 + 4 + 1): the longest exit is not paired with the longest successor
+        assert_eq!(wcet(&blocks, 0x1010), (23, "23".to_string()));
+    }
+
+    #[test]
+    fn exit_costs_with_a_symbolic_bound() {
+        std::env::set_var("CYCLE_0x1120", "exits");
+        std::env::set_var("PARAM_exits", "10");
+        let blocks = blocks(vec![
+            nops(0x1110, 1, Some(ExitJump::Next(0x1120))),
+            nops(0x1120, 1, conditional(0x1130, 0x1150)),
+            nops(0x1130, 4, conditional(0x1120, 0x1160)),
+            nops(0x1150, 6, None),
+            nops(0x1160, 1, None),
+        ]);
+
+        assert_eq!(wcet(&blocks, 0x1110), (58, "5*exits + 8".to_string()));
+    }
+
+    // the loop at base + 0x30 inside the loop at base + 0x20, an inner iteration takes 3 clock cycles
+    fn nested_loops(base: u64) -> BTreeMap<u64, Block> {
+        blocks(vec![
+            nops(base + 0x10, 1, Some(ExitJump::Next(base + 0x20))),
+            nops(base + 0x20, 1, conditional(base + 0x30, base + 0x60)),
+            nops(base + 0x30, 1, conditional(base + 0x40, base + 0x50)),
+            nops(
+                base + 0x40,
+                2,
+                Some(ExitJump::UnconditionalRelative(base + 0x30)),
+            ),
+            nops(
+                base + 0x50,
+                1,
+                Some(ExitJump::UnconditionalRelative(base + 0x20)),
+            ),
+            nops(base + 0x60, 1, None),
+        ])
+    }
+
+    #[test]
+    fn total_bound_replaces_the_inner_iterations_of_every_outer_iteration() {
+        std::env::set_var("CYCLE_0x2020", "4");
+        std::env::set_var("CYCLE_0x2030", "4");
+        let blocks = nested_loops(0x2000);
+        // 1 + 4 outer iterations of (1 + 4 inner iterations of 3 + 1 + 1) + 1 + 1
+        assert_eq!(wcet(&blocks, 0x2010).0, 63);
+
+        std::env::set_var("CYCLE_0x2120", "4");
+        std::env::set_var("CYCLE_0x2130", "4");
+        std::env::set_var("CYCLE_TOTAL_0x2130", "10");
+        let blocks = nested_loops(0x2100);
+        // 1 + 4 outer iterations of (1 + 1 + 1) + 10 inner iterations of 3 + 1 + 1
+        assert_eq!(wcet(&blocks, 0x2110).0, 45);
+    }
+
+    #[test]
+    fn total_bound_as_a_formula() {
+        std::env::set_var("CYCLE_0x2220", "rows");
+        std::env::set_var("CYCLE_0x2230", "rows");
+        std::env::set_var("CYCLE_TOTAL_0x2230", "rows*(rows + 1)/2");
+        std::env::set_var("PARAM_rows", "4");
+        let blocks = nested_loops(0x2200);
+
+        assert_eq!(
+            wcet(&blocks, 0x2210),
+            (45, "1.5*rows^2 + 4.5*rows + 3".to_string())
+        );
+    }
+
+    #[test]
+    fn total_bound_of_an_outermost_loop_is_not_considered() {
+        std::env::set_var("CYCLE_0x2320", "4");
+        std::env::set_var("CYCLE_TOTAL_0x2320", "1");
+        std::env::set_var("CYCLE_0x2330", "4");
+        let blocks = nested_loops(0x2300);
+
+        assert_eq!(wcet(&blocks, 0x2310).0, 63);
+    }
 }
@@ -0,0 +1,400 @@
+use std::collections::{BTreeMap, HashMap};
+
+use capstone::Arch;
+
+use crate::block::Block;
+use crate::instruction::{Instruction, Operand};
+use crate::timing::add_latency;
+
+// env variable format: EXTERNAL_SYMBOL=latency (in cycles), e.g. EXTERNAL_PRINTF=200
+// the latency can depend on an argument of the call: EXTERNAL_MEMCPY=10+2*ARG3
+// EXTERNAL_BCET_SYMBOL sets the best case latency, by default it is the same as the worst case
+// EXTERNAL_SYMBOL_ARGn bounds the argument when it is not a constant at the call site
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct CostFormula {
+    base: u32,
+    factor: u32,
+    argument: Option<usize>, // 1-based index of the argument
+}
+
+#[derive(Debug, Clone)]
+pub struct ExternalCall {
+    pub address: u64,
+    pub symbol: String,
+    pub wcet: u32,
+    pub bcet: u32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ExternalCallReport {
+    pub calls: Vec<ExternalCall>,
+    pub unmodeled: BTreeMap<String, Vec<u64>>, // symbol -> call addresses
+}
+
+// add the latency of every external call to its call instruction
+pub fn annotate_external_calls(
+    blocks: &mut BTreeMap<u64, Block>,
+    external_calls: &HashMap<u64, String>, // call_insn_address -> external symbol
+    arch: Arch,
+) -> ExternalCallReport {
+    let mut report = ExternalCallReport::default();
+
+    for block in blocks.values_mut() {
+        for index in 0..block.instructions.len() {
+            let address = block.instructions[index].address;
+            let Some(symbol) = external_calls.get(&address) else {
+                continue;
+            };
+
+            let env_var_key = format!("EXTERNAL_{}", sanitize_symbol(symbol));
+            let Some(wcet_formula) = get_cost_formula(&env_var_key) else {
+                report
+                    .unmodeled
+                    .entry(symbol.clone())
+                    .or_default()
+                    .push(address);
+                continue;
+            };
+            let bcet_formula =
+                get_cost_formula(&format!("EXTERNAL_BCET_{}", sanitize_symbol(symbol)))
+                    .unwrap_or(wcet_formula);
+
+            let preceding = &block.instructions[..index];
+            let wcet = evaluate(&wcet_formula, &env_var_key, preceding, arch);
+            let bcet = evaluate(&bcet_formula, &env_var_key, preceding, arch);
+
+            match (wcet, bcet) {
+                (Some(wcet), Some(bcet)) => {
+                    block.instructions[index].add_latency(wcet);
+                    block.instructions[index].bcet_latency =
+                        add_latency(block.instructions[index].bcet_latency, bcet);
+                    report.calls.push(ExternalCall {
+                        address,
+                        symbol: symbol.clone(),
+                        wcet,
+                        bcet,
+                    });
+                }
+                _ => {
+                    // the argument is unknown and not bounded
+                    report
+                        .unmodeled
+                        .entry(symbol.clone())
+                        .or_default()
+                        .push(address);
+                }
+            }
+        }
+    }
+
+    report
+}
+
+fn get_cost_formula(env_var_key: &str) -> Option<CostFormula> {
+    let value = std::env::var(env_var_key).ok()?;
+
+    let parse_number = |number: &str| match number.trim().parse::<u32>() {
+        Ok(number) => number,
+        Err(_) => panic!("The environment variable {env_var_key} is not a valid cost"),
+    };
+
+    match value.split_once('+') {
+        None => Some(CostFormula {
+            base: parse_number(&value),
+            factor: 0,
+            argument: None,
+        }),
+        Some((base, argument_term)) => {
+            let Some((factor, argument)) = argument_term.split_once('*') else {
+                panic!("The environment variable {env_var_key} is not a valid cost");
+            };
+            let argument = match argument.trim().strip_prefix("ARG") {
+                Some(index) => parse_number(index) as usize,
+                None => panic!("The environment variable {env_var_key} is not a valid cost"),
+            };
+
+            Some(CostFormula {
+                base: parse_number(base),
+                factor: parse_number(factor),
+                argument: Some(argument),
+            })
+        }
+    }
+}
+
+fn evaluate(
+    formula: &CostFormula,
+    env_var_key: &str,
+    preceding: &[Instruction],
+    arch: Arch,
+) -> Option<u32> {
+    let Some(argument) = formula.argument else {
+        return Some(formula.base);
+    };
+
+    let argument_value = match get_constant_argument(preceding, argument, arch) {
+        Some(value) => value,
+        None => {
+            let bound_key = format!("{env_var_key}_ARG{argument}");
+            match std::env::var(&bound_key).ok()?.parse::<u32>() {
+                Ok(bound) => bound,
+                Err(_) => panic!("The environment variable {bound_key} is not a valid number"),
+            }
+        }
+    };
+
+    let latency = formula
+        .factor
+        .checked_mul(argument_value)
+        .and_then(|latency| latency.checked_add(formula.base));
+    match latency {
+        Some(latency) => Some(latency),
+        None => {
+            panic!("The latency of {env_var_key} overflows u32 with the argument {argument_value}")
+        }
+    }
+}
+
+// the value of the argument if the last instruction writing its register before the call is a constant move
+fn get_constant_argument(preceding: &[Instruction], argument: usize, arch: Arch) -> Option<u32> {
+    let registers = get_argument_registers(arch, argument);
+
+    for instruction in preceding.iter().rev() {
+        // an earlier call may change the argument registers
+        if is_call(instruction) {
+            return None;
+        }
+        if !instruction
+            .regs_write
+            .iter()
+            .any(|reg| registers.contains(&reg.as_str()))
+        {
+            continue;
+        }
+
+        return match (
+            instruction.mnemonic.as_str(),
+            instruction.operands.as_slice(),
+        ) {
+            // the writes of 8 and 16 bits keep the other bits of the register
+            ("mov" | "movs" | "li" | "c.li", [Operand::Reg(reg), Operand::Imm(value)])
+                if !X86_PARTIAL_REGISTERS.contains(&reg.as_str()) =>
+            {
+                u32::try_from(*value).ok()
+            }
+            // li of RISC-V
+            ("addi", [_, Operand::Reg(zero), Operand::Imm(value)]) if zero == "zero" => {
+                u32::try_from(*value).ok()
+            }
+            _ => None,
+        };
+    }
+
+    None
+}
+
+fn is_call(instruction: &Instruction) -> bool {
+    let mnemonic = instruction.mnemonic.as_str();
+    mnemonic.starts_with("call")
+        || matches!(
+            mnemonic,
+            "bl" | "blx" | "blr" | "jal" | "jalr" | "c.jal" | "c.jalr"
+        )
+}
+
+const X86_PARTIAL_REGISTERS: &[&str] = &[
+    "di", "dil", "si", "sil", "dx", "dl", "cx", "cl", "r8w", "r8b", "r9w", "r9b",
+];
+
+fn get_argument_registers(arch: Arch, argument: usize) -> Vec<&'static str> {
+    let registers: &[&[&str]] = match arch {
+        Arch::X86 => &[
+            &["rdi", "edi", "di", "dil"],
+            &["rsi", "esi", "si", "sil"],
+            &["rdx", "edx", "dx", "dl"],
+            &["rcx", "ecx", "cx", "cl"],
+            &["r8", "r8d", "r8w", "r8b"],
+            &["r9", "r9d", "r9w", "r9b"],
+        ],
+        Arch::ARM => &[&["r0"], &["r1"], &["r2"], &["r3"]],
+        Arch::ARM64 => &[
+            &["x0", "w0"],
+            &["x1", "w1"],
+            &["x2", "w2"],
+            &["x3", "w3"],
+            &["x4", "w4"],
+            &["x5", "w5"],
+            &["x6", "w6"],
+            &["x7", "w7"],
+        ],
+        Arch::RISCV => &[
+            &["a0"],
+            &["a1"],
+            &["a2"],
+            &["a3"],
+            &["a4"],
+            &["a5"],
+            &["a6"],
+            &["a7"],
+        ],
+        _ => &[],
+    };
+
+    argument
+        .checked_sub(1)
+        .and_then(|index| registers.get(index))
+        .map(|registers| registers.to_vec())
+        .unwrap_or_default()
+}
+
+// symbols like memcpy@GLIBC_2.14 can't be used as env variable names
+fn sanitize_symbol(symbol: &str) -> String {
+    symbol
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+impl std::fmt::Display for ExternalCallReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for call in &self.calls {
+            writeln!(
+                f,
+                "External call to {} at 0x{:x}: WCET {} BCET {} clock cycles",
+                call.symbol, call.address, call.wcet, call.bcet
+            )?;
+        }
+        for (symbol, addresses) in &self.unmodeled {
+            let addresses = addresses
+                .iter()
+                .map(|address| format!("0x{address:x}"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            writeln!(
+                f,
+                "WARNING: Unmodeled external call to {symbol} at {addresses} -> 0 cycles considered for the wcet calculation. \
+                If you want to change the value, please set the env var EXTERNAL_{}",
+                sanitize_symbol(symbol)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{disassemble, riscv32, x86_64};
+
+    #[test]
+    fn parse_cost_formulas() {
+        std::env::set_var("EXTERNAL_TEST_CONSTANT", "200");
+        std::env::set_var("EXTERNAL_TEST_ARGUMENT", " 10 + 2*ARG3 ");
+        assert_eq!(
+            get_cost_formula("EXTERNAL_TEST_CONSTANT"),
+            Some(CostFormula {
+                base: 200,
+                factor: 0,
+                argument: None,
+            })
+        );
+        assert_eq!(
+            get_cost_formula("EXTERNAL_TEST_ARGUMENT"),
+            Some(CostFormula {
+                base: 10,
+                factor: 2,
+                argument: Some(3),
+            })
+        );
+        assert_eq!(get_cost_formula("EXTERNAL_TEST_MISSING"), None);
+
+        for (key, value) in [
+            ("EXTERNAL_TEST_NEGATIVE", "-1"),
+            ("EXTERNAL_TEST_NO_FACTOR", "10+ARG1"),
+            ("EXTERNAL_TEST_NO_ARGUMENT", "10+2*N"),
+        ] {
+            std::env::set_var(key, value);
+            assert!(std::panic::catch_unwind(|| get_cost_formula(key)).is_err());
+        }
+    }
+
+    #[test]
+    fn evaluate_with_the_argument_bound() {
+        std::env::set_var("EXTERNAL_TEST_BOUNDED_ARG1", "100");
+        let formula = CostFormula {
+            base: 10,
+            factor: 2,
+            argument: Some(1),
+        };
+        assert_eq!(
+            evaluate(&formula, "EXTERNAL_TEST_BOUNDED", &[], Arch::X86),
+            Some(210)
+        );
+        assert_eq!(
+            evaluate(&formula, "EXTERNAL_TEST_UNBOUNDED", &[], Arch::X86),
+            None
+        );
+
+        let overflow = CostFormula {
+            base: 1,
+            factor: u32::MAX,
+            argument: Some(1),
+        };
+        assert!(std::panic::catch_unwind(|| {
+            evaluate(&overflow, "EXTERNAL_TEST_BOUNDED", &[], Arch::X86)
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn constant_argument_of_the_last_write() {
+        let x86 = |code: &[u8]| {
+            let preceding = disassemble(&x86_64(), 0x1000, code);
+            get_constant_argument(&preceding, 1, Arch::X86)
+        };
+        // mov edi, 5
+        assert_eq!(x86(&[0xbf, 0x05, 0x00, 0x00, 0x00]), Some(5));
+        // mov edi, 5; xor eax, eax; mov esi, 7
+        assert_eq!(
+            x86(&[0xbf, 0x05, 0x00, 0x00, 0x00, 0x31, 0xc0, 0xbe, 0x07, 0x00, 0x00, 0x00]),
+            Some(5)
+        );
+        // mov edi, 5; mov dil, 5
+        assert_eq!(x86(&[0xbf, 0x05, 0x00, 0x00, 0x00, 0x40, 0xb7, 0x05]), None);
+        // mov di, 5
+        assert_eq!(x86(&[0x66, 0xbf, 0x05, 0x00]), None);
+        // mov edi, 5; add edi, 1
+        assert_eq!(x86(&[0xbf, 0x05, 0x00, 0x00, 0x00, 0x83, 0xc7, 0x01]), None);
+        // mov edi, 5; lea rdi, [rsp + 8]
+        assert_eq!(
+            x86(&[0xbf, 0x05, 0x00, 0x00, 0x00, 0x48, 0x8d, 0x7c, 0x24, 0x08]),
+            None
+        );
+        // mov edi, 5; call
+        assert_eq!(
+            x86(&[0xbf, 0x05, 0x00, 0x00, 0x00, 0xe8, 0x00, 0x00, 0x00, 0x00]),
+            None
+        );
+
+        let riscv = |code: &[u8], argument: usize| {
+            let preceding = disassemble(&riscv32(), 0x1000, code);
+            get_constant_argument(&preceding, argument, Arch::RISCV)
+        };
+        // li a0, 7; li a1, 3
+        let code = [0x13, 0x05, 0x70, 0x00, 0x93, 0x05, 0x30, 0x00];
+        assert_eq!(riscv(&code, 1), Some(7));
+        assert_eq!(riscv(&code, 2), Some(3));
+        // li a0, 7; addi a0, a0, 1
+        assert_eq!(
+            riscv(&[0x13, 0x05, 0x70, 0x00, 0x13, 0x05, 0x15, 0x00], 1),
+            None
+        );
+    }
+}
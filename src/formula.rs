@@ -0,0 +1,519 @@
+// Symbolic latencies: the loop bounds can be expressions over named parameters (e.g. CYCLE_0x1012=len/4+1),
+// with the values of the parameters in the PARAM_ env vars (e.g. PARAM_len=64).
+// A formula is the maximum of polynomials over the parameters, the parameters are never negative:
+// a polynomial with all the coefficients lower than another one is dropped from the maximum.
+// The divisions are real divisions, an upper bound of the integer ones.
+use std::collections::{BTreeMap, BTreeSet};
+
+// beyond this number of polynomials the maximum is replaced by the maximum of every coefficient
+const MAX_POLYNOMIALS: usize = 16;
+
+// every integer up to this value is exactly an f64
+const MAX_EXACT_VALUE: f64 = (1u64 << f64::MANTISSA_DIGITS) as f64;
+
+type Monomial = BTreeMap<String, u32>; // parameter -> exponent
+
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Polynomial {
+    terms: BTreeMap<Monomial, f64>, // without the zero coefficients
+}
+
+impl Polynomial {
+    fn constant(value: f64) -> Self {
+        let mut polynomial = Polynomial::default();
+        polynomial.add_term(Monomial::new(), value);
+        polynomial
+    }
+
+    fn parameter(name: &str) -> Self {
+        let mut polynomial = Polynomial::default();
+        polynomial.add_term(Monomial::from([(name.to_string(), 1)]), 1.0);
+        polynomial
+    }
+
+    fn add_term(&mut self, monomial: Monomial, coefficient: f64) {
+        let sum = self.terms.get(&monomial).copied().unwrap_or(0.0) + coefficient;
+        if sum == 0.0 {
+            self.terms.remove(&monomial);
+        } else {
+            self.terms.insert(monomial, sum);
+        }
+    }
+
+    fn as_constant(&self) -> Option<f64> {
+        match self.terms.len() {
+            0 => Some(0.0),
+            1 => self.terms.get(&Monomial::new()).copied(),
+            _ => None,
+        }
+    }
+
+    fn coefficient(&self, monomial: &Monomial) -> f64 {
+        self.terms.get(monomial).copied().unwrap_or(0.0)
+    }
+
+    fn add(&self, other: &Polynomial) -> Polynomial {
+        let mut sum = self.clone();
+        for (monomial, coefficient) in &other.terms {
+            sum.add_term(monomial.clone(), *coefficient);
+        }
+        sum
+    }
+
+    fn mul(&self, other: &Polynomial) -> Polynomial {
+        let mut product = Polynomial::default();
+        for (monomial, coefficient) in &self.terms {
+            for (other_monomial, other_coefficient) in &other.terms {
+                let mut monomial = monomial.clone();
+                for (name, exponent) in other_monomial {
+                    *monomial.entry(name.clone()).or_insert(0) += exponent;
+                }
+                product.add_term(monomial, coefficient * other_coefficient);
+            }
+        }
+        product
+    }
+
+    fn scale(&self, factor: f64) -> Polynomial {
+        self.mul(&Polynomial::constant(factor))
+    }
+
+    // lower or equal for all the non-negative values of the parameters
+    fn dominated_by(&self, other: &Polynomial) -> bool {
+        self.terms
+            .keys()
+            .chain(other.terms.keys())
+            .all(|monomial| self.coefficient(monomial) <= other.coefficient(monomial))
+    }
+
+    fn evaluate(&self, parameters: &BTreeMap<String, f64>) -> Option<f64> {
+        let mut value = 0.0;
+        for (monomial, coefficient) in &self.terms {
+            let mut term = *coefficient;
+            for (name, exponent) in monomial {
+                term *= parameters.get(name)?.powi(*exponent as i32);
+            }
+            value += term;
+        }
+        Some(value)
+    }
+}
+
+impl std::fmt::Display for Polynomial {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.terms.is_empty() {
+            return write!(f, "0");
+        }
+
+        // the highest degrees first
+        let mut terms = self.terms.iter().collect::<Vec<_>>();
+        terms.sort_by_key(|(monomial, _)| std::cmp::Reverse(monomial.values().sum::<u32>()));
+
+        for (i, (monomial, coefficient)) in terms.into_iter().enumerate() {
+            let sign = if *coefficient < 0.0 { "-" } else { "+" };
+            if i > 0 {
+                write!(f, " {sign} ")?;
+            } else if *coefficient < 0.0 {
+                write!(f, "-")?;
+            }
+
+            let coefficient = coefficient.abs();
+            let mut factors = Vec::new();
+            if coefficient != 1.0 || monomial.is_empty() {
+                factors.push(format!("{coefficient}"));
+            }
+            for (name, exponent) in monomial {
+                if *exponent == 1 {
+                    factors.push(name.clone());
+                } else {
+                    factors.push(format!("{name}^{exponent}"));
+                }
+            }
+            write!(f, "{}", factors.join("*"))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Formula {
+    polynomials: Vec<Polynomial>, // maximum of the polynomials, never empty
+}
+
+impl Formula {
+    pub fn constant(value: f64) -> Self {
+        Formula {
+            polynomials: vec![Polynomial::constant(value)],
+        }
+    }
+
+    pub fn zero() -> Self {
+        Formula::constant(0.0)
+    }
+
+    pub fn as_constant(&self) -> Option<f64> {
+        match self.polynomials.as_slice() {
+            [polynomial] => polynomial.as_constant(),
+            _ => None,
+        }
+    }
+
+    pub fn parameters(&self) -> BTreeSet<String> {
+        self.polynomials
+            .iter()
+            .flat_map(|polynomial| polynomial.terms.keys())
+            .flat_map(|monomial| monomial.keys().cloned())
+            .collect()
+    }
+
+    fn from_polynomials(polynomials: Vec<Polynomial>) -> Self {
+        // the constants are merged, then the dominated polynomials are dropped
+        let (constants, mut polynomials): (Vec<_>, Vec<_>) = polynomials
+            .into_iter()
+            .partition(|polynomial| polynomial.as_constant().is_some());
+        if let Some(constant) = constants
+            .iter()
+            .filter_map(Polynomial::as_constant)
+            .reduce(f64::max)
+        {
+            polynomials.push(Polynomial::constant(constant));
+        }
+
+        let mut kept: Vec<Polynomial> = Vec::new();
+        for polynomial in polynomials {
+            if kept.iter().any(|other| polynomial.dominated_by(other)) {
+                continue;
+            }
+            kept.retain(|other| !other.dominated_by(&polynomial));
+            kept.push(polynomial);
+        }
+
+        if kept.len() > MAX_POLYNOMIALS {
+            let mut bound = Polynomial::default();
+            let monomials = kept
+                .iter()
+                .flat_map(|polynomial| polynomial.terms.keys().cloned())
+                .collect::<BTreeSet<_>>();
+            for monomial in monomials {
+                let coefficient = kept
+                    .iter()
+                    .map(|polynomial| polynomial.coefficient(&monomial))
+                    .fold(f64::MIN, f64::max);
+                bound.add_term(monomial, coefficient);
+            }
+            kept = vec![bound];
+        }
+
+        if kept.is_empty() {
+            kept.push(Polynomial::default());
+        }
+        Formula { polynomials: kept }
+    }
+
+    pub fn add(&self, other: &Formula) -> Formula {
+        Formula::from_polynomials(
+            self.polynomials
+                .iter()
+                .flat_map(|a| other.polynomials.iter().map(move |b| a.add(b)))
+                .collect(),
+        )
+    }
+
+    // both the formulas are never negative
+    pub fn mul(&self, other: &Formula) -> Formula {
+        Formula::from_polynomials(
+            self.polynomials
+                .iter()
+                .flat_map(|a| other.polynomials.iter().map(move |b| a.mul(b)))
+                .collect(),
+        )
+    }
+
+    pub fn max(&self, other: &Formula) -> Formula {
+        Formula::from_polynomials(
+            self.polynomials
+                .iter()
+                .chain(other.polynomials.iter())
+                .cloned()
+                .collect(),
+        )
+    }
+
+    // an upper bound of max(self - other, 0): exact for the constants, self for the others
+    pub fn saturating_sub(&self, other: &Formula) -> Formula {
+        match (self.as_constant(), other.as_constant()) {
+            (Some(a), Some(b)) => Formula::constant((a - b).max(0.0)),
+            _ => self.clone(),
+        }
+    }
+
+    pub fn evaluate(&self, parameters: &BTreeMap<String, f64>) -> Option<f64> {
+        self.polynomials
+            .iter()
+            .map(|polynomial| polynomial.evaluate(parameters))
+            .try_fold(f64::MIN, |max, value| Some(max.max(value?)))
+    }
+
+    // the value rounded up (e.g. of a loop bound), the missing parameters count as zero
+    pub fn value(&self, parameters: &BTreeMap<String, f64>) -> u64 {
+        let mut parameters = parameters.clone();
+        for name in self.parameters() {
+            parameters.entry(name).or_insert(0.0);
+        }
+        // beyond 2^53 the f64 values are not exact integers, the rounding could go down
+        let value = self.evaluate(&parameters).unwrap_or(0.0).max(0.0);
+        if value.is_nan() || value > MAX_EXACT_VALUE {
+            panic!("The value {value} of {self} is beyond the exact integers of f64");
+        }
+        value.ceil() as u64
+    }
+
+    // expression of numbers, parameters, + - * / and parentheses (the divisors are constants)
+    pub fn parse(expression: &str) -> Option<Formula> {
+        let tokens = tokenize(expression)?;
+        let mut position = 0;
+        let polynomial = parse_sum(&tokens, &mut position)?;
+        (position == tokens.len()).then(|| Formula::from_polynomials(vec![polynomial]))
+    }
+}
+
+impl std::fmt::Display for Formula {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let [polynomial] = self.polynomials.as_slice() {
+            return write!(f, "{polynomial}");
+        }
+        let polynomials = self
+            .polynomials
+            .iter()
+            .map(|polynomial| polynomial.to_string())
+            .collect::<Vec<_>>();
+        write!(f, "max({})", polynomials.join(", "))
+    }
+}
+
+// the values of the parameters, from the PARAM_ env vars
+pub fn parameters_from_env() -> BTreeMap<String, f64> {
+    let mut parameters = BTreeMap::new();
+    for (key, value) in std::env::vars() {
+        if let Some(name) = key.strip_prefix("PARAM_") {
+            match value.parse::<f64>() {
+                Ok(value) if value >= 0.0 => {
+                    parameters.insert(name.to_string(), value);
+                }
+                _ => panic!("The environment variable {} is not a valid number", key),
+            }
+        }
+    }
+    parameters
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Name(String),
+    Symbol(char),
+}
+
+fn tokenize(expression: &str) -> Option<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = expression.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        if "+-*/()".contains(c) {
+            tokens.push(Token::Symbol(c));
+        } else if c.is_ascii_digit() || c == '.' {
+            let mut number = c.to_string();
+            while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '.') {
+                number.push(c);
+            }
+            let value = match number.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16).ok()? as f64,
+                None => number.parse::<f64>().ok()?,
+            };
+            tokens.push(Token::Number(value));
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let mut name = c.to_string();
+            while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+                name.push(c);
+            }
+            tokens.push(Token::Name(name));
+        } else {
+            return None;
+        }
+    }
+    Some(tokens)
+}
+
+fn parse_sum(tokens: &[Token], position: &mut usize) -> Option<Polynomial> {
+    let mut sum = parse_product(tokens, position)?;
+    while let Some(Token::Symbol(symbol @ ('+' | '-'))) = tokens.get(*position) {
+        *position += 1;
+        let term = parse_product(tokens, position)?;
+        sum = match symbol {
+            '+' => sum.add(&term),
+            _ => sum.add(&term.scale(-1.0)),
+        };
+    }
+    Some(sum)
+}
+
+fn parse_product(tokens: &[Token], position: &mut usize) -> Option<Polynomial> {
+    let mut product = parse_factor(tokens, position)?;
+    while let Some(Token::Symbol(symbol @ ('*' | '/'))) = tokens.get(*position) {
+        *position += 1;
+        let factor = parse_factor(tokens, position)?;
+        product = match symbol {
+            '*' => product.mul(&factor),
+            _ => match factor.as_constant() {
+                Some(divisor) if divisor != 0.0 => product.scale(1.0 / divisor),
+                _ => return None,
+            },
+        };
+    }
+    Some(product)
+}
+
+fn parse_factor(tokens: &[Token], position: &mut usize) -> Option<Polynomial> {
+    let token = tokens.get(*position)?.clone();
+    *position += 1;
+    match token {
+        Token::Number(value) => Some(Polynomial::constant(value)),
+        Token::Name(name) => Some(Polynomial::parameter(&name)),
+        Token::Symbol('(') => {
+            let polynomial = parse_sum(tokens, position)?;
+            (tokens.get(*position) == Some(&Token::Symbol(')'))).then(|| {
+                *position += 1;
+                polynomial
+            })
+        }
+        Token::Symbol('-') => Some(parse_factor(tokens, position)?.scale(-1.0)),
+        Token::Symbol(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parameters(values: &[(&str, f64)]) -> BTreeMap<String, f64> {
+        values
+            .iter()
+            .map(|(name, value)| (name.to_string(), *value))
+            .collect()
+    }
+
+    #[test]
+    fn parse_expressions() {
+        let formula = Formula::parse("len/4 + 1").unwrap();
+        assert_eq!(formula.to_string(), "0.25*len + 1");
+        assert_eq!(formula.value(&parameters(&[("len", 64.0)])), 17);
+
+        let formula = Formula::parse("2*(n + 1) - -3").unwrap();
+        assert_eq!(formula.to_string(), "2*n + 5");
+        assert_eq!(Formula::parse("0x10").unwrap().as_constant(), Some(16.0));
+        assert_eq!(Formula::parse(" n * n ").unwrap().to_string(), "n^2");
+
+        // the divisors are constants
+        assert_eq!(Formula::parse("n/m"), None);
+        assert_eq!(Formula::parse("n/0"), None);
+        assert_eq!(Formula::parse("3 +"), None);
+        assert_eq!(Formula::parse("(n"), None);
+        assert_eq!(Formula::parse("n % 2"), None);
+    }
+
+    #[test]
+    fn value_is_rounded_up_and_never_negative() {
+        assert_eq!(
+            Formula::parse("n/3")
+                .unwrap()
+                .value(&parameters(&[("n", 10.0)])),
+            4
+        );
+        assert_eq!(
+            Formula::parse("len/4 - 1").unwrap().value(&parameters(&[])),
+            0
+        );
+        let square = Formula::parse("n * n").unwrap();
+        assert_eq!(
+            square.value(&parameters(&[("n", 1e7)])),
+            100_000_000_000_000
+        );
+        assert!(std::panic::catch_unwind(|| square.value(&parameters(&[("n", 1e9)]))).is_err());
+
+        // the negative bounds of the env vars are clamped with max
+        let bound = Formula::parse("len/4 - 1").unwrap().max(&Formula::zero());
+        assert_eq!(bound.evaluate(&parameters(&[("len", 0.0)])), Some(0.0));
+        assert_eq!(bound.evaluate(&parameters(&[("len", 8.0)])), Some(1.0));
+    }
+
+    #[test]
+    fn max_drops_dominated_polynomials() {
+        let n = Formula::parse("n").unwrap();
+        let m = Formula::parse("m").unwrap();
+
+        assert_eq!(
+            n.max(&Formula::parse("2*n + 1").unwrap()).to_string(),
+            "2*n + 1"
+        );
+        assert_eq!(
+            Formula::constant(3.0)
+                .max(&Formula::constant(5.0))
+                .as_constant(),
+            Some(5.0)
+        );
+
+        let max = n.max(&m);
+        assert_eq!(max.to_string(), "max(n, m)");
+        assert_eq!(
+            max.evaluate(&parameters(&[("n", 2.0), ("m", 7.0)])),
+            Some(7.0)
+        );
+        // a missing parameter has no value
+        assert_eq!(max.evaluate(&parameters(&[("n", 2.0)])), None);
+    }
+
+    #[test]
+    fn mul_and_add_distribute_over_max() {
+        let n = Formula::parse("n").unwrap();
+        let m = Formula::parse("m").unwrap();
+
+        let product = Formula::parse("n + 1").unwrap().mul(&m);
+        assert_eq!(product.to_string(), "m*n + m");
+
+        let formula = n
+            .max(&m)
+            .mul(&Formula::constant(3.0))
+            .add(&Formula::constant(1.0));
+        assert_eq!(formula.to_string(), "max(3*n + 1, 3*m + 1)");
+        assert_eq!(formula.value(&parameters(&[("n", 4.0), ("m", 2.0)])), 13);
+    }
+
+    #[test]
+    fn too_many_polynomials_become_one_upper_bound() {
+        let formulas = (0..=MAX_POLYNOMIALS)
+            .map(|i| Formula::parse(&format!("{} * p{i}", i + 1)).unwrap())
+            .collect::<Vec<_>>();
+        let max = formulas
+            .iter()
+            .skip(1)
+            .fold(formulas[0].clone(), |max, formula| max.max(formula));
+
+        // the maximum of every coefficient, an upper bound of each polynomial
+        assert_eq!(max.polynomials.len(), 1);
+        assert_eq!(max.parameters().len(), MAX_POLYNOMIALS + 1);
+        let values = (0..=MAX_POLYNOMIALS)
+            .map(|i| (format!("p{i}"), 1.0))
+            .collect::<BTreeMap<_, _>>();
+        let expected = (1..=MAX_POLYNOMIALS + 1).sum::<usize>() as f64;
+        assert_eq!(max.evaluate(&values), Some(expected));
+
+        // MAX_POLYNOMIALS of them are kept as they are
+        let max = formulas[..MAX_POLYNOMIALS]
+            .iter()
+            .skip(1)
+            .fold(formulas[0].clone(), |max, formula| max.max(formula));
+        assert_eq!(max.polynomials.len(), MAX_POLYNOMIALS);
+    }
+}
@@ -1,18 +1,29 @@
 #![allow(dead_code)]
-use std::collections::{hash_map, HashMap};
+use std::cell::OnceCell;
+use std::collections::{hash_map, BTreeMap, BTreeSet, HashMap};
 
-use petgraph::algo::{bellman_ford, condensation};
+use petgraph::algo::{condensation, dijkstra, tarjan_scc, toposort, Cycle};
 use petgraph::dot::Dot;
+use petgraph::graphmap::DiGraphMap;
 use petgraph::stable_graph::EdgeIndex;
 use petgraph::stable_graph::{NodeIndex, StableGraph};
 use petgraph::visit::EdgeRef;
 use petgraph::Direction;
 
 use crate::block::Block;
+use crate::formula::Formula;
+
+type LongestPaths = HashMap<NodeIndex<u32>, (u64, Option<NodeIndex<u32>>)>; // node -> (longest path from the node, next node)
+
+// the clock cycles are u64: an overflow stops the analysis instead of giving a wrong wcet
+pub fn add_cycles(a: u64, b: u64) -> u64 {
+    a.checked_add(b)
+        .unwrap_or_else(|| panic!("The wcet overflows u64: {a} + {b} clock cycles"))
+}
 
 #[derive(Debug, Clone)]
 pub struct MappedGraph {
-    pub graph: StableGraph<Block, f32>,
+    pub graph: StableGraph<Block, u64>,
     pub node_index_map: HashMap<u64, NodeIndex<u32>>,
     pub edge_index_map: HashMap<(u64, u64), EdgeIndex<u32>>,
 }
@@ -26,6 +37,28 @@ impl MappedGraph {
         }
     }
 
+    // one node for each block and one edge for each jump between blocks
+    pub fn from_blocks(blocks: &BTreeMap<u64, Block>) -> Self {
+        let mut graph = MappedGraph::new();
+
+        for block in blocks.values() {
+            // a block without edges (e.g. a function without jumps) is still part of the graph
+            graph.add_node(block.clone());
+
+            for target in block.get_targets() {
+                if let Some(target_block) = blocks.get(&target) {
+                    graph.add_edge(
+                        block.clone(),
+                        target_block.clone(),
+                        u64::from(target_block.get_latency_from(block)),
+                    );
+                }
+            }
+        }
+
+        graph
+    }
+
     pub fn add_node(&mut self, block: Block) {
         if let hash_map::Entry::Vacant(e) = self.node_index_map.entry(block.leader) {
             let node_index = self.graph.add_node(block);
@@ -43,7 +76,7 @@ impl MappedGraph {
         self.graph.node_weights().cloned().collect::<Vec<Block>>()
     }
 
-    pub fn add_edge(&mut self, source: Block, target: Block, weight: f32) {
+    pub fn add_edge(&mut self, source: Block, target: Block, weight: u64) {
         self.add_node(source.clone());
         self.add_node(target.clone());
 
@@ -63,13 +96,13 @@ impl MappedGraph {
         self.edge_index_map.remove(&(source.leader, target.leader));
     }
 
-    pub fn update_edge(&mut self, a: &Block, b: &Block, weight: f32) {
+    pub fn update_edge(&mut self, a: &Block, b: &Block, weight: u64) {
         let a_index = self.node_index_map[&a.leader];
         let b_index = self.node_index_map[&b.leader];
         self.graph.update_edge(a_index, b_index, weight);
     }
 
-    pub fn get_edges(&self) -> Vec<(Block, Block, f32)> {
+    pub fn get_edges(&self) -> Vec<(Block, Block, u64)> {
         self.graph
             .edge_indices()
             .map(|edge_index| {
@@ -81,10 +114,10 @@ impl MappedGraph {
 
                 (source.clone(), target.clone(), *edge)
             })
-            .collect::<Vec<(Block, Block, f32)>>()
+            .collect::<Vec<(Block, Block, u64)>>()
     }
 
-    pub fn edges_directed(&self, node: &Block, direction: Direction) -> Vec<(Block, Block, f32)> {
+    pub fn edges_directed(&self, node: &Block, direction: Direction) -> Vec<(Block, Block, u64)> {
         let node_index = self.node_index_map[&node.leader];
         let edges = self.graph.edges_directed(node_index, direction);
 
@@ -95,7 +128,7 @@ impl MappedGraph {
 
                 (source.clone(), target.clone(), *edge.weight())
             })
-            .collect::<Vec<(Block, Block, f32)>>()
+            .collect::<Vec<(Block, Block, u64)>>()
     }
 
     pub fn neighbors_directed(&self, node: &Block, direction: Direction) -> Vec<Block> {
@@ -112,57 +145,24 @@ impl MappedGraph {
         blocks
     }
 
-    pub fn shortest_path(&self, source: &Block) -> f32 {
-        let paths = bellman_ford(&self.graph, self.node_index_map[&source.leader]).unwrap();
+    // shortest path from the source to a block without successors, with the best case latencies
+    pub fn best_case_path(&self, source: &Block) -> Option<u64> {
+        let source_index = self.node_index_map[&source.leader];
+        let distances = dijkstra(&self.graph, source_index, None, |edge| {
+            u64::from(self.graph[edge.target()].get_best_case_latency())
+        });
 
-        let max_path_latency = paths
-            .distances
+        distances
             .iter()
-            .filter(|x| x.is_finite())
-            .max_by(|a, b| a.partial_cmp(b).unwrap())
-            .unwrap()
-            .to_owned();
-
-        max_path_latency
-    }
-
-    pub fn longest_path(&self, source: &Block) -> Result<f32, petgraph::algo::NegativeCycle> {
-        // change the weights of the edges to negative values to find the longest path
-        let mut graph = self.graph.clone();
-        for edge in graph.edge_weights_mut() {
-            *edge = -*edge;
-        }
-
-        let paths = bellman_ford(&graph, self.node_index_map[&source.leader])?;
-
-        let min_path_latency = paths
-            .distances
-            .iter()
-            .filter(|x| x.is_finite())
-            .min_by(|a, b| a.partial_cmp(b).unwrap())
-            .unwrap()
-            .to_owned();
-
-        Ok(min_path_latency * -1.0)
-    }
-
-    pub fn reconstruct_longest_path(
-        &self,
-        source: &Block,
-        exit: &Block,
-        entry_node_latency: f32,
-        max_cycles: u32,
-    ) -> Result<f32, petgraph::algo::NegativeCycle> {
-        match self.longest_path(source) {
-            Ok(path) => {
-                let cycle_path = path + entry_node_latency;
-                let directed_path = cycle_path - self.longest_path(exit).unwrap();
-                let total_cyle_path = cycle_path * max_cycles as f32 + directed_path;
-
-                Ok(total_cyle_path)
-            }
-            Err(e) => Err(e),
-        }
+            .filter(|(node, _)| {
+                self.graph
+                    .neighbors_directed(**node, Direction::Outgoing)
+                    .next()
+                    .is_none()
+            })
+            .map(|(_, distance)| *distance)
+            .min()
+            .map(|distance| add_cycles(distance, u64::from(source.get_best_case_latency())))
     }
 
     pub fn to_dot_graph(&self) -> String {
@@ -172,7 +172,7 @@ impl MappedGraph {
 
     pub fn condense_cycles(&mut self) -> MappedCondensedGraph {
         let condensed_graph = condensation(self.graph.clone().into(), true);
-        let stable_condensed_graph: StableGraph<Vec<Block>, f32> = condensed_graph.into();
+        let stable_condensed_graph: StableGraph<Vec<Block>, u64> = condensed_graph.into();
 
         let mut node_index_map = HashMap::new();
         let mut edge_index_map = HashMap::new();
@@ -198,15 +198,80 @@ impl MappedGraph {
             graph: stable_condensed_graph,
             node_index_map,
             edge_index_map,
+            longest_paths: OnceCell::new(),
+        }
+    }
+
+    // the headers of the loops, nested ones included: the blocks where the cycles are entered
+    pub fn loop_headers(&self) -> BTreeSet<u64> {
+        self.loops().into_iter().map(|(header, _)| header).collect()
+    }
+
+    // the loops of the graph, with the leaders of their blocks (inner loops included)
+    pub fn loops(&self) -> Vec<(u64, BTreeSet<u64>)> {
+        let mut leaders_graph = DiGraphMap::<u64, ()>::new();
+        for block in self.graph.node_weights() {
+            leaders_graph.add_node(block.leader);
+        }
+        for edge_index in self.graph.edge_indices() {
+            let (source, target) = self.graph.edge_endpoints(edge_index).unwrap();
+            leaders_graph.add_edge(self.graph[source].leader, self.graph[target].leader, ());
+        }
+
+        find_loops(&leaders_graph)
+    }
+}
+
+// loop header -> blocks of the loop, nested loops included, sorted by header.
+// The inner loops are found again in every loop without the back edges to its header
+pub fn find_loops(graph: &DiGraphMap<u64, ()>) -> Vec<(u64, BTreeSet<u64>)> {
+    let mut loops = Vec::new();
+    find_loops_in(graph, &mut loops);
+    loops.sort_by_key(|(header, _)| *header);
+    loops
+}
+
+fn find_loops_in(graph: &DiGraphMap<u64, ()>, loops: &mut Vec<(u64, BTreeSet<u64>)>) {
+    for scc in tarjan_scc(graph) {
+        let is_loop = scc.len() > 1 || graph.contains_edge(scc[0], scc[0]);
+        if !is_loop {
+            continue;
+        }
+
+        let members = scc.iter().copied().collect::<BTreeSet<_>>();
+        let header = members
+            .iter()
+            .copied()
+            .find(|leader| {
+                graph
+                    .neighbors_directed(*leader, Direction::Incoming)
+                    .any(|predecessor| !members.contains(&predecessor))
+            })
+            .unwrap_or(scc[0]);
+
+        // the inner loops are the cycles left without the back edges to the header
+        let mut inner_graph = DiGraphMap::<u64, ()>::new();
+        for leader in &members {
+            inner_graph.add_node(*leader);
+        }
+        for (source, target, _) in graph.all_edges() {
+            if members.contains(&source) && members.contains(&target) && target != header {
+                inner_graph.add_edge(source, target, ());
+            }
         }
+        find_loops_in(&inner_graph, loops);
+
+        loops.push((header, members));
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct MappedCondensedGraph {
-    pub graph: StableGraph<Vec<Block>, f32>,
+    pub graph: StableGraph<Vec<Block>, u64>,
     pub node_index_map: HashMap<u64, NodeIndex<u32>>,
     pub edge_index_map: HashMap<(u64, u64), EdgeIndex<u32>>,
+    // longest paths from every node, computed once for all the entries and reset when the graph changes
+    longest_paths: OnceCell<LongestPaths>,
 }
 
 impl MappedCondensedGraph {
@@ -223,6 +288,7 @@ impl MappedCondensedGraph {
     }
 
     pub fn add_node(&mut self, blocks: Vec<Block>) {
+        self.longest_paths.take();
         if let hash_map::Entry::Vacant(e) = self.node_index_map.entry(blocks[0].leader) {
             let node_index = self.graph.add_node(blocks.clone());
             e.insert(node_index);
@@ -230,6 +296,7 @@ impl MappedCondensedGraph {
     }
 
     pub fn remove_node(&mut self, blocks: &[Block]) {
+        self.longest_paths.take();
         let node_index = self.node_index_map[&blocks[0].leader];
         self.graph.remove_node(node_index);
         self.node_index_map.remove(&blocks[0].leader);
@@ -246,7 +313,7 @@ impl MappedCondensedGraph {
         nodes
     }
 
-    pub fn add_edge(&mut self, source: Vec<Block>, target: Vec<Block>, weight: f32) {
+    pub fn add_edge(&mut self, source: Vec<Block>, target: Vec<Block>, weight: u64) {
         self.add_node(source.clone());
         self.add_node(target.clone());
 
@@ -263,19 +330,21 @@ impl MappedCondensedGraph {
     }
 
     pub fn remove_edge(&mut self, source: &[Block], target: &[Block]) {
+        self.longest_paths.take();
         let edge_index = self.edge_index_map[&(source[0].leader, target[0].leader)];
         self.graph.remove_edge(edge_index);
         self.edge_index_map
             .remove(&(source[0].leader, target[0].leader));
     }
 
-    pub fn update_edge(&mut self, a: &[Block], b: &[Block], weight: f32) {
+    pub fn update_edge(&mut self, a: &[Block], b: &[Block], weight: u64) {
+        self.longest_paths.take();
         let source_index = self.node_index_map[&a[0].leader];
         let target_index = self.node_index_map[&b[0].leader];
         self.graph.update_edge(source_index, target_index, weight);
     }
 
-    pub fn get_edges(&self) -> Vec<(Vec<Block>, Vec<Block>, f32)> {
+    pub fn get_edges(&self) -> Vec<(Vec<Block>, Vec<Block>, u64)> {
         let mut edges = Vec::new();
 
         for edge_index in self.graph.edge_indices() {
@@ -296,7 +365,7 @@ impl MappedCondensedGraph {
         &self,
         node: &[Block],
         direction: Direction,
-    ) -> Vec<(Vec<Block>, Vec<Block>, f32)> {
+    ) -> Vec<(Vec<Block>, Vec<Block>, u64)> {
         let node_index = self.node_index_map[&node[0].leader];
         let edges = self.graph.edges_directed(node_index, direction);
 
@@ -326,57 +395,88 @@ impl MappedCondensedGraph {
         blocks
     }
 
-    pub fn shortest_path(&self, source: &[Block]) -> f32 {
-        let paths = bellman_ford(&self.graph, self.node_index_map[&source[0].leader]).unwrap();
+    // longest path from every node with the next node on it, in one pass over the reverse topological order.
+    // The condensed graph is acyclic, the result is kept until the graph changes
+    fn longest_paths(&self) -> Result<&LongestPaths, Cycle<NodeIndex<u32>>> {
+        if let Some(longest_paths) = self.longest_paths.get() {
+            return Ok(longest_paths);
+        }
 
-        let max_path_latency = paths
-            .distances
-            .iter()
-            .filter(|x| x.is_finite())
-            .max_by(|a, b| a.partial_cmp(b).unwrap())
-            .unwrap()
-            .to_owned();
+        let mut longest_paths = LongestPaths::new();
+        for node_index in toposort(&self.graph, None)?.into_iter().rev() {
+            let mut longest = (0, None);
+            for edge in self.graph.edges_directed(node_index, Direction::Outgoing) {
+                let length = add_cycles(*edge.weight(), longest_paths[&edge.target()].0);
+                let next = Some(edge.target());
+                // the lowest node index as a tie-break independent of the edge order
+                if longest.1.is_none()
+                    || length > longest.0
+                    || (length == longest.0 && next < longest.1)
+                {
+                    longest = (length, next);
+                }
+            }
+            longest_paths.insert(node_index, longest);
+        }
 
-        max_path_latency
+        Ok(self.longest_paths.get_or_init(|| longest_paths))
     }
 
-    pub fn longest_path(&self, source: &[Block]) -> Result<f32, petgraph::algo::NegativeCycle> {
-        // change the weights of the edges to negative values to find the longest path
-        let mut graph = self.graph.clone();
-        for edge in graph.edge_weights_mut() {
-            *edge = -*edge;
-        }
-
-        let paths = bellman_ford(&graph, self.node_index_map[&source[0].leader])?;
+    pub fn longest_path(&self, source: &[Block]) -> Result<u64, Cycle<NodeIndex<u32>>> {
+        let source_index = self.node_index_map[&source[0].leader];
+        Ok(self.longest_paths()?[&source_index].0)
+    }
 
-        let min_path_latency = paths
-            .distances
-            .iter()
-            .filter(|x| x.is_finite())
-            .min_by(|a, b| a.partial_cmp(b).unwrap())
-            .unwrap()
-            .to_owned();
+    // longest path from every node with the latencies of the edges as formulas ([0] as reference of the nodes),
+    // in one pass over the reverse topological order of the condensed graph
+    pub fn longest_path_formulas(
+        &self,
+        edge_formulas: &HashMap<(u64, u64), Formula>,
+    ) -> HashMap<u64, Formula> {
+        let mut longest_paths = HashMap::<NodeIndex<u32>, Formula>::new();
+
+        for node_index in toposort(&self.graph, None)
+            .expect("The condensed graph is acyclic")
+            .into_iter()
+            .rev()
+        {
+            let mut longest = Formula::zero();
+            for edge in self.graph.edges_directed(node_index, Direction::Outgoing) {
+                let key = (
+                    self.graph[node_index][0].leader,
+                    self.graph[edge.target()][0].leader,
+                );
+                let latency = edge_formulas
+                    .get(&key)
+                    .cloned()
+                    .unwrap_or(Formula::constant(*edge.weight() as f64));
+                longest = longest.max(&latency.add(&longest_paths[&edge.target()]));
+            }
+            longest_paths.insert(node_index, longest);
+        }
 
-        Ok(min_path_latency * -1.0)
+        longest_paths
+            .into_iter()
+            .map(|(node_index, formula)| (self.graph[node_index][0].leader, formula))
+            .collect()
     }
 
-    pub fn reconstruct_longest_path(
-        &mut self,
+    // longest path from the source and the leaders of its condensed nodes
+    pub fn longest_path_nodes(
+        &self,
         source: &[Block],
-        exit: &[Block],
-        entry_node_latency: f32,
-        max_cycles: u32,
-    ) -> Result<f32, petgraph::algo::NegativeCycle> {
-        match self.longest_path(source) {
-            Ok(path) => {
-                let cycle_path = path + entry_node_latency;
-                let directed_path = cycle_path - self.longest_path(exit)?;
-                let total_cyle_path = cycle_path * max_cycles as f32 + directed_path;
-
-                Ok(total_cyle_path)
-            }
-            Err(e) => Err(e),
+    ) -> Result<(u64, Vec<u64>), Cycle<NodeIndex<u32>>> {
+        let longest_paths = self.longest_paths()?;
+        let source_index = self.node_index_map[&source[0].leader];
+
+        let mut path = Vec::new();
+        let mut node = Some(source_index);
+        while let Some(node_index) = node {
+            path.push(self.graph[node_index][0].leader);
+            node = longest_paths[&node_index].1;
         }
+
+        Ok((longest_paths[&source_index].0, path))
     }
 
     pub fn to_dot_graph(&self) -> String {
@@ -384,3 +484,95 @@ impl MappedCondensedGraph {
         format!("{digraph:?}")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::nop_block;
+
+    const NODES: u64 = 9;
+
+    // random acyclic graphs from a fixed seed: the edges go from a lower to a higher leader
+    fn random_dag(seed: &mut u64) -> MappedGraph {
+        let mut next = || {
+            *seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            *seed >> 33
+        };
+        let leader = |node: u64| 0x4000 + node * 0x10;
+
+        let mut graph = MappedGraph::new();
+        for node in 0..NODES {
+            graph.add_node(nop_block(leader(node), None));
+        }
+        for source in 0..NODES {
+            for target in source + 1..NODES {
+                if next() % 3 == 0 {
+                    graph.add_edge(
+                        nop_block(leader(source), None),
+                        nop_block(leader(target), None),
+                        next() % 20,
+                    );
+                }
+            }
+        }
+        graph
+    }
+
+    // the longest path from the node by trying every path
+    fn brute_force(graph: &MappedCondensedGraph, node: NodeIndex<u32>) -> u64 {
+        graph
+            .graph
+            .edges_directed(node, Direction::Outgoing)
+            .map(|edge| edge.weight() + brute_force(graph, edge.target()))
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn check_longest_paths(graph: &MappedCondensedGraph) {
+        let formulas = graph.longest_path_formulas(&HashMap::new());
+        for node in graph.get_nodes() {
+            let expected = brute_force(graph, graph.node_index_map[&node[0].leader]);
+            assert_eq!(graph.longest_path(&node).unwrap(), expected);
+            assert_eq!(
+                formulas[&node[0].leader].as_constant(),
+                Some(expected as f64)
+            );
+
+            // the path goes through the edges of the graph and its length is the longest one
+            let (length, path) = graph.longest_path_nodes(&node).unwrap();
+            assert_eq!(length, expected);
+            let path_length = path
+                .windows(2)
+                .map(|edge| {
+                    let edge_index = graph.edge_index_map[&(edge[0], edge[1])];
+                    graph.graph[edge_index]
+                })
+                .sum::<u64>();
+            assert_eq!(path_length, expected);
+        }
+    }
+
+    #[test]
+    fn longest_paths_of_random_dags_match_brute_force() {
+        let mut seed = 47;
+        for _ in 0..50 {
+            let mut graph = random_dag(&mut seed).condense_cycles();
+            check_longest_paths(&graph);
+
+            // the cached longest paths are computed again after every change of the graph
+            let edges = graph.get_edges();
+            if let Some((source, target, weight)) = edges.first() {
+                graph.update_edge(source, target, weight + 100);
+                check_longest_paths(&graph);
+                graph.remove_edge(source, target);
+                check_longest_paths(&graph);
+            }
+            if let Some(node) = graph.get_nodes().pop() {
+                graph.remove_node(&node);
+                check_longest_paths(&graph);
+            }
+        }
+    }
+}
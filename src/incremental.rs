@@ -0,0 +1,377 @@
+// Incremental re-analysis: the results of an analysis are stored in ANALYSIS_CACHE_DIR, keyed by a hash of
+// the code reachable from the entry (the function and all its callees, with the latencies of the instructions),
+// of its relocations, of the flow facts and settings that apply to it and of the analyzer executable.
+// The warnings, errors and debug files (values.txt, graph.dot, ...) of the analysis are stored with the results,
+// on a cache hit the warnings and errors are printed again and the debug files written again.
+// A changed function changes the key of all the functions that reach it, so only they are analyzed again.
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use crate::batch::{create_output_file, AnalysisSummary, Recording};
+use crate::binary::CodeReference;
+use crate::block::Block;
+use crate::printwarning;
+
+// the flow facts keyed by an address, they are part of the key only if the address is in the analyzed code
+pub const ADDRESS_PREFIXES: &[&str] = &[
+    "CYCLE_TOTAL_0x",
+    "CYCLE_0x",
+    "RECURSIVE_0x",
+    "INFEASIBLE_0x",
+];
+
+// the settings that change the results of any analysis
+const SETTING_PREFIXES: &[&str] = &[
+    "PARAM_",
+    "EXTERNAL_",
+    "MEMORY_",
+    "ICACHE_",
+    "DCACHE_",
+    "TIMING_MODEL",
+    "PIPELINE_",
+    "BRANCH_",
+    "TRACE_",
+    "SIMULATE_",
+    "STRICT_LATENCIES",
+];
+
+// FNV-1a, the same hash in every run and on every platform
+struct StableHasher(u64);
+
+impl StableHasher {
+    fn new() -> Self {
+        StableHasher(0xcbf29ce484222325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        self.write(&value.to_le_bytes());
+    }
+
+    // with the length, so that consecutive strings can't be confused
+    fn write_str(&mut self, value: &str) {
+        self.write_u64(value.len() as u64);
+        self.write(value.as_bytes());
+    }
+}
+
+// a hash of the analyzer executable, a rebuilt analyzer can give other results with the same version.
+// None if the executable can't be read
+fn build_id() -> Option<u64> {
+    static BUILD_ID: OnceLock<Option<u64>> = OnceLock::new();
+    *BUILD_ID.get_or_init(|| {
+        let executable = std::fs::read(std::env::current_exe().ok()?).ok()?;
+        let mut hasher = StableHasher::new();
+        hasher.write(&executable);
+        Some(hasher.0)
+    })
+}
+
+// an analysis stored in the cache
+pub struct CachedAnalysis {
+    pub summary: AnalysisSummary,
+    pub diagnostics: Vec<String>, // warnings and errors
+    pub files: Vec<String>,       // names of the debug files
+}
+
+pub struct AnalysisCache {
+    dir: PathBuf,
+    build_id: u64,
+}
+
+impl AnalysisCache {
+    pub fn from_env() -> Option<Self> {
+        let dir = PathBuf::from(std::env::var("ANALYSIS_CACHE_DIR").ok()?);
+        let Some(build_id) = build_id() else {
+            printwarning!(
+                "Unable to read the analyzer executable -> the analysis cache is not used"
+            );
+            return None;
+        };
+        std::fs::create_dir_all(&dir).expect("Unable to create the analysis cache directory");
+        Some(AnalysisCache { dir, build_id })
+    }
+
+    fn path(&self, key: u64) -> PathBuf {
+        self.dir.join(format!("{key:016x}.txt"))
+    }
+
+    fn files_dir(&self, key: u64) -> PathBuf {
+        self.dir.join(format!("{key:016x}"))
+    }
+
+    // the blocks are the ones reachable from the entry, the call instructions of the external calls
+    // and the relocations are filtered on them
+    pub fn key(
+        &self,
+        blocks: &BTreeMap<u64, Block>,
+        external_calls: &HashMap<u64, String>, // call_insn_address -> external symbol
+        relocations: &HashMap<u64, CodeReference>, // relocated address -> referenced code
+    ) -> u64 {
+        let variables = std::env::vars().collect::<BTreeMap<_, _>>();
+        self.key_with_variables(blocks, external_calls, relocations, &variables)
+    }
+
+    // the key with the given env vars
+    fn key_with_variables(
+        &self,
+        blocks: &BTreeMap<u64, Block>,
+        external_calls: &HashMap<u64, String>,
+        relocations: &HashMap<u64, CodeReference>,
+        variables: &BTreeMap<String, String>,
+    ) -> u64 {
+        let mut hasher = StableHasher::new();
+        hasher.write_u64(self.build_id);
+
+        let mut addresses = BTreeSet::new();
+        for block in blocks.values() {
+            hasher.write_u64(block.leader);
+            hasher.write_str(&format!("{:?}", block.exit_jump));
+            for instruction in &block.instructions {
+                addresses.insert(instruction.address);
+                hasher.write_u64(instruction.address);
+                hasher.write_str(&instruction.mnemonic);
+                hasher.write_str(&instruction.op_str);
+                // the latency profile, after the external calls and the CPU profile are applied
+                hasher.write_u64(u64::from(instruction.latency));
+                hasher.write_u64(u64::from(instruction.bcet_latency));
+                hasher.write_u64(u64::from(instruction.taken_penalty));
+                hasher.write_u64(u64::from(instruction.not_taken_penalty));
+
+                if let Some(symbol) = external_calls.get(&instruction.address) {
+                    hasher.write_str(symbol);
+                }
+                let end = instruction.address + u64::from(instruction.size);
+                for address in instruction.address..end {
+                    if let Some(reference) = relocations.get(&address) {
+                        hasher.write_u64(address);
+                        hasher.write_str(&format!("{reference:?}"));
+                    }
+                }
+            }
+        }
+
+        // the env vars in name order
+        for (name, value) in variables {
+            let relevant = match ADDRESS_PREFIXES
+                .iter()
+                .find_map(|prefix| name.strip_prefix(prefix))
+            {
+                Some(address) => u64::from_str_radix(address, 16)
+                    .is_ok_and(|address| addresses.contains(&address)),
+                None => SETTING_PREFIXES
+                    .iter()
+                    .any(|prefix| name.starts_with(prefix)),
+            };
+            if relevant {
+                hasher.write_str(name);
+                hasher.write_str(value);
+            }
+        }
+
+        // the measured clock cycles of a hybrid analysis
+        if let Some(trace_file) = variables.get("TRACE_FILE") {
+            if let Ok(trace) = std::fs::read(trace_file) {
+                hasher.write_u64(trace.len() as u64);
+                hasher.write(&trace);
+            }
+        }
+
+        hasher.0
+    }
+
+    pub fn load(&self, key: u64) -> Option<CachedAnalysis> {
+        let content = std::fs::read_to_string(self.path(key)).ok()?;
+
+        let mut analysis = CachedAnalysis {
+            summary: AnalysisSummary {
+                cached: true,
+                ..Default::default()
+            },
+            diagnostics: Vec::new(),
+            files: Vec::new(),
+        };
+        let summary = &mut analysis.summary;
+        for line in content.lines() {
+            let (name, value) = line.split_once('=')?;
+            match name {
+                "wcet" => summary.wcet = Some(value.parse().ok()?),
+                "bcet" => summary.bcet = Some(value.parse().ok()?),
+                "formula" => summary.formula = Some(value.to_string()),
+                "hybrid" => summary.hybrid = value == "true",
+                "output" => analysis.diagnostics.push(value.to_string()),
+                "file" if self.files_dir(key).join(value).is_file() => {
+                    analysis.files.push(value.to_string())
+                }
+                // a file of another version, or with a removed debug file, is analyzed again
+                _ => return None,
+            }
+        }
+        Some(analysis)
+    }
+
+    // the debug files of the stored analysis, in the output directory of the current one
+    pub fn restore_files(&self, key: u64, files: &[String]) {
+        for name in files {
+            let mut stored = std::fs::File::open(self.files_dir(key).join(name))
+                .expect("Unable to read analysis cache file");
+            std::io::copy(&mut stored, &mut create_output_file(name))
+                .expect("Unable to write the debug file");
+        }
+    }
+
+    pub fn store(&self, key: u64, summary: &AnalysisSummary, recording: &Recording) {
+        let mut content = String::new();
+        for diagnostic in &recording.diagnostics {
+            content.push_str(&format!("output={diagnostic}\n"));
+        }
+        // the debug files are stored before the results that refer to them
+        let files_dir = self.files_dir(key);
+        std::fs::create_dir_all(&files_dir).expect("Unable to create the analysis cache directory");
+        for (name, path) in &recording.files {
+            std::fs::copy(path, files_dir.join(name)).expect("Unable to write analysis cache file");
+            content.push_str(&format!("file={name}\n"));
+        }
+        if let Some(wcet) = summary.wcet {
+            content.push_str(&format!("wcet={wcet}\n"));
+        }
+        if let Some(bcet) = summary.bcet {
+            content.push_str(&format!("bcet={bcet}\n"));
+        }
+        if let Some(formula) = &summary.formula {
+            content.push_str(&format!("formula={formula}\n"));
+        }
+        content.push_str(&format!("hybrid={}\n", summary.hybrid));
+
+        // written to a temporary file first, an analysis running at the same time never reads half a file
+        let path = self.path(key);
+        let temporary_path = path.with_extension(format!(
+            "{}.{:?}.tmp",
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        let mut file =
+            std::fs::File::create(&temporary_path).expect("Unable to create analysis cache file");
+        file.write_all(content.as_bytes())
+            .expect("Unable to write analysis cache file");
+        std::fs::rename(&temporary_path, &path).expect("Unable to write analysis cache file");
+    }
+
+    pub fn display_path(&self, key: u64) -> String {
+        self.path(key).display().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch::{start_recording, take_recording};
+    use crate::jump::ExitJump;
+    use crate::testing::{block, disassemble, x86_64};
+
+    fn cache(name: &str) -> AnalysisCache {
+        let dir = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        AnalysisCache { dir, build_id: 1 }
+    }
+
+    // call 0x5100 at 0x5000 and ret, the callee at 0x5100 is mov eax, <value>; ret
+    fn caller_and_callee(value: u8) -> BTreeMap<u64, Block> {
+        let x86_64 = x86_64();
+        [
+            block(
+                disassemble(&x86_64, 0x5000, &[0xe8, 0xfb, 0x00, 0x00, 0x00]),
+                Some(ExitJump::Call(0x5100, 0x5005)),
+            ),
+            block(
+                disassemble(&x86_64, 0x5005, &[0xc3]),
+                Some(ExitJump::Ret(0x5005)),
+            ),
+            block(
+                disassemble(&x86_64, 0x5100, &[0xb8, value, 0x00, 0x00, 0x00, 0xc3]),
+                Some(ExitJump::Ret(0x5105)),
+            ),
+        ]
+        .into_iter()
+        .map(|block| (block.leader, block))
+        .collect()
+    }
+
+    #[test]
+    fn key_changes_with_a_callee_or_a_flow_fact() {
+        let cache = cache("asm-analyzer-cache-key");
+        let key = |blocks: &BTreeMap<u64, Block>, variables: &[(&str, &str)]| {
+            let variables = variables
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect();
+            cache.key_with_variables(blocks, &HashMap::new(), &HashMap::new(), &variables)
+        };
+        let blocks = caller_and_callee(1);
+        let original = key(&blocks, &[]);
+
+        assert_eq!(key(&caller_and_callee(1), &[]), original);
+        assert_ne!(key(&caller_and_callee(2), &[]), original);
+
+        // the flow facts of the analyzed addresses and the settings are part of the key, the other env vars are not
+        assert_ne!(key(&blocks, &[("CYCLE_0x5100", "4")]), original);
+        assert_ne!(key(&blocks, &[("INFEASIBLE_0x5005", "0x5100")]), original);
+        assert_ne!(key(&blocks, &[("PARAM_n", "4")]), original);
+        assert_eq!(key(&blocks, &[("CYCLE_0x6000", "4")]), original);
+        assert_eq!(key(&blocks, &[("HOME", "/tmp")]), original);
+        assert_ne!(
+            key(&blocks, &[("CYCLE_0x5100", "4")]),
+            key(&blocks, &[("CYCLE_0x5100", "5")])
+        );
+
+        // the latencies of the CPU profile
+        let mut slower = blocks.clone();
+        slower.get_mut(&0x5100).unwrap().instructions[0].latency += 1;
+        assert_ne!(key(&slower, &[]), original);
+    }
+
+    #[test]
+    fn stored_analysis_with_its_debug_files() {
+        let cache = cache("asm-analyzer-cache-store");
+        let debug_file = cache.dir.join("values.txt");
+        std::fs::write(&debug_file, "values").unwrap();
+
+        start_recording();
+        printwarning!("stored warning");
+        let mut recording = take_recording();
+        recording
+            .files
+            .insert("values.txt".to_string(), debug_file.clone());
+        let summary = AnalysisSummary {
+            wcet: Some(42),
+            formula: Some("2*n + 1".to_string()),
+            ..Default::default()
+        };
+        cache.store(7, &summary, &recording);
+
+        let analysis = cache.load(7).unwrap();
+        assert_eq!(analysis.summary.wcet, Some(42));
+        assert_eq!(analysis.summary.bcet, None);
+        assert_eq!(analysis.summary.formula.as_deref(), Some("2*n + 1"));
+        assert!(analysis.summary.cached);
+        assert_eq!(
+            analysis.diagnostics,
+            ["WARNING: stored warning".to_string()]
+        );
+        assert_eq!(analysis.files, ["values.txt".to_string()]);
+        assert!(cache.load(8).is_none());
+
+        // a removed debug file is analyzed again
+        std::fs::remove_file(cache.files_dir(7).join("values.txt")).unwrap();
+        assert!(cache.load(7).is_none());
+    }
+}
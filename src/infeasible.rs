@@ -0,0 +1,778 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
+
+use petgraph::Direction::{Incoming, Outgoing};
+
+use crate::block::Block;
+use crate::graph::{MappedCondensedGraph, MappedGraph};
+use crate::instruction::{Instruction, Operand};
+use crate::jump::ExitJump;
+use crate::memory::{get_memory_operands, MemoryAccessKind};
+use crate::printwarning;
+use crate::value::{Interval, Value, ValueAnalysis};
+
+// env variable format: INFEASIBLE_0xA=0xB the blocks at 0xA and 0xB never execute in the same run,
+// more blocks are separated by commas (INFEASIBLE_0xA=0xB,0xC).
+// The other infeasible paths come from the branches decided by the value analysis
+// and from the branches that test the same unchanged value.
+
+const MAX_PATH_SEARCHES: u32 = 1000; // longest path searches before ignoring the infeasible paths
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum PathElement {
+    Block(u64),     // block_leader
+    Edge(u64, u64), // source and target block_leader
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InfeasiblePath {
+    pub elements: Vec<PathElement>, // no execution goes through all of them
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Predicate {
+    Eq,
+    Ne,
+    Lt,
+    Ge,
+    Le,
+    Gt,
+    Ltu,
+    Geu,
+    Leu,
+    Gtu,
+}
+
+impl Predicate {
+    fn holds(&self, a: i64, b: i64) -> bool {
+        match self {
+            Predicate::Eq => a == b,
+            Predicate::Ne => a != b,
+            Predicate::Lt => a < b,
+            Predicate::Ge => a >= b,
+            Predicate::Le => a <= b,
+            Predicate::Gt => a > b,
+            Predicate::Ltu => (a as u64) < b as u64,
+            Predicate::Geu => a as u64 >= b as u64,
+            Predicate::Leu => a as u64 <= b as u64,
+            Predicate::Gtu => a as u64 > b as u64,
+        }
+    }
+}
+
+// the value tested by a branch
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum TestedValue {
+    Register(String),
+    StackSlot(i64, u64), // offset from the stack pointer at the analysis entry, bytes
+    Memory(u64, u64),    // address, bytes
+}
+
+#[derive(Debug, Clone)]
+struct Condition {
+    leader: u64,
+    tested: TestedValue,
+    predicate: Predicate, // the branch is taken if predicate(tested, constant)
+    constant: i64,
+    interval: Interval, // of the tested value, from the value analysis
+    read_index: usize,  // instruction that reads the tested value
+    taken: u64,
+    not_taken: u64,
+}
+
+impl Condition {
+    fn target(&self, taken: bool) -> u64 {
+        if taken {
+            self.taken
+        } else {
+            self.not_taken
+        }
+    }
+}
+
+fn x86_predicate(mnemonic: &str) -> Option<Predicate> {
+    let predicate = match mnemonic {
+        "je" | "jz" => Predicate::Eq,
+        "jne" | "jnz" => Predicate::Ne,
+        "jl" | "jnge" => Predicate::Lt,
+        "jge" | "jnl" => Predicate::Ge,
+        "jle" | "jng" => Predicate::Le,
+        "jg" | "jnle" => Predicate::Gt,
+        "jb" | "jnae" | "jc" => Predicate::Ltu,
+        "jae" | "jnb" | "jnc" => Predicate::Geu,
+        "jbe" | "jna" => Predicate::Leu,
+        "ja" | "jnbe" => Predicate::Gtu,
+        _ => return None,
+    };
+    Some(predicate)
+}
+
+// the predicate and whether the branch compares with zero (beqz rs, offset)
+fn riscv_predicate(mnemonic: &str) -> Option<(Predicate, bool)> {
+    let predicate = match mnemonic.trim_end_matches('z') {
+        "beq" => Predicate::Eq,
+        "bne" => Predicate::Ne,
+        "blt" => Predicate::Lt,
+        "bge" => Predicate::Ge,
+        "ble" => Predicate::Le,
+        "bgt" => Predicate::Gt,
+        "bltu" => Predicate::Ltu,
+        "bgeu" => Predicate::Geu,
+        "bleu" => Predicate::Leu,
+        "bgtu" => Predicate::Gtu,
+        _ => return None,
+    };
+    Some((predicate, mnemonic.ends_with('z')))
+}
+
+fn get_condition(block: &Block, values: &ValueAnalysis) -> Option<Condition> {
+    let (taken, not_taken) = match block.exit_jump {
+        Some(ExitJump::ConditionalRelative { taken, not_taken })
+        | Some(ExitJump::ConditionalAbsolute { taken, not_taken }) => (taken, not_taken),
+        _ => return None,
+    };
+    if taken == not_taken {
+        return None;
+    }
+
+    let branch_index = block.instructions.len().checked_sub(1)?;
+    let branch = &block.instructions[branch_index];
+    let mnemonic = branch.mnemonic.trim_start_matches("c.");
+
+    // the instruction that compares and its operands
+    let (predicate, compare_index, tested, compared) =
+        if let Some(predicate) = x86_predicate(mnemonic) {
+            // the last instruction that sets the flags
+            let compare_index = (0..branch_index).rev().find(|i| {
+                block.instructions[*i]
+                    .regs_write
+                    .iter()
+                    .any(|reg| reg == "rflags" || reg == "eflags")
+            })?;
+            let compare = &block.instructions[compare_index];
+            match (compare.mnemonic.as_str(), compare.operands.as_slice()) {
+                ("cmp", [tested, compared]) => (predicate, compare_index, tested, compared.clone()),
+                ("test", [tested @ Operand::Reg(a), Operand::Reg(b)]) if a == b => {
+                    (predicate, compare_index, tested, Operand::Imm(0))
+                }
+                _ => return None,
+            }
+        } else {
+            let (predicate, with_zero) = riscv_predicate(mnemonic)?;
+            match branch.operands.as_slice() {
+                [tested @ Operand::Reg(_), Operand::Imm(_)] if with_zero => {
+                    (predicate, branch_index, tested, Operand::Imm(0))
+                }
+                [tested @ Operand::Reg(_), compared @ Operand::Reg(_), Operand::Imm(_)]
+                    if !with_zero =>
+                {
+                    (predicate, branch_index, tested, compared.clone())
+                }
+                _ => return None,
+            }
+        };
+
+    let compare = &block.instructions[compare_index];
+    let constant = match compared {
+        Operand::Reg(ref reg) if reg == "zero" || reg == "x0" => 0,
+        _ => match values.operand_before(block.leader, compare, &compared)? {
+            Value::Number(interval) => interval.as_constant()?,
+            Value::StackAddress(_) => return None,
+        },
+    };
+
+    // a register loaded from memory in the block tests the memory
+    let (read_index, tested) = match tested {
+        Operand::Reg(reg) => {
+            let full_register = values.full_register(reg);
+            let load_index = (0..compare_index).rev().find(|i| {
+                block.instructions[*i]
+                    .regs_write
+                    .iter()
+                    .any(|written| values.full_register(written) == full_register)
+            });
+            match load_index.map(|i| (i, &block.instructions[i])) {
+                Some((i, load))
+                    if matches!(
+                        load.mnemonic.as_str(),
+                        "mov" | "lw" | "ld" | "c.lw" | "c.ld"
+                    ) && load.operands.first() == Some(tested) =>
+                {
+                    match load.operands.get(1) {
+                        Some(memory @ Operand::Mem { .. }) => (i, memory),
+                        _ => (compare_index, tested),
+                    }
+                }
+                _ => (compare_index, tested),
+            }
+        }
+        _ => (compare_index, tested),
+    };
+
+    let reader = &block.instructions[read_index];
+    let interval = match values.operand_before(block.leader, reader, tested)? {
+        Value::Number(interval) => interval,
+        Value::StackAddress(_) => return None,
+    };
+    let tested = match tested {
+        Operand::Reg(reg) => TestedValue::Register(reg.clone()),
+        Operand::Mem { .. } => {
+            let size = values.memory_size(reader);
+            match values.address_before(block.leader, reader, tested)? {
+                Value::StackAddress(offset) => TestedValue::StackSlot(offset.as_constant()?, size),
+                Value::Number(address) => TestedValue::Memory(address.as_constant()? as u64, size),
+            }
+        }
+        Operand::Imm(_) => return None,
+    };
+
+    Some(Condition {
+        leader: block.leader,
+        tested,
+        predicate,
+        constant,
+        interval,
+        read_index,
+        taken,
+        not_taken,
+    })
+}
+
+// values that give every result of the predicates on the constants, inside the interval
+fn representatives(interval: &Interval, constants: &[i64]) -> Vec<i64> {
+    let mut candidates = vec![interval.min, interval.max, i64::MIN, i64::MAX, -1, 0];
+    for constant in constants {
+        candidates.extend([
+            constant.wrapping_sub(1),
+            *constant,
+            constant.wrapping_add(1),
+        ]);
+    }
+    candidates
+        .into_iter()
+        .filter(|value| *value >= interval.min && *value <= interval.max)
+        .collect()
+}
+
+fn is_call(instruction: &Instruction) -> bool {
+    let mnemonic = instruction.mnemonic.trim_start_matches("c.");
+    mnemonic.starts_with("call") || matches!(mnemonic, "jal" | "jalr")
+}
+
+fn may_overlap(address: Value, size: u64, tested: &TestedValue) -> bool {
+    let overlap = |start: i64, end: i64, tested_start: i64, tested_size: u64| {
+        start < tested_start.saturating_add(tested_size as i64) && tested_start < end
+    };
+    match (address, tested) {
+        (_, TestedValue::Register(_)) => false,
+        (Value::StackAddress(offset), TestedValue::StackSlot(slot, slot_size)) => overlap(
+            offset.min,
+            offset.max.saturating_add(size as i64),
+            *slot,
+            *slot_size,
+        ),
+        (Value::StackAddress(_), TestedValue::Memory(..)) => false,
+        (Value::Number(address), _) if !address.is_bounded() => true,
+        (Value::Number(_), TestedValue::StackSlot(..)) => false,
+        (Value::Number(address), TestedValue::Memory(tested_address, tested_size)) => overlap(
+            address.min,
+            address.max.saturating_add(size as i64),
+            *tested_address as i64,
+            *tested_size,
+        ),
+    }
+}
+
+// the instruction may change the tested value
+fn writes(
+    instruction: &Instruction,
+    leader: u64,
+    tested: &TestedValue,
+    values: &ValueAnalysis,
+) -> bool {
+    if is_call(instruction) {
+        return true;
+    }
+
+    if let TestedValue::Register(reg) = tested {
+        let full_register = values.full_register(reg);
+        return instruction
+            .regs_write
+            .iter()
+            .any(|written| values.full_register(written) == full_register);
+    }
+
+    get_memory_operands(instruction)
+        .into_iter()
+        .filter(|(kind, _)| *kind == MemoryAccessKind::Write)
+        .any(|(_, operand)| {
+            let size = values.memory_size(instruction);
+            let address = match operand {
+                Some(operand) => values.address_before(leader, instruction, operand),
+                // push below the stack pointer
+                None => values
+                    .operand_before(
+                        leader,
+                        instruction,
+                        &Operand::Reg(values.stack_pointer().to_string()),
+                    )
+                    .map(|stack_pointer| stack_pointer.sub(&Value::constant(8))),
+            };
+            match address {
+                Some(address) => may_overlap(address, size.max(8), tested),
+                None => true,
+            }
+        })
+}
+
+fn reachable(graph: &MappedGraph, block: &Block, direction: petgraph::Direction) -> BTreeSet<u64> {
+    let mut visited = BTreeSet::new();
+    let mut worklist = VecDeque::from(graph.neighbors_directed(block, direction));
+    while let Some(block) = worklist.pop_front() {
+        if visited.insert(block.leader) {
+            worklist.extend(graph.neighbors_directed(&block, direction));
+        }
+    }
+    visited
+}
+
+fn real_address(blocks: &HashMap<u64, Block>, leader: u64) -> u64 {
+    blocks
+        .get(&leader)
+        .and_then(|block| block.instructions.first())
+        .map_or(leader, |instruction| instruction.address)
+}
+
+pub fn find_infeasible_paths(graph: &MappedGraph, values: &ValueAnalysis) -> Vec<InfeasiblePath> {
+    let blocks = graph
+        .get_nodes()
+        .into_iter()
+        .map(|block| (block.leader, block))
+        .collect::<HashMap<u64, Block>>();
+    let edge_name = |source: u64, target: u64| {
+        format!(
+            "0x{:x} -> 0x{:x}",
+            real_address(&blocks, source),
+            real_address(&blocks, target)
+        )
+    };
+
+    let mut infeasible_paths = Vec::new();
+
+    let conditions = blocks
+        .values()
+        .filter_map(|block| get_condition(block, values))
+        .collect::<Vec<_>>();
+
+    // the branches decided by the value analysis
+    for condition in &conditions {
+        for taken in [true, false] {
+            let feasible = representatives(&condition.interval, &[condition.constant])
+                .iter()
+                .any(|value| condition.predicate.holds(*value, condition.constant) == taken);
+            if !feasible {
+                let target = condition.target(taken);
+                infeasible_paths.push(InfeasiblePath {
+                    elements: vec![PathElement::Edge(condition.leader, target)],
+                    reason: format!(
+                        "{} is never taken, the tested value is {}",
+                        edge_name(condition.leader, target),
+                        Value::Number(condition.interval)
+                    ),
+                });
+            }
+        }
+    }
+
+    // the branches that test the same value, unchanged between them
+    for first in &conditions {
+        let after_first = reachable(graph, &blocks[&first.leader], Outgoing);
+        for second in &conditions {
+            if first.leader == second.leader
+                || first.tested != second.tested
+                || !after_first.contains(&second.leader)
+            {
+                continue;
+            }
+
+            let before_second = reachable(graph, &blocks[&second.leader], Incoming);
+            let first_block = &blocks[&first.leader];
+            let second_block = &blocks[&second.leader];
+            let between = after_first
+                .intersection(&before_second)
+                .flat_map(|leader| {
+                    let block = &blocks[leader];
+                    block.instructions.iter().map(|i| (block.leader, i))
+                })
+                .chain(
+                    first_block.instructions[first.read_index..]
+                        .iter()
+                        .map(|i| (first.leader, i)),
+                )
+                .chain(
+                    second_block.instructions[..second.read_index]
+                        .iter()
+                        .map(|i| (second.leader, i)),
+                )
+                .collect::<Vec<_>>();
+            if between
+                .iter()
+                .any(|(leader, instruction)| writes(instruction, *leader, &first.tested, values))
+            {
+                continue;
+            }
+
+            let constants = [first.constant, second.constant];
+            let values_tested = representatives(&first.interval, &constants);
+            for first_taken in [true, false] {
+                for second_taken in [true, false] {
+                    let feasible = values_tested.iter().any(|value| {
+                        first.predicate.holds(*value, first.constant) == first_taken
+                            && second.predicate.holds(*value, second.constant) == second_taken
+                    });
+                    if !feasible {
+                        let first_target = first.target(first_taken);
+                        let second_target = second.target(second_taken);
+                        infeasible_paths.push(InfeasiblePath {
+                            elements: vec![
+                                PathElement::Edge(first.leader, first_target),
+                                PathElement::Edge(second.leader, second_target),
+                            ],
+                            reason: format!(
+                                "{} and {} test the same value",
+                                edge_name(first.leader, first_target),
+                                edge_name(second.leader, second_target)
+                            ),
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    infeasible_paths.extend(get_infeasible_paths_from_env(&blocks));
+    infeasible_paths
+}
+
+fn get_infeasible_paths_from_env(blocks: &HashMap<u64, Block>) -> Vec<InfeasiblePath> {
+    let parse_address = |env_var_key: &str, address: &str| {
+        let address = address.trim();
+        match u64::from_str_radix(address.trim_start_matches("0x"), 16) {
+            Ok(address) => address,
+            Err(_) => panic!("The environment variable {env_var_key} is not a valid address list"),
+        }
+    };
+    // the duplicated blocks have fictious leaders, but the instructions keep their addresses
+    let copies = |address: u64| {
+        let mut leaders = blocks
+            .values()
+            .filter(|block| block.instructions.first().map(|i| i.address) == Some(address))
+            .map(|block| block.leader)
+            .collect::<Vec<_>>();
+        leaders.sort();
+        leaders
+    };
+
+    let mut infeasible_paths = Vec::new();
+    let mut env_vars = std::env::vars()
+        .filter(|(key, _)| key.starts_with("INFEASIBLE_0x"))
+        .collect::<Vec<_>>();
+    env_vars.sort();
+
+    for (key, value) in env_vars {
+        let first = parse_address(&key, key.trim_start_matches("INFEASIBLE_"));
+        for second in value.split(',').map(|address| parse_address(&key, address)) {
+            for first_leader in copies(first) {
+                for second_leader in copies(second) {
+                    infeasible_paths.push(InfeasiblePath {
+                        elements: vec![
+                            PathElement::Block(first_leader),
+                            PathElement::Block(second_leader),
+                        ],
+                        reason: format!(
+                            "0x{first:x} and 0x{second:x} never execute together ({key})"
+                        ),
+                    });
+                }
+            }
+        }
+    }
+
+    infeasible_paths
+}
+
+// the blocks of the element are a node of the condensed graph, not a part of a cycle
+fn is_visible(graph: &MappedCondensedGraph, element: &PathElement) -> bool {
+    let is_single_block = |leader: &u64| {
+        graph
+            .node_index_map
+            .get(leader)
+            .is_some_and(|node_index| graph.graph[*node_index].len() == 1)
+    };
+    match element {
+        PathElement::Block(leader) => is_single_block(leader),
+        PathElement::Edge(source, target) => {
+            is_single_block(source)
+                && is_single_block(target)
+                && graph.edge_index_map.contains_key(&(*source, *target))
+        }
+    }
+}
+
+fn is_on_path(element: &PathElement, path: &[u64]) -> bool {
+    match element {
+        PathElement::Block(leader) => path.contains(leader),
+        PathElement::Edge(source, target) => path.windows(2).any(|w| w == [*source, *target]),
+    }
+}
+
+fn remove_element(graph: &mut MappedCondensedGraph, element: &PathElement) {
+    let node = |graph: &MappedCondensedGraph, leader: &u64| {
+        graph
+            .node_index_map
+            .get(leader)
+            .map(|node_index| graph.graph[*node_index].clone())
+    };
+    match element {
+        PathElement::Block(leader) => {
+            if let Some(blocks) = node(graph, leader) {
+                graph.remove_node(&blocks);
+            }
+        }
+        PathElement::Edge(source, target) => {
+            if let (Some(source_blocks), Some(target_blocks)) =
+                (node(graph, source), node(graph, target))
+            {
+                if graph.edge_index_map.contains_key(&(*source, *target)) {
+                    graph.remove_edge(&source_blocks, &target_blocks);
+                }
+            }
+        }
+    }
+}
+
+// longest path from the source that doesn't go through an infeasible path, None if there is none.
+// The longest path is searched again without one element of the infeasible path it contains
+pub fn longest_feasible_path(
+    graph: &MappedCondensedGraph,
+    source: &[Block],
+    infeasible_paths: &[InfeasiblePath],
+) -> Option<u64> {
+    let infeasible_paths = infeasible_paths
+        .iter()
+        .filter(|path| {
+            path.elements
+                .iter()
+                .all(|element| is_visible(graph, element))
+        })
+        .collect::<Vec<_>>();
+
+    let mut best: Option<u64> = None;
+    let mut searches = 0;
+    let mut candidates = vec![graph.clone()];
+
+    while let Some(candidate) = candidates.pop() {
+        searches += 1;
+        if searches > MAX_PATH_SEARCHES {
+            printwarning!(
+                "More than {MAX_PATH_SEARCHES} longest path searches for the infeasible paths -> they are not considered for the wcet calculation"
+            );
+            return graph.longest_path(source).ok();
+        }
+
+        if !candidate.node_index_map.contains_key(&source[0].leader) {
+            continue;
+        }
+        let (length, path) = candidate.longest_path_nodes(source).unwrap();
+        // removing elements only makes the path shorter
+        if best.is_some_and(|best| length <= best) {
+            continue;
+        }
+
+        match infeasible_paths
+            .iter()
+            .find(|infeasible| infeasible.elements.iter().all(|e| is_on_path(e, &path)))
+        {
+            Some(infeasible) => {
+                for element in &infeasible.elements {
+                    let mut next_candidate = candidate.clone();
+                    remove_element(&mut next_candidate, element);
+                    candidates.push(next_candidate);
+                }
+            }
+            None => best = Some(length),
+        }
+    }
+
+    best
+}
+
+impl std::fmt::Display for InfeasiblePath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Infeasible path: {}", self.reason)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::testing::{block, disassemble, graph, nop_block, x86_64};
+    use crate::value::analyze_values;
+
+    fn conditional(taken: u64, not_taken: u64) -> Option<ExitJump> {
+        Some(ExitJump::ConditionalRelative { taken, not_taken })
+    }
+
+    // cmp rdi, 5 and the branch at the base, 5 nops at base + 0x10, cmp rdi, 5 and the second branch
+    // (or mov rdi, rax; cmp rdi, 5 and the branch) at base + 0x20, 5 nops at base + 0x30 and ret at base + 0x40
+    fn correlated_branches(
+        base: u64,
+        first_branch: u8,
+        second_branch: u8,
+        write: bool,
+    ) -> MappedGraph {
+        let cmp = [0x48, 0x83, 0xff, 0x05];
+        let second = match write {
+            true => [&[0x48, 0x89, 0xc7][..], &cmp, &[second_branch, 0x00]].concat(),
+            false => [&cmp[..], &[second_branch, 0x00]].concat(),
+        };
+        let x86_64 = x86_64();
+        graph(&[
+            block(
+                disassemble(&x86_64, base, &[&cmp[..], &[first_branch, 0x00]].concat()),
+                conditional(base + 0x10, base + 0x20),
+            ),
+            block(
+                disassemble(&x86_64, base + 0x10, &[0x90; 5]),
+                Some(ExitJump::Next(base + 0x20)),
+            ),
+            block(
+                disassemble(&x86_64, base + 0x20, &second),
+                conditional(base + 0x30, base + 0x40),
+            ),
+            block(
+                disassemble(&x86_64, base + 0x30, &[0x90; 5]),
+                Some(ExitJump::Next(base + 0x40)),
+            ),
+            block(disassemble(&x86_64, base + 0x40, &[0xc3]), None),
+        ])
+    }
+
+    fn elements(infeasible_paths: &[InfeasiblePath]) -> Vec<Vec<PathElement>> {
+        infeasible_paths
+            .iter()
+            .map(|path| path.elements.clone())
+            .collect()
+    }
+
+    // the longest path from the entry with and without the infeasible paths
+    fn longest_paths(
+        mut graph: MappedGraph,
+        entry: u64,
+        infeasible_paths: &[InfeasiblePath],
+    ) -> (u64, u64) {
+        let condensed_graph = graph.condense_cycles();
+        let source = condensed_graph
+            .get_nodes()
+            .into_iter()
+            .find(|node| node[0].leader == entry)
+            .unwrap();
+        (
+            condensed_graph.longest_path(&source).unwrap(),
+            longest_feasible_path(&condensed_graph, &source, infeasible_paths).unwrap(),
+        )
+    }
+
+    #[test]
+    fn branches_on_the_same_value() {
+        // jl after the first cmp, jge after the second one: the nops at 0x3010 and 0x3030 never run together
+        let graph = correlated_branches(0x3000, 0x7c, 0x7d, false);
+        let values = analyze_values(&graph, &x86_64());
+        let infeasible_paths = find_infeasible_paths(&graph, &values);
+
+        assert_eq!(
+            elements(&infeasible_paths),
+            [
+                vec![
+                    PathElement::Edge(0x3000, 0x3010),
+                    PathElement::Edge(0x3020, 0x3030)
+                ],
+                vec![
+                    PathElement::Edge(0x3000, 0x3020),
+                    PathElement::Edge(0x3020, 0x3040)
+                ],
+            ]
+        );
+        assert_eq!(
+            infeasible_paths[0].to_string(),
+            "Infeasible path: 0x3000 -> 0x3010 and 0x3020 -> 0x3030 test the same value"
+        );
+        let (longest, feasible) = longest_paths(graph, 0x3000, &infeasible_paths);
+        assert_eq!(longest - feasible, 5);
+    }
+
+    #[test]
+    fn branches_on_a_value_written_between_them() {
+        let graph = correlated_branches(0x3100, 0x7c, 0x7d, true);
+        let values = analyze_values(&graph, &x86_64());
+
+        assert!(find_infeasible_paths(&graph, &values).is_empty());
+    }
+
+    #[test]
+    fn branch_decided_by_the_value_analysis() {
+        // mov edi, 3 before the first cmp: jl is always taken
+        let graph = correlated_branches(0x3200, 0x7c, 0x7c, false);
+        let mut blocks = graph
+            .get_nodes()
+            .into_iter()
+            .map(|block| (block.leader, block))
+            .collect::<BTreeMap<_, _>>();
+        blocks.insert(
+            0x31f0,
+            block(
+                disassemble(&x86_64(), 0x31f0, &[0xbf, 0x03, 0x00, 0x00, 0x00]),
+                Some(ExitJump::Next(0x3200)),
+            ),
+        );
+        let graph = MappedGraph::from_blocks(&blocks);
+        let values = analyze_values(&graph, &x86_64());
+        let infeasible_paths = find_infeasible_paths(&graph, &values);
+
+        // the pairs of the two branches are infeasible too, the decided branches are single edges
+        let mut infeasible_edges = elements(&infeasible_paths)
+            .into_iter()
+            .filter(|elements| elements.len() == 1)
+            .collect::<Vec<_>>();
+        infeasible_edges.sort();
+        assert_eq!(
+            infeasible_edges,
+            [
+                vec![PathElement::Edge(0x3200, 0x3220)],
+                vec![PathElement::Edge(0x3220, 0x3240)],
+            ]
+        );
+    }
+
+    #[test]
+    fn infeasible_blocks_from_the_env() {
+        std::env::set_var("INFEASIBLE_0x3310", "0x3330");
+        let graph = graph(&[
+            nop_block(0x3300, conditional(0x3310, 0x3320)),
+            nop_block(0x3310, Some(ExitJump::Next(0x3320))),
+            nop_block(0x3320, conditional(0x3330, 0x3340)),
+            nop_block(0x3330, Some(ExitJump::Next(0x3340))),
+            nop_block(0x3340, None),
+        ]);
+        let values = analyze_values(&graph, &x86_64());
+        let infeasible_paths = find_infeasible_paths(&graph, &values);
+
+        assert_eq!(
+            elements(&infeasible_paths),
+            [vec![PathElement::Block(0x3310), PathElement::Block(0x3330)]]
+        );
+        assert_eq!(longest_paths(graph, 0x3300, &infeasible_paths), (4, 3));
+    }
+}
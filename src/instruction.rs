@@ -1,73 +1,458 @@
-use capstone::Insn;
+use capstone::arch::arm::ArmOperandType;
+use capstone::arch::arm64::Arm64OperandType;
+use capstone::arch::mips::MipsOperand;
+use capstone::arch::ppc::PpcOperand;
+use capstone::arch::riscv::RiscVOperand;
+use capstone::arch::sparc::SparcOperand;
+use capstone::arch::x86::X86OperandType;
+use capstone::arch::ArchOperand;
+use capstone::{Arch, Capstone, Insn, RegAccessType, RegId};
 
 use crate::CURRENT_ARCH;
 
+pub const DEFAULT_LATENCY: u32 = 1; // clock cycles of the instructions without a latency rule
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub enum Operand {
+    Reg(String),
+    Imm(i64),
+    Mem {
+        base: Option<String>,
+        index: Option<String>,
+        scale: i32,
+        disp: i64,
+    },
+}
+
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
 pub struct Instruction {
     pub address: u64,
+    pub size: u32, // bytes
     pub mnemonic: String,
-    pub operands: (Option<String>, Option<String>),
-    pub latency: u32, // clock cycles
+    pub op_str: String,
+    pub operands: Vec<Operand>,
+    pub regs_read: Vec<String>,
+    pub regs_write: Vec<String>,
+    pub latency: u32,          // clock cycles, upper bound used for the WCET
+    pub bcet_latency: u32,     // clock cycles, lower bound used for the BCET
+    pub default_latency: bool, // no latency rule matched the instruction
+    // extra clock cycles of a conditional branch in each direction, added on the edges of the graph
+    pub taken_penalty: u32,
+    pub not_taken_penalty: u32,
 }
 
-impl<'a> From<&'a Insn<'a>> for Instruction {
-    fn from(insn: &'a Insn<'a>) -> Self {
+impl Instruction {
+    pub fn new(insn: &Insn, cs: &Capstone) -> Self {
         let mnemonic = insn.mnemonic().unwrap().to_string();
 
-        let operands = match insn.op_str() {
-            Some(operands) => {
-                let operands = operands.split(',').map(|o| o.trim()).collect::<Vec<&str>>();
-                if operands.len() == 1 {
-                    (Some(operands[0]), None)
-                } else {
-                    (Some(operands[0]), Some(operands[1]))
+        let mut operands = Vec::new();
+        let mut operand_sizes = Vec::new(); // bits, 0 if unknown
+        let mut regs_read = Vec::new();
+        let mut regs_write = Vec::new();
+
+        if let Ok(insn_detail) = cs.insn_detail(insn) {
+            let arch_operands = insn_detail.arch_detail().operands();
+
+            (operands, operand_sizes) = arch_operands
+                .iter()
+                .cloned()
+                .filter_map(|op| {
+                    let size = match &op {
+                        ArchOperand::X86Operand(op) => op.size as u32 * 8,
+                        _ => 0,
+                    };
+                    Some((Operand::new(op, cs)?, size))
+                })
+                .unzip();
+
+            // implicit registers (e.g. the stack pointer of push or the flags of cmp)
+            regs_read.extend(
+                insn_detail
+                    .regs_read()
+                    .iter()
+                    .filter_map(|r| reg_name(cs, *r)),
+            );
+            regs_write.extend(
+                insn_detail
+                    .regs_write()
+                    .iter()
+                    .filter_map(|r| reg_name(cs, *r)),
+            );
+
+            let x86_accesses = arch_operands
+                .iter()
+                .filter_map(|op| match op {
+                    ArchOperand::X86Operand(op) => Some(op.access),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+
+            // registers of memory operands are only read
+            for operand in &operands {
+                if let Operand::Mem { base, index, .. } = operand {
+                    regs_read.extend(base.iter().chain(index.iter()).cloned());
                 }
             }
-            None => (None, None),
-        };
 
-        let arch_mode = CURRENT_ARCH.with(|arch| arch.borrow().clone());
+            let explicit_registers = operands.iter().enumerate().filter_map(|(i, op)| match op {
+                Operand::Reg(reg) => Some((i, reg.clone())),
+                _ => None,
+            });
 
-        let arch_mnemonic_str = if let Some(arch_mode) = arch_mode {
-            format!(
-                "{}_{}",
-                arch_mode.arch.to_string().to_uppercase(),
-                mnemonic.to_uppercase()
-            )
-        } else {
-            panic!("No architecture set")
-        };
+            if x86_accesses.len() == operands.len() {
+                // x86 tells how every operand is accessed
+                for (i, reg) in explicit_registers {
+                    let access = x86_accesses[i].unwrap_or(RegAccessType::ReadOnly);
+                    if access.is_readable() {
+                        regs_read.push(reg.clone());
+                    }
+                    if access.is_writable() {
+                        regs_write.push(reg);
+                    }
+                }
+            } else {
+                // the destination is the first operand, unless the instruction only reads its operands
+                let writes_first_operand = !is_read_only_instruction(&mnemonic, &operands);
+                for (i, reg) in explicit_registers {
+                    if i == 0 && writes_first_operand {
+                        regs_write.push(reg);
+                    } else {
+                        regs_read.push(reg);
+                    }
+                }
+            }
+        }
+
+        let arch_mnemonic_str = get_arch_mnemonic_str(&mnemonic);
 
-        let latency = match std::env::var(arch_mnemonic_str) {
-            Ok(latency) => latency.parse::<u32>().unwrap(),
-            _ => 1,
+        let latency_rule = get_latency_rule(&arch_mnemonic_str, &operands, &operand_sizes);
+        let (bcet_latency, latency) = latency_rule.unwrap_or((DEFAULT_LATENCY, DEFAULT_LATENCY));
+
+        // e.g. X86_JE_TAKEN=3 and X86_JE_NOT_TAKEN=1, the block keeps the cheapest direction
+        let direction_latency = |direction: &str| {
+            let env_var_key = format!("{arch_mnemonic_str}_{direction}");
+            let value = std::env::var(&env_var_key).ok()?;
+            Some(parse_latency_range(&env_var_key, &value))
         };
+        let taken_rule = direction_latency("TAKEN");
+        let not_taken_rule = direction_latency("NOT_TAKEN");
+        let default_latency =
+            latency_rule.is_none() && taken_rule.is_none() && not_taken_rule.is_none();
+
+        let (taken_bcet_latency, taken_latency) = taken_rule.unwrap_or((bcet_latency, latency));
+        let (not_taken_bcet_latency, not_taken_latency) =
+            not_taken_rule.unwrap_or((bcet_latency, latency));
+        let latency = taken_latency.min(not_taken_latency);
+        let bcet_latency = taken_bcet_latency.min(not_taken_bcet_latency);
 
         Instruction {
             address: insn.address(),
+            size: insn.bytes().len() as u32,
             mnemonic,
-            operands: (
-                operands.0.map(|s| s.to_string()),
-                operands.1.map(|s| s.to_string()),
-            ),
+            op_str: insn.op_str().unwrap_or_default().to_string(),
+            operands,
+            regs_read,
+            regs_write,
             latency,
+            bcet_latency: bcet_latency.min(latency),
+            default_latency,
+            taken_penalty: taken_latency - latency,
+            not_taken_penalty: not_taken_latency - latency,
+        }
+    }
+}
+
+impl Instruction {
+    // extra clock cycles of the instruction, e.g. of a cache miss
+    pub fn add_latency(&mut self, latency: u32) {
+        self.latency = self.latency.checked_add(latency).unwrap_or_else(|| {
+            panic!(
+                "The latency of the instruction at 0x{:x} overflows u32: {} + {latency} clock cycles",
+                self.address, self.latency
+            )
+        });
+    }
+
+    // prefix of the latency variables of the instruction, e.g. X86_IMUL
+    pub fn arch_mnemonic_str(&self) -> String {
+        get_arch_mnemonic_str(&self.mnemonic)
+    }
+}
+
+fn get_arch_mnemonic_str(mnemonic: &str) -> String {
+    let arch_mode = CURRENT_ARCH.with(|arch| arch.borrow().clone());
+
+    let Some(arch_mode) = arch_mode else {
+        panic!("No architecture set")
+    };
+
+    // the thumb width qualifiers don't change the timing, the other dots can't be in env variable names
+    let mnemonic = match arch_mode.arch {
+        Arch::ARM => mnemonic.trim_end_matches(".w").trim_end_matches(".n"),
+        _ => mnemonic,
+    };
+
+    format!(
+        "{}_{}",
+        arch_mode.arch.to_string().to_uppercase(),
+        mnemonic.to_uppercase().replace('.', "_")
+    )
+}
+
+impl Operand {
+    fn new(op: ArchOperand, cs: &Capstone) -> Option<Self> {
+        let reg = |reg: RegId| Some(Operand::Reg(reg_name(cs, reg)?));
+
+        match op {
+            ArchOperand::X86Operand(op) => match op.op_type {
+                X86OperandType::Reg(r) => reg(r),
+                X86OperandType::Imm(imm) => Some(Operand::Imm(imm)),
+                X86OperandType::Mem(mem) => Some(Operand::Mem {
+                    base: reg_name(cs, mem.base()),
+                    index: reg_name(cs, mem.index()),
+                    scale: mem.scale(),
+                    disp: mem.disp(),
+                }),
+                X86OperandType::Invalid => None,
+            },
+            ArchOperand::ArmOperand(op) => match op.op_type {
+                ArmOperandType::Reg(r) => reg(r),
+                ArmOperandType::Imm(imm) => Some(Operand::Imm(imm as i64)),
+                ArmOperandType::Mem(mem) => Some(Operand::Mem {
+                    base: reg_name(cs, mem.base()),
+                    index: reg_name(cs, mem.index()),
+                    scale: mem.scale(),
+                    disp: mem.disp() as i64,
+                }),
+                _ => None,
+            },
+            ArchOperand::Arm64Operand(op) => match op.op_type {
+                Arm64OperandType::Reg(r) => reg(r),
+                Arm64OperandType::Imm(imm) => Some(Operand::Imm(imm)),
+                Arm64OperandType::Mem(mem) => Some(Operand::Mem {
+                    base: reg_name(cs, mem.base()),
+                    index: reg_name(cs, mem.index()),
+                    scale: 1,
+                    disp: mem.disp() as i64,
+                }),
+                _ => None,
+            },
+            ArchOperand::RiscVOperand(op) => match op {
+                RiscVOperand::Reg(r) => reg(r),
+                RiscVOperand::Imm(imm) => Some(Operand::Imm(imm)),
+                RiscVOperand::Mem(mem) => Some(Operand::Mem {
+                    base: reg_name(cs, mem.base()),
+                    index: None,
+                    scale: 1,
+                    disp: mem.disp(),
+                }),
+                RiscVOperand::Invalid => None,
+            },
+            ArchOperand::MipsOperand(op) => match op {
+                MipsOperand::Reg(r) => reg(r),
+                MipsOperand::Imm(imm) => Some(Operand::Imm(imm)),
+                MipsOperand::Mem(mem) => Some(Operand::Mem {
+                    base: reg_name(cs, mem.base()),
+                    index: None,
+                    scale: 1,
+                    disp: mem.disp(),
+                }),
+                MipsOperand::Invalid => None,
+            },
+            ArchOperand::PpcOperand(op) => match op {
+                PpcOperand::Reg(r) => reg(r),
+                PpcOperand::Imm(imm) => Some(Operand::Imm(imm)),
+                PpcOperand::Mem(mem) => Some(Operand::Mem {
+                    base: reg_name(cs, mem.base()),
+                    index: None,
+                    scale: 1,
+                    disp: mem.disp() as i64,
+                }),
+                _ => None,
+            },
+            ArchOperand::SparcOperand(op) => match op {
+                SparcOperand::Reg(r) => reg(r),
+                SparcOperand::Imm(imm) => Some(Operand::Imm(imm)),
+                SparcOperand::Mem(mem) => Some(Operand::Mem {
+                    base: reg_name(cs, mem.base()),
+                    index: reg_name(cs, mem.index()),
+                    scale: 1,
+                    disp: mem.disp() as i64,
+                }),
+                SparcOperand::Invalid => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+// stores, compares and branches read all their register operands
+fn is_read_only_instruction(mnemonic: &str, operands: &[Operand]) -> bool {
+    let mnemonic = mnemonic.trim_start_matches("c.");
+    let is_store = operands.iter().any(|op| matches!(op, Operand::Mem { .. }))
+        && (mnemonic.starts_with("st") || matches!(mnemonic, "sb" | "sh" | "sw" | "sd" | "push"));
+
+    is_store
+        || matches!(
+            mnemonic,
+            "cmp" | "cmn" | "tst" | "teq" | "push" | "bx" | "blx" | "jr" | "jalr"
+        )
+        || mnemonic.starts_with('b') && !mnemonic.starts_with("bic") && !mnemonic.starts_with("bfi")
+        || mnemonic.starts_with("cb")
+        || mnemonic.starts_with("tb")
+}
+
+// the most specific rule wins: X86_IMUL_R64_M64X, X86_IMUL_R64_M64, X86_IMUL_R_M and then X86_IMUL.
+// R, M and I are register, memory and immediate operands, followed by the size in bits (x86 only)
+// and for memory by the addressing mode: A absolute or pc relative, B base + displacement, X indexed.
+// The latency is a number or a min-max range for data-dependent instructions (e.g. X86_IDIV_R64=40-90)
+fn get_latency_rule(
+    arch_mnemonic_str: &str,
+    operands: &[Operand],
+    operand_sizes: &[u32],
+) -> Option<(u32, u32)> {
+    let pattern = |with_size: bool, with_mode: bool| {
+        operands
+            .iter()
+            .zip(operand_sizes)
+            .map(|(operand, size)| {
+                let kind = match operand {
+                    Operand::Reg(_) => "R",
+                    Operand::Imm(_) => "I",
+                    Operand::Mem { .. } => "M",
+                };
+                let size = if with_size && *size > 0 {
+                    size.to_string()
+                } else {
+                    String::new()
+                };
+                let mode = match operand {
+                    Operand::Mem { base, index, .. } if with_mode => {
+                        match (base.as_deref(), index) {
+                            (None | Some("rip" | "pc"), None) => "A",
+                            (_, None) => "B",
+                            (_, Some(_)) => "X",
+                        }
+                    }
+                    _ => "",
+                };
+                format!("{kind}{size}{mode}")
+            })
+            .collect::<Vec<_>>()
+            .join("_")
+    };
+
+    let mut keys = Vec::new();
+    if !operands.is_empty() {
+        for (with_size, with_mode) in [(true, true), (true, false), (false, false)] {
+            let key = format!("{arch_mnemonic_str}_{}", pattern(with_size, with_mode));
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
         }
     }
+    keys.push(arch_mnemonic_str.to_string());
+
+    keys.iter().find_map(|key| {
+        let value = std::env::var(key).ok()?;
+        Some(parse_latency_range(key, &value))
+    })
+}
+
+fn parse_latency_range(env_var_key: &str, value: &str) -> (u32, u32) {
+    let parse_number = |number: &str| match number.trim().parse::<u32>() {
+        Ok(number) => number,
+        Err(_) => panic!("The environment variable {env_var_key} is not a valid latency"),
+    };
+
+    match value.split_once('-') {
+        Some((min, max)) => {
+            let (min, max) = (parse_number(min), parse_number(max));
+            if min > max {
+                panic!("The environment variable {env_var_key} is not a valid latency range");
+            }
+            (min, max)
+        }
+        None => {
+            let latency = parse_number(value);
+            (latency, latency)
+        }
+    }
+}
+
+// register id 0 is the invalid register (e.g. a memory operand without index)
+fn reg_name(cs: &Capstone, reg: RegId) -> Option<String> {
+    if reg == RegId::INVALID_REG {
+        None
+    } else {
+        cs.reg_name(reg)
+    }
 }
 
 impl std::fmt::Display for Instruction {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let operands_str = match &self.operands {
-            (Some(op1), Some(op2)) => format!("{op1}, {op2}"),
-            (Some(op1), None) => op1.to_string(),
-            _ => "".to_string(),
-        };
         write!(
             f,
             "0x{:x} {} {}",
             self.address,
             self.mnemonic,
-            operands_str.trim()
+            self.op_str.trim()
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{disassemble, x86_64};
+
+    #[test]
+    fn parse_latency_ranges() {
+        assert_eq!(parse_latency_range("X86_ADD", "7"), (7, 7));
+        assert_eq!(parse_latency_range("X86_IDIV_R64", "40-90"), (40, 90));
+        assert_eq!(parse_latency_range("X86_IDIV_R64", " 3 - 5 "), (3, 5));
+        assert!(std::panic::catch_unwind(|| parse_latency_range("X86_IDIV_R64", "90-40")).is_err());
+        assert!(std::panic::catch_unwind(|| parse_latency_range("X86_IDIV_R64", "-4")).is_err());
+        assert!(std::panic::catch_unwind(|| parse_latency_range("X86_ADD", "fast")).is_err());
+    }
+
+    #[test]
+    fn most_specific_latency_rule() {
+        std::env::set_var("X86_IMUL", "9");
+        std::env::set_var("X86_IMUL_R_R", "7");
+        std::env::set_var("X86_IMUL_R64_R64", "3-6");
+        std::env::set_var("X86_IMUL_R64_M64B", "20");
+
+        // imul rax, rbx; imul eax, ebx; imul rax, qword ptr [rbx]; imul rax, qword ptr [rbx + 8*rcx]
+        let code = [
+            0x48, 0x0f, 0xaf, 0xc3, 0x0f, 0xaf, 0xc3, 0x48, 0x0f, 0xaf, 0x03, 0x48, 0x0f, 0xaf,
+            0x04, 0xcb,
+        ];
+        let latencies = disassemble(&x86_64(), 0x8000, &code)
+            .iter()
+            .map(|instruction| {
+                (
+                    instruction.bcet_latency,
+                    instruction.latency,
+                    instruction.default_latency,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            latencies,
+            [(3, 6, false), (7, 7, false), (20, 20, false), (9, 9, false)]
+        );
+    }
+
+    #[test]
+    fn branch_latency_of_each_direction() {
+        std::env::set_var("X86_JNS_TAKEN", "3-4");
+        std::env::set_var("X86_JNS_NOT_TAKEN", "1");
+
+        // jns with the cheapest direction as latency and the rest as the penalty of the other one
+        let jns = &disassemble(&x86_64(), 0x8100, &[0x79, 0x00])[0];
+        assert_eq!((jns.bcet_latency, jns.latency), (1, 1));
+        assert_eq!((jns.taken_penalty, jns.not_taken_penalty), (3, 0));
+        assert!(!jns.default_latency);
+    }
+}
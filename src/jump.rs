@@ -1,3 +1,11 @@
+use capstone::arch::arm::{ArmCC, ArmInsn, ArmOperandType, ArmReg};
+use capstone::arch::arm64::Arm64OperandType;
+use capstone::arch::mips::MipsOperand;
+use capstone::arch::ppc::PpcOperand;
+use capstone::arch::riscv::RiscVOperand;
+use capstone::arch::sparc::SparcOperand;
+use capstone::arch::x86::X86OperandType;
+use capstone::arch::{ArchDetail, ArchOperand, DetailsArchInsn};
 use capstone::{Arch, Insn, InsnDetail, InsnGroupType};
 
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
@@ -8,6 +16,7 @@ pub enum ExitJump {
     UnconditionalAbsolute(u64),
     Indirect,
     Ret(u64),
+    ConditionalRet { ret: u64, not_taken: u64 },
     Call(u64, u64), // target, return address
     Next(u64),
 }
@@ -41,12 +50,46 @@ impl std::fmt::Display for ExitJump {
                     write!(f, "Ret {{ targets: None }}")
                 }
             }
+            ExitJump::ConditionalRet { ret, not_taken } => {
+                if *ret != 0 {
+                    write!(
+                        f,
+                        "ConditionalRet {{ ret: 0x{ret:x}, not_taken: 0x{not_taken:x} }}"
+                    )
+                } else {
+                    write!(
+                        f,
+                        "ConditionalRet {{ ret: None, not_taken: 0x{not_taken:x} }}"
+                    )
+                }
+            }
             ExitJump::Call(target, _) => write!(f, "Call {{ target: 0x{target:x} }}"),
             ExitJump::Next(target) => write!(f, "Next {{ target: 0x{target:x} }}"),
         }
     }
 }
 
+impl ExitJump {
+    pub fn is_ret(&self) -> bool {
+        matches!(self, ExitJump::Ret(_) | ExitJump::ConditionalRet { .. })
+    }
+
+    // the return address of a (conditional) return, 0 if not yet resolved
+    pub fn ret_target(&self) -> Option<u64> {
+        match self {
+            ExitJump::Ret(ret) | ExitJump::ConditionalRet { ret, .. } => Some(*ret),
+            _ => None,
+        }
+    }
+
+    pub fn set_ret_target(&mut self, target: u64) {
+        match self {
+            ExitJump::Ret(ret) | ExitJump::ConditionalRet { ret, .. } => *ret = target,
+            _ => {}
+        }
+    }
+}
+
 pub fn get_exit_jump(
     insn: &Insn,
     next_insn: &Insn,
@@ -55,17 +98,48 @@ pub fn get_exit_jump(
 ) -> Option<ExitJump> {
     let insn_group_ids = insn_detail.groups();
 
+    // on ARM the condition code tells if the instruction is predicated (also inside IT blocks)
+    let mut is_predicated = false;
+
+    if let ArchDetail::ArmDetail(arm_detail) = insn_detail.arch_detail() {
+        is_predicated = !matches!(arm_detail.cc(), ArmCC::ARM_CC_AL | ArmCC::ARM_CC_INVALID);
+
+        if is_arm_return(insn, &arm_detail) {
+            if is_predicated {
+                return Some(ExitJump::ConditionalRet {
+                    ret: 0, // the correct value can't be determined here
+                    not_taken: next_insn.address(),
+                });
+            }
+            return Some(ExitJump::Ret(0));
+        }
+
+        // any other write to pc that is not a branch is an indirect jump (e.g. ldrne pc, [r1])
+        let writes_pc = arm_detail.operands().next().is_some_and(|op| {
+            matches!(op.op_type, ArmOperandType::Reg(reg) if reg.0 as u32 == ArmReg::ARM_REG_PC)
+        });
+        let is_branch = insn_group_ids.iter().any(|id| {
+            let id = id.0 as u32;
+            id == InsnGroupType::CS_GRP_JUMP || id == InsnGroupType::CS_GRP_CALL
+        });
+        if writes_pc && !is_branch {
+            return Some(ExitJump::Indirect);
+        }
+    }
+
     // check if the instruction is a jump and check its JumpType
     let mut is_jump = false;
     let mut is_relative = false;
     let mut is_call = false;
     let mut is_ret = false;
+    let mut is_interrupt = false;
 
     for id in insn_group_ids {
         let id = id.0 as u32;
 
-        if id == InsnGroupType::CS_GRP_INT
-            || id == InsnGroupType::CS_GRP_CALL
+        if id == InsnGroupType::CS_GRP_INT {
+            is_interrupt = true;
+        } else if id == InsnGroupType::CS_GRP_CALL
             || id == InsnGroupType::CS_GRP_JUMP
             || id == InsnGroupType::CS_GRP_RET
             || id == InsnGroupType::CS_GRP_IRET
@@ -74,7 +148,7 @@ pub fn get_exit_jump(
 
             if id == InsnGroupType::CS_GRP_CALL {
                 is_call = true;
-            } else if id == InsnGroupType::CS_GRP_RET {
+            } else if id == InsnGroupType::CS_GRP_RET || id == InsnGroupType::CS_GRP_IRET {
                 is_ret = true;
             }
         } else if id == InsnGroupType::CS_GRP_BRANCH_RELATIVE {
@@ -82,6 +156,51 @@ pub fn get_exit_jump(
         }
     }
 
+    // capstone leaves most RISC-V jumps without groups, so we look at the mnemonic
+    if arch == Arch::RISCV {
+        match insn.mnemonic().unwrap() {
+            "ret" => {
+                is_jump = true;
+                is_ret = true;
+            }
+            "ecall" | "scall" | "ebreak" => is_interrupt = true,
+            "jr" | "c.jr" if insn.op_str() == Some("ra") => {
+                is_jump = true;
+                is_ret = true;
+            }
+            "jal" | "c.jal" | "jalr" | "c.jalr" | "call" => {
+                is_jump = true;
+                is_call = true;
+                is_relative = true;
+            }
+            "j" | "c.j" | "tail" | "jr" | "c.jr" => {
+                is_jump = true;
+                is_relative = true;
+            }
+            op if op.starts_with('b') || op.starts_with("c.b") => {
+                is_jump = true;
+                is_relative = true;
+            }
+            _ => {}
+        }
+    }
+
+    // a return has no branch target, the immediate of ret imm16 is the size of the popped arguments
+    if is_ret {
+        if is_predicated {
+            return Some(ExitJump::ConditionalRet {
+                ret: 0, // the correct value can't be determined here
+                not_taken: next_insn.address(),
+            });
+        }
+        return Some(ExitJump::Ret(0)); // the correct value can't be determined here
+    }
+
+    // an interrupt or a system call (int 0x80, syscall, ecall) goes on with the next instruction
+    if is_interrupt && !is_jump {
+        return None;
+    }
+
     if is_jump {
         let op = insn.mnemonic().unwrap();
         let is_unconditional = match arch {
@@ -111,35 +230,25 @@ pub fn get_exit_jump(
                     | "c.jalr"
             ),
             _ => panic!("Unsupported architecture!"),
-        };
-
-        let operands = insn.op_str().unwrap();
-        let operands = operands.split(',').collect::<Vec<&str>>();
-        let last_operand = operands.last().unwrap().trim();
-
-        if last_operand.contains("0x") {
-            let last_operand =
-                u64::from_str_radix(last_operand.split("0x").collect::<Vec<&str>>()[1], 16)
-                    .unwrap();
+        } && !is_predicated;
 
+        if let Some(target) = get_branch_target(insn, insn_detail, arch) {
             if is_call {
-                return Some(ExitJump::Call(last_operand, next_insn.address()));
+                return Some(ExitJump::Call(target, next_insn.address()));
             }
 
             match (is_relative, is_unconditional) {
-                (true, true) => Some(ExitJump::UnconditionalRelative(last_operand)),
+                (true, true) => Some(ExitJump::UnconditionalRelative(target)),
                 (true, false) => Some(ExitJump::ConditionalRelative {
-                    taken: last_operand,
+                    taken: target,
                     not_taken: next_insn.address(),
                 }),
-                (false, true) => Some(ExitJump::UnconditionalAbsolute(last_operand)),
+                (false, true) => Some(ExitJump::UnconditionalAbsolute(target)),
                 (false, false) => Some(ExitJump::ConditionalAbsolute {
-                    taken: last_operand,
+                    taken: target,
                     not_taken: next_insn.address(),
                 }),
             }
-        } else if is_ret {
-            Some(ExitJump::Ret(0)) // the correct value can't be determined here
         } else {
             Some(ExitJump::Indirect)
         }
@@ -147,3 +256,62 @@ pub fn get_exit_jump(
         None
     }
 }
+
+// the branch target is the last immediate operand of the instruction
+fn get_branch_target(insn: &Insn, insn_detail: &InsnDetail, arch: Arch) -> Option<u64> {
+    let immediate = insn_detail
+        .arch_detail()
+        .operands()
+        .into_iter()
+        .rev()
+        .find_map(|op| match op {
+            ArchOperand::X86Operand(op) => match op.op_type {
+                X86OperandType::Imm(imm) => Some(imm),
+                _ => None,
+            },
+            ArchOperand::ArmOperand(op) => match op.op_type {
+                ArmOperandType::Imm(imm) => Some(imm as u32 as i64),
+                _ => None,
+            },
+            ArchOperand::Arm64Operand(op) => match op.op_type {
+                Arm64OperandType::Imm(imm) => Some(imm),
+                _ => None,
+            },
+            ArchOperand::RiscVOperand(RiscVOperand::Imm(imm))
+            | ArchOperand::MipsOperand(MipsOperand::Imm(imm))
+            | ArchOperand::PpcOperand(PpcOperand::Imm(imm))
+            | ArchOperand::SparcOperand(SparcOperand::Imm(imm)) => Some(imm),
+            _ => None,
+        })?;
+
+    match arch {
+        // RISC-V immediates are offsets from the instruction address
+        Arch::RISCV => Some(insn.address().wrapping_add(immediate as u64)),
+        _ => Some(immediate as u64),
+    }
+}
+
+// pop {..., pc}, ldm sp!, {..., pc}, bx lr and mov pc, lr all return to the caller
+fn is_arm_return(insn: &Insn, arm_detail: &capstone::arch::arm::ArmInsnDetail) -> bool {
+    let registers = arm_detail
+        .operands()
+        .filter_map(|op| match op.op_type {
+            ArmOperandType::Reg(reg) => Some(reg.0 as u32),
+            _ => None,
+        })
+        .collect::<Vec<u32>>();
+
+    let id = insn.id().0;
+
+    if id == ArmInsn::ARM_INS_POP as u32 {
+        registers.contains(&ArmReg::ARM_REG_PC)
+    } else if id == ArmInsn::ARM_INS_LDM as u32 {
+        registers.first() == Some(&ArmReg::ARM_REG_SP) && registers.contains(&ArmReg::ARM_REG_PC)
+    } else if id == ArmInsn::ARM_INS_BX as u32 {
+        registers.first() == Some(&ArmReg::ARM_REG_LR)
+    } else if id == ArmInsn::ARM_INS_MOV as u32 {
+        registers == [ArmReg::ARM_REG_PC, ArmReg::ARM_REG_LR]
+    } else {
+        false
+    }
+}
@@ -0,0 +1,338 @@
+// Loop nesting forest of the control flow graph, built from the dominators.
+// A back edge is an edge whose target dominates its source: the target is the header of a natural loop,
+// made of the blocks that reach the source of the back edge without passing through the header.
+// The loops with the same header are merged, a loop is nested in the smallest loop that contains its header.
+// The irreducible cycles are split before (cycle::split_irreducible_cycles), so every cycle has a header.
+use std::collections::{BTreeMap, BTreeSet};
+
+use petgraph::algo::dominators::simple_fast;
+use petgraph::graphmap::DiGraphMap;
+use petgraph::visit::Dfs;
+use petgraph::Direction::{Incoming, Outgoing};
+
+use crate::graph::MappedGraph;
+use crate::source::source_suffix;
+
+#[derive(Debug, Clone)]
+pub struct Loop {
+    pub header: u64,
+    pub address: u64, // address of the header, the same as the leader unless the header is a copy
+    pub blocks: BTreeSet<u64>, // inner loops included
+    pub back_edges: BTreeSet<(u64, u64)>,
+    pub exit_edges: BTreeSet<(u64, u64)>,
+    pub depth: usize, // 1 for the outermost loops
+    pub parent: Option<u64>,
+    pub children: BTreeSet<u64>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LoopForest {
+    pub loops: BTreeMap<u64, Loop>, // header -> loop
+}
+
+impl LoopForest {
+    pub fn from_graph(graph: &MappedGraph) -> Self {
+        // None is a virtual root before all the blocks without predecessors
+        let mut leaders_graph = DiGraphMap::<Option<u64>, ()>::new();
+        leaders_graph.add_node(None);
+        for block in graph.graph.node_weights() {
+            leaders_graph.add_node(Some(block.leader));
+        }
+        for edge_index in graph.graph.edge_indices() {
+            let (source, target) = graph.graph.edge_endpoints(edge_index).unwrap();
+            leaders_graph.add_edge(
+                Some(graph.graph[source].leader),
+                Some(graph.graph[target].leader),
+                (),
+            );
+        }
+
+        let leaders = graph
+            .graph
+            .node_weights()
+            .map(|block| block.leader)
+            .collect::<BTreeSet<_>>();
+        for leader in &leaders {
+            if leaders_graph
+                .neighbors_directed(Some(*leader), Incoming)
+                .next()
+                .is_none()
+            {
+                leaders_graph.add_edge(None, Some(*leader), ());
+            }
+        }
+
+        // a cycle without predecessors is entered from the root at its lowest block
+        let mut reached = BTreeSet::new();
+        let mut dfs = Dfs::new(&leaders_graph, None);
+        while let Some(node) = dfs.next(&leaders_graph) {
+            reached.insert(node);
+        }
+        while let Some(leader) = leaders
+            .iter()
+            .find(|leader| !reached.contains(&Some(**leader)))
+        {
+            leaders_graph.add_edge(None, Some(*leader), ());
+            dfs.move_to(Some(*leader));
+            while let Some(node) = dfs.next(&leaders_graph) {
+                reached.insert(node);
+            }
+        }
+
+        let dominators = simple_fast(&leaders_graph, None);
+
+        let mut loops = BTreeMap::<u64, Loop>::new();
+        for (source, target, _) in leaders_graph.all_edges() {
+            let (Some(source), Some(header)) = (source, target) else {
+                continue;
+            };
+            let is_back_edge = dominators
+                .dominators(Some(source))
+                .is_some_and(|mut source_dominators| source_dominators.any(|d| d == Some(header)));
+            if !is_back_edge {
+                continue;
+            }
+
+            let header_block = &graph.graph[graph.node_index_map[&header]];
+            let natural_loop = loops.entry(header).or_insert_with(|| Loop {
+                header,
+                address: header_block
+                    .instructions
+                    .first()
+                    .map_or(header, |instruction| instruction.address),
+                blocks: BTreeSet::from([header]),
+                back_edges: BTreeSet::new(),
+                exit_edges: BTreeSet::new(),
+                depth: 0,
+                parent: None,
+                children: BTreeSet::new(),
+            });
+            natural_loop.back_edges.insert((source, header));
+
+            // the blocks that reach the back edge without passing through the header
+            let mut worklist = vec![source];
+            while let Some(leader) = worklist.pop() {
+                if natural_loop.blocks.insert(leader) {
+                    worklist.extend(
+                        leaders_graph
+                            .neighbors_directed(Some(leader), Incoming)
+                            .flatten(),
+                    );
+                }
+            }
+        }
+
+        for natural_loop in loops.values_mut() {
+            for leader in &natural_loop.blocks {
+                for target in leaders_graph.neighbors_directed(Some(*leader), Outgoing) {
+                    if let Some(target) =
+                        target.filter(|target| !natural_loop.blocks.contains(target))
+                    {
+                        natural_loop.exit_edges.insert((*leader, target));
+                    }
+                }
+            }
+        }
+
+        // the parent is the smallest other loop that contains the header
+        let parents = loops
+            .values()
+            .map(|natural_loop| {
+                let parent = loops
+                    .values()
+                    .filter(|other| {
+                        other.header != natural_loop.header
+                            && other.blocks.contains(&natural_loop.header)
+                    })
+                    .min_by_key(|other| other.blocks.len())
+                    .map(|other| other.header);
+                (natural_loop.header, parent)
+            })
+            .collect::<Vec<_>>();
+        for (header, parent) in parents {
+            loops.get_mut(&header).unwrap().parent = parent;
+            if let Some(parent) = parent {
+                loops.get_mut(&parent).unwrap().children.insert(header);
+            }
+        }
+
+        let headers = loops.keys().copied().collect::<Vec<_>>();
+        for header in headers {
+            let mut depth = 1;
+            let mut parent = loops[&header].parent;
+            while let Some(parent_header) = parent {
+                depth += 1;
+                parent = loops[&parent_header].parent;
+            }
+            loops.get_mut(&header).unwrap().depth = depth;
+        }
+
+        LoopForest { loops }
+    }
+
+    // the outermost loops
+    pub fn roots(&self) -> Vec<&Loop> {
+        self.loops
+            .values()
+            .filter(|natural_loop| natural_loop.parent.is_none())
+            .collect()
+    }
+
+    // the inner loops before the outer ones, to compute the latencies bottom-up
+    pub fn post_order(&self) -> Vec<&Loop> {
+        let mut loops = self.loops.values().collect::<Vec<_>>();
+        loops.sort_by_key(|natural_loop| std::cmp::Reverse(natural_loop.depth));
+        loops
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.loops
+            .values()
+            .map(|natural_loop| natural_loop.depth)
+            .max()
+            .unwrap_or(0)
+    }
+
+    // the header of the child of the loop that contains the block, if any
+    pub fn child_containing(&self, natural_loop: &Loop, leader: u64) -> Option<u64> {
+        natural_loop
+            .children
+            .iter()
+            .copied()
+            .find(|child| self.loops[child].blocks.contains(&leader))
+    }
+
+    fn fmt_loop(&self, f: &mut std::fmt::Formatter<'_>, natural_loop: &Loop) -> std::fmt::Result {
+        let indent = "  ".repeat(natural_loop.depth - 1);
+        write!(f, "{indent}Loop 0x{:x}", natural_loop.header)?;
+        if natural_loop.address != natural_loop.header {
+            write!(f, " (copy of 0x{:x})", natural_loop.address)?;
+        }
+        write!(f, "{}", source_suffix(natural_loop.address))?;
+        writeln!(
+            f,
+            ": depth {}, {} blocks",
+            natural_loop.depth,
+            natural_loop.blocks.len()
+        )?;
+
+        let edges = |edges: &BTreeSet<(u64, u64)>| {
+            edges
+                .iter()
+                .map(|(source, target)| format!("0x{source:x} -> 0x{target:x}"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        writeln!(
+            f,
+            "{indent}  back edges: {}",
+            edges(&natural_loop.back_edges)
+        )?;
+        writeln!(
+            f,
+            "{indent}  exit edges: {}",
+            edges(&natural_loop.exit_edges)
+        )?;
+
+        for child in &natural_loop.children {
+            self.fmt_loop(f, &self.loops[child])?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for LoopForest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for natural_loop in self.roots() {
+            self.fmt_loop(f, natural_loop)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+    use crate::jump::ExitJump;
+    use crate::testing::{graph, nop_block};
+
+    #[test]
+    fn nested_loops_with_a_break() {
+        // the inner loop 0x30-0x40 breaks out of both loops from 0x40
+        let conditional =
+            |taken, not_taken| Some(ExitJump::ConditionalRelative { taken, not_taken });
+        let graph = graph(&[
+            nop_block(0x10, Some(ExitJump::Next(0x20))),
+            nop_block(0x20, conditional(0x30, 0x60)),
+            nop_block(0x30, conditional(0x40, 0x50)),
+            nop_block(0x40, conditional(0x30, 0x60)),
+            nop_block(0x50, Some(ExitJump::UnconditionalRelative(0x20))),
+            nop_block(0x60, None),
+        ]);
+        let forest = LoopForest::from_graph(&graph);
+
+        assert_eq!(
+            forest.loops.keys().copied().collect::<Vec<_>>(),
+            [0x20, 0x30]
+        );
+        let outer = &forest.loops[&0x20];
+        assert_eq!(outer.blocks, BTreeSet::from([0x20, 0x30, 0x40, 0x50]));
+        assert_eq!(outer.back_edges, BTreeSet::from([(0x50, 0x20)]));
+        assert_eq!(
+            outer.exit_edges,
+            BTreeSet::from([(0x20, 0x60), (0x40, 0x60)])
+        );
+        assert_eq!((outer.depth, outer.parent), (1, None));
+        assert_eq!(outer.children, BTreeSet::from([0x30]));
+
+        let inner = &forest.loops[&0x30];
+        assert_eq!(inner.blocks, BTreeSet::from([0x30, 0x40]));
+        assert_eq!(inner.back_edges, BTreeSet::from([(0x40, 0x30)]));
+        assert_eq!(
+            inner.exit_edges,
+            BTreeSet::from([(0x30, 0x50), (0x40, 0x60)])
+        );
+        assert_eq!((inner.depth, inner.parent), (2, Some(0x20)));
+
+        assert_eq!(forest.max_depth(), 2);
+        let post_order = forest
+            .post_order()
+            .iter()
+            .map(|natural_loop| natural_loop.header)
+            .collect::<Vec<_>>();
+        assert_eq!(post_order, [0x30, 0x20]);
+        assert_eq!(forest.child_containing(outer, 0x40), Some(0x30));
+        assert_eq!(forest.child_containing(outer, 0x50), None);
+    }
+
+    #[test]
+    fn loops_with_the_same_header_are_merged() {
+        // two back edges to 0x20, and a self loop at 0x40 not reached from the entry
+        let conditional =
+            |taken, not_taken| Some(ExitJump::ConditionalRelative { taken, not_taken });
+        let graph = graph(&[
+            nop_block(0x10, Some(ExitJump::Next(0x20))),
+            nop_block(0x20, conditional(0x30, 0x28)),
+            nop_block(0x28, Some(ExitJump::UnconditionalRelative(0x20))),
+            nop_block(0x30, conditional(0x20, 0x38)),
+            nop_block(0x38, None),
+            nop_block(0x40, Some(ExitJump::UnconditionalRelative(0x40))),
+        ]);
+        let forest = LoopForest::from_graph(&graph);
+
+        let natural_loop = &forest.loops[&0x20];
+        assert_eq!(natural_loop.blocks, BTreeSet::from([0x20, 0x28, 0x30]));
+        assert_eq!(
+            natural_loop.back_edges,
+            BTreeSet::from([(0x28, 0x20), (0x30, 0x20)])
+        );
+        assert_eq!(natural_loop.exit_edges, BTreeSet::from([(0x30, 0x38)]));
+
+        let self_loop = &forest.loops[&0x40];
+        assert_eq!(self_loop.blocks, BTreeSet::from([0x40]));
+        assert_eq!(self_loop.depth, 1);
+        assert_eq!(forest.roots().len(), 2);
+    }
+}
@@ -1,43 +1,132 @@
 #[macro_use]
-
 mod arch;
+mod batch;
+mod binary;
 mod block;
+mod cache;
 mod cycle;
+mod external;
+mod formula;
 mod graph;
+mod incremental;
+mod infeasible;
 mod instruction;
 mod jump;
+mod loops;
+mod memory;
+mod profile;
+mod simulator;
+mod source;
+#[cfg(test)]
+mod testing;
+mod timing;
+mod trace;
+mod value;
 
 use std::cell::RefCell;
 use std::collections::{hash_map, BTreeMap, HashMap, HashSet};
 use std::io::Write;
+use std::path::Path;
 
-use capstone::{Capstone, NO_EXTRA_MODE};
+use capstone::{Arch, Capstone, NO_EXTRA_MODE};
 use jump::get_exit_jump;
-use object::{Object, ObjectSection};
+use object::Object;
 use petgraph::Direction::Incoming;
 
 use crate::arch::ArchMode;
+use crate::batch::{
+    create_output_file, jobs_from_env, run_batch, start_recording, take_recording, write_output,
+    AnalysisSummary, BatchJob,
+};
+use crate::binary::{Binary, CodeReference, EntrySymbol};
 use crate::block::Block;
-use crate::cycle::condensate_graph;
-use crate::graph::MappedGraph;
+use crate::cache::{analyze_instruction_cache, CacheConfig};
+use crate::cycle::{condensate_graph, split_irreducible_cycles, CondensedFormulas, Latency};
+use crate::external::annotate_external_calls;
+use crate::formula::{parameters_from_env, Formula};
+use crate::graph::{add_cycles, MappedGraph};
+use crate::incremental::{AnalysisCache, CachedAnalysis};
+use crate::infeasible::{find_infeasible_paths, longest_feasible_path};
+use crate::instruction::{Instruction, DEFAULT_LATENCY};
 use crate::jump::ExitJump;
+use crate::loops::LoopForest;
+use crate::memory::{apply_memory_latencies, MemoryMap, MemoryReport};
+use crate::profile::CpuProfile;
+use crate::simulator::simulate;
+use crate::source::{set_source_map, source_suffix, SourceMap};
+use crate::timing::{
+    get_branch_model_from_env, get_timing_model_from_env, set_branch_model, set_timing_model,
+};
+use crate::trace::{read_trace_from_env, MeasuredModel};
+use crate::value::analyze_values;
 
 #[macro_export]
 macro_rules! printwarning {
     ($($arg:tt)*) => {
-        println!("WARNING: {}", format_args!($($arg)*))
+        $crate::printline!("WARNING: {}", format_args!($($arg)*))
     };
 }
 
 thread_local! {
-    static CURRENT_ARCH: RefCell<Option<ArchMode>> = RefCell::new(None);
+    static CURRENT_ARCH: RefCell<Option<ArchMode>> = const { RefCell::new(None) };
 }
 
 fn main() {
     dotenv::dotenv().ok(); // load .env file
 
-    let file_bytes = std::fs::read("ricorsiva_all.o").unwrap(); //prova_3ret.o --> 219, prova_d --> 229,  prova_without_cycles.o --> 139, 3cicli.o --> 241, parenthesis.o -> 319
-    let obj_file = object::File::parse(file_bytes.as_slice()).unwrap(); //prova_2for --> 159, ooribile.o --> 230, peggio --> 266, funzioni.o --> 245, funzioni_1ciclo.o --> 252
+    // the profile fills the latencies that the .env file doesn't set
+    let cpu_profile = CpuProfile::from_env();
+    if let Some(cpu_profile) = cpu_profile {
+        cpu_profile.load();
+        println!("CPU profile: {cpu_profile}");
+    }
+
+    // many inputs, or all their functions, on a thread pool
+    if let Some(jobs) = jobs_from_env() {
+        run_batch(jobs, analyze_job);
+        return;
+    }
+
+    let entry_symbol = entry_symbol_from_env();
+    //prova_3ret.o --> 219, prova_d --> 229,  prova_without_cycles.o --> 139, 3cicli.o --> 241, parenthesis.o -> 319
+    //prova_2for --> 159, ooribile.o --> 230, peggio --> 266, funzioni.o --> 245, funzioni_1ciclo.o --> 252
+    analyze(
+        Path::new("ricorsiva_all.o"),
+        entry_symbol.as_ref(),
+        cpu_profile,
+    );
+}
+
+fn analyze_job(job: &BatchJob) -> AnalysisSummary {
+    let entry_symbol = match &job.function {
+        Some(function) => Some(EntrySymbol {
+            name: function.name.clone(),
+            location: Some((function.section, function.address)),
+        }),
+        None => entry_symbol_from_env(),
+    };
+    analyze(&job.input, entry_symbol.as_ref(), CpuProfile::from_env())
+}
+
+fn entry_symbol_from_env() -> Option<EntrySymbol> {
+    std::env::var("ENTRY_SYMBOL").ok().map(|name| EntrySymbol {
+        name,
+        location: None,
+    })
+}
+
+// the analysis of one input from the entry symbol, its state is in the thread locals of the current thread
+fn analyze(
+    input: &Path,
+    entry_symbol: Option<&EntrySymbol>,
+    cpu_profile: Option<&'static CpuProfile>,
+) -> AnalysisSummary {
+    set_timing_model(get_timing_model_from_env());
+    set_branch_model(get_branch_model_from_env());
+
+    let file_bytes = std::fs::read(input)
+        .unwrap_or_else(|_| panic!("Unable to read the input {}", input.display()));
+    let obj_file = object::File::parse(file_bytes.as_slice()).unwrap();
 
     let arch = obj_file.architecture();
     let arch_mode = ArchMode::from(arch);
@@ -45,30 +134,49 @@ fn main() {
         *current_arch.borrow_mut() = Some(arch_mode.clone());
     });
 
-    println!("{arch_mode:?}");
-
-    let mut text_section = Vec::new();
-    for section in obj_file.sections() {
-        // join all the sections .text in one
-        if section.name().unwrap().contains("text") {
-            text_section.extend_from_slice(section.data().unwrap());
-        }
-    }
+    printline!("{arch_mode:?}");
 
     let mut cs = Capstone::new_raw(arch_mode.arch, arch_mode.mode, NO_EXTRA_MODE, None)
         .expect("Failed to create Capstone handle");
     cs.set_detail(true).unwrap();
 
-    let instructions = cs
-        .disasm_all(&text_section, 0x1000)
-        .expect("Failed to disassemble given code");
+    let binary = Binary::new(&obj_file, &file_bytes, &cs);
+    let entry_address = binary.entry_address(&obj_file, entry_symbol);
+
+    // file and line of the instructions for the warnings, the dot labels and the reports
+    let source_map = SourceMap::new(&obj_file, &binary);
+    if let Some(source_map) = &source_map {
+        printline!(
+            "Debug info: {} source lines, {} functions",
+            source_map.lines_len(),
+            source_map.functions_len()
+        );
+    }
+    set_source_map(source_map);
+
+    for section in &binary.code_sections {
+        printline!("Section {} at 0x{:x}", section.name, section.address);
+    }
+
+    let disassembled_sections = binary
+        .code_sections
+        .iter()
+        .map(|section| {
+            cs.disasm_all(&section.data, section.address)
+                .expect("Failed to disassemble given code")
+        })
+        .collect::<Vec<_>>();
+    let instructions = disassembled_sections
+        .iter()
+        .flat_map(|section_instructions| section_instructions.iter())
+        .collect::<Vec<_>>();
 
     //print all the instrcutions in a file
-    let mut file = std::fs::File::create("instructions.txt").unwrap();
+    let mut file = create_output_file("instructions.txt");
 
     for instruction in instructions.iter() {
         let insn_detail = cs.insn_detail(instruction).unwrap();
-        let exit_jump = get_exit_jump(instruction, &instructions[0], &insn_detail, arch_mode.arch);
+        let exit_jump = get_exit_jump(instruction, instructions[0], &insn_detail, arch_mode.arch);
         writeln!(
             file,
             "{:x} {:?} {:?} {:?}",
@@ -86,6 +194,18 @@ fn main() {
     let mut duplicated = HashMap::<(u64, u64), (u64, u64)>::new(); // (call_target_address, call_insn_address) -> (fictious address, return_address)
     let mut counter = 0;
     let mut vacant_ret = Vec::<u64>::new();
+    let mut external_calls = HashMap::<u64, String>::new(); // call_insn_address -> external symbol
+
+    // every code section (also the ones joined in a relocatable object) and the analysis entry start a new block
+    let section_starts = binary
+        .section_addresses
+        .values()
+        .copied()
+        .collect::<HashSet<_>>();
+    leaders.extend(&section_starts);
+    if let Some(entry_address) = entry_address {
+        leaders.insert(entry_address);
+    }
 
     // iteration to find all leaders and exit jumps
     instructions.windows(2).for_each(|window| {
@@ -94,7 +214,37 @@ fn main() {
 
         let insn_detail = cs.insn_detail(instruction).unwrap();
 
-        let exit_jump = get_exit_jump(instruction, next_instruction, &insn_detail, arch_mode.arch);
+        let exit_jump =
+            match get_exit_jump(instruction, next_instruction, &insn_detail, arch_mode.arch) {
+                Some(ExitJump::Call(target, return_address)) => {
+                    match binary.resolve_call(instruction.address(), instruction.len(), target) {
+                        CodeReference::Internal(target) => {
+                            Some(ExitJump::Call(target, return_address))
+                        }
+                        // a call to an external function has no blocks, its latency is added to the call instruction
+                        CodeReference::External(symbol) => {
+                            external_calls.insert(instruction.address(), symbol);
+                            None
+                        }
+                    }
+                }
+                // the jalr of a RISC-V call has no target until its relocation is resolved
+                Some(ExitJump::Indirect)
+                    if arch_mode.arch == Arch::RISCV
+                        && binary.relocations.contains_key(&instruction.address()) =>
+                {
+                    match &binary.relocations[&instruction.address()] {
+                        CodeReference::Internal(target) => {
+                            Some(ExitJump::Call(*target, next_instruction.address()))
+                        }
+                        CodeReference::External(symbol) => {
+                            external_calls.insert(instruction.address(), symbol.clone());
+                            None
+                        }
+                    }
+                }
+                exit_jump => exit_jump,
+            };
 
         // if the instruction is a jump, add the jump target address and the next instruction address to the leaders
         // Then add the jump instruction to the jumps map
@@ -141,6 +291,9 @@ fn main() {
                     }
                 }
                 ExitJump::Ret(_) => {}
+                ExitJump::ConditionalRet { .. } => {
+                    // not taken is the next instruction, so it is already inserted
+                }
                 ExitJump::Next(_) => {}
             }
         }
@@ -148,12 +301,10 @@ fn main() {
 
     // iterate through all instructions and create the basic blocks
     let first_instruction = instructions.first().unwrap();
-    let mut current_block: Block = Block::new(first_instruction.into());
+    let mut current_block: Block = Block::new(Instruction::new(first_instruction, &cs));
     // we need to keep the order of the blocks to have a consistent entry point of a condensed node
     let mut blocks = BTreeMap::<u64, Block>::new();
 
-    let mut graph = MappedGraph::new();
-
     // for each window of 2 instructions
     instructions
         .windows(2)
@@ -164,18 +315,26 @@ fn main() {
 
             // if the next instruction is a leader, push the current block to the list of blocks
             if leaders.contains(&next_insn.address()) {
+                // the first block of a called function may also end without a jump
+                if call_map.contains_key(&current_block.leader) {
+                    vacant_ret.push(current_block.leader);
+                }
                 if let Some(exit_jump) = jumps.get(&insn.address()) {
-                    if call_map.contains_key(&current_block.leader) {
-                        vacant_ret.push(current_block.leader);
-                    }
-                    if let ExitJump::Ret(_) = exit_jump {
-                        if let Some(targets) = call_map.get(&current_block.leader) {
-                            vacant_ret.pop().unwrap();
-                            current_block.set_exit_jump(ExitJump::Ret(*targets));
-                        } else if !vacant_ret.is_empty() {
-                            if let Some(ret) = call_map.get(&vacant_ret.pop().unwrap()) {
-                                current_block.set_exit_jump(ExitJump::Ret(*ret));
-                            }
+                    if exit_jump.is_ret() {
+                        // a conditional return does not close the function, its not taken path goes on
+                        let is_conditional = matches!(exit_jump, ExitJump::ConditionalRet { .. });
+                        let function_entry = if is_conditional {
+                            vacant_ret.last().copied()
+                        } else {
+                            vacant_ret.pop()
+                        };
+
+                        let mut exit_jump = exit_jump.clone();
+                        if let Some(ret) = function_entry.and_then(|entry| call_map.get(&entry)) {
+                            exit_jump.set_ret_target(*ret);
+                            current_block.set_exit_jump(exit_jump);
+                        } else if is_conditional {
+                            current_block.set_exit_jump(exit_jump);
                         }
                     } else if let ExitJump::Call(target, _) = exit_jump {
                         if let Some((fictious_address, return_address)) =
@@ -189,25 +348,80 @@ fn main() {
                     } else {
                         current_block.set_exit_jump(exit_jump.clone());
                     }
-                } else {
+                } else if !section_starts.contains(&next_insn.address()) {
+                    // the last instruction of a section doesn't fall through to the next one
                     current_block.set_exit_jump(ExitJump::Next(next_insn.address()));
                 }
 
                 // insert the current block to the list of blocks
                 blocks.insert(current_block.leader, current_block.clone());
-                current_block = Block::new(next_insn.into());
+                current_block = Block::new(Instruction::new(next_insn, &cs));
             } else {
                 // push the instruction to the current block
-                current_block.add_instruction(next_insn.into());
+                current_block.add_instruction(Instruction::new(next_insn, &cs));
             }
 
-            // last instruction pair -> add last instruction to block and push block (exit_jump is None)
+            // last instruction pair -> the last instruction is already in the block, push block (exit_jump is None)
             if index == instructions.len() - 2 {
-                current_block.add_instruction(next_insn.into());
                 blocks.insert(current_block.leader, current_block.clone());
             }
         });
 
+    let external_call_report =
+        annotate_external_calls(&mut blocks, &external_calls, arch_mode.arch);
+
+    if let Some(cpu_profile) = cpu_profile {
+        if std::env::var("CPU_PROFILE_REPORT").is_ok_and(|value| value == "true") {
+            let block_instructions = blocks
+                .values()
+                .flat_map(|block| block.instructions.clone())
+                .collect::<Vec<_>>();
+            for (mnemonic, addresses) in cpu_profile.missing_mnemonics(&block_instructions) {
+                printline!(
+                    "Mnemonic {mnemonic} missing from the CPU profile {cpu_profile}: {} instructions, first at 0x{:x}",
+                    addresses.len(),
+                    addresses.first().unwrap()
+                );
+            }
+        }
+    }
+
+    // the execution of a function on the given inputs is a lower bound of the WCET
+    let simulation = std::env::var("SIMULATE_FUNCTION").ok().map(|function| {
+        let address = match function.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => binary.symbol_address(&obj_file, &function),
+        };
+        let Some(address) = address else {
+            panic!(
+                "The function {function} set in SIMULATE_FUNCTION is not defined in a code section"
+            );
+        };
+        simulate(
+            address,
+            &blocks,
+            &binary.code_sections,
+            &external_calls,
+            &arch_mode,
+        )
+    });
+    if let Some(simulation) = &simulation {
+        write_output(&simulation.to_string());
+    }
+
+    // the blocks measured in the trace keep their observed clock cycles (hybrid analysis)
+    let mut hybrid = false;
+    if let Some(trace_report) = read_trace_from_env(&blocks) {
+        write_output(&trace_report.to_string());
+        if !trace_report.block_cycles.is_empty() {
+            set_timing_model(Box::new(MeasuredModel {
+                block_cycles: trace_report.block_cycles.into_iter().collect(),
+                fallback: get_timing_model_from_env(),
+            }));
+            hybrid = true;
+        }
+    }
+
     let mut recursive_functions = HashMap::<u64, u64>::new();
     let mut fictious_map = HashMap::<u64, u64>::new(); // real_address -> fictious address
 
@@ -237,39 +451,138 @@ fn main() {
         }
     }
 
-    // add edges to the graph (it also adds the nodes)
-    for block in blocks.values() {
-        for target in block.get_targets() {
-            if let Some(target_block) = blocks.get(&target) {
-                graph.add_edge(
-                    block.clone(),
-                    target_block.clone(),
-                    target_block.get_latency() as f32,
-                );
+    // keep only the code reachable from the analysis entry
+    if let Some(entry_address) = entry_address {
+        retain_reachable_blocks(&mut blocks, entry_address);
+    }
+
+    // only the analyzed code, STRICT_LATENCIES doesn't fail on the code not reachable from the entry
+    report_default_latencies(&blocks);
+
+    // an unchanged function, with unchanged callees and flow facts, keeps the results of its last analysis
+    let analysis_cache = AnalysisCache::from_env().map(|cache| {
+        let key = cache.key(&blocks, &external_calls, &binary.relocations);
+        (cache, key)
+    });
+    if let Some((cache, key)) = &analysis_cache {
+        if let Some(CachedAnalysis {
+            summary,
+            diagnostics,
+            files,
+        }) = cache.load(*key)
+        {
+            printline!(
+                "Analysis cache: the code and the flow facts are unchanged -> results of {}",
+                cache.display_path(*key)
+            );
+            // the warnings, errors and debug files of the stored analysis, the ones before the lookup
+            // are written in every run
+            for diagnostic in diagnostics {
+                printline!("{diagnostic}");
+            }
+            cache.restore_files(*key, &files);
+            if let Some(formula) = &summary.formula {
+                printline!("WCET formula: {formula} clock cycles");
+            }
+            match summary.wcet {
+                Some(wcet) if summary.hybrid => printline!("Hybrid WCET: {wcet} clock cycles"),
+                Some(wcet) => printline!("WCET: {wcet} clock cycles"),
+                None => printline!("WCET: set the env vars PARAM_ of the formula for a value"),
+            }
+            if let Some(bcet) = summary.bcet {
+                printline!("BCET: {bcet} clock cycles");
             }
+            return summary;
+        }
+        start_recording();
+    }
+
+    // a cycle entered at more than one block is copied for every entry
+    split_irreducible_cycles(&mut blocks, entry_address, &mut fictious_map);
+
+    // add edges to the graph (it also adds the nodes)
+    let mut graph = MappedGraph::from_blocks(&blocks);
+
+    // values of the registers and stack slots before every instruction
+    let value_analysis = analyze_values(&graph, &arch_mode);
+    let mut values_file = create_output_file("values.txt");
+    write!(values_file, "{value_analysis}").expect("Unable to write values file");
+
+    // the instruction cache misses are added to the latencies of the instructions, so the graph is rebuilt
+    let mut first_miss_penalty = 0;
+    if let Some(icache_config) = CacheConfig::from_env("ICACHE") {
+        let icache_analysis = analyze_instruction_cache(&graph, &icache_config);
+        icache_analysis.apply(&mut blocks, &icache_config);
+        first_miss_penalty = icache_analysis.first_miss_penalty(&icache_config);
+
+        let mut icache_file = create_output_file("icache.txt");
+        write!(icache_file, "{icache_analysis}").expect("Unable to write icache file");
+
+        graph = MappedGraph::from_blocks(&blocks);
+    }
+
+    // the same for the latencies of the data memory accesses
+    let mut memory_report = MemoryReport::default();
+    if let Some(memory_map) = MemoryMap::from_env() {
+        let dcache_config = CacheConfig::from_env("DCACHE");
+        memory_report = apply_memory_latencies(
+            &mut blocks,
+            &graph,
+            &memory_map,
+            dcache_config.as_ref(),
+            &value_analysis,
+        );
+
+        if let Some(dcache_analysis) = &memory_report.dcache_analysis {
+            let mut dcache_file = create_output_file("dcache.txt");
+            write!(dcache_file, "{dcache_analysis}").expect("Unable to write dcache file");
         }
+
+        graph = MappedGraph::from_blocks(&blocks);
     }
 
-    let mut dot_file = std::fs::File::create("graph.dot").expect("Unable to create file");
+    let mut dot_file = create_output_file("graph.dot");
     let digraph = graph.to_dot_graph();
     dot_file
         .write_all(digraph.as_bytes())
         .expect("Unable to write dot file");
 
-    let mut condensed_entry_node_latency = HashMap::<u64, u32>::new(); // block_leader -> latency
-    let mut latency_map = HashMap::<u64, u32>::new(); // ret_address -> latency
+    // the paths that no execution takes are excluded from the longest path
+    let infeasible_paths = find_infeasible_paths(&graph, &value_analysis);
+    let mut infeasible_file = create_output_file("infeasible.txt");
+    for infeasible_path in &infeasible_paths {
+        writeln!(infeasible_file, "{infeasible_path}").expect("Unable to write infeasible file");
+    }
+
+    // loop nesting forest from the dominators, the wcet is computed bottom-up over it
+    let loop_forest = LoopForest::from_graph(&graph);
+    let mut loops_file = create_output_file("loops.txt");
+    write!(loops_file, "{loop_forest}").expect("Unable to write loops file");
+    if !loop_forest.loops.is_empty() {
+        printline!(
+            "Loops: {} (loops.txt), maximum nesting depth {}",
+            loop_forest.loops.len(),
+            loop_forest.max_depth()
+        );
+    }
+
+    let mut condensed_entry_node_latency = HashMap::<u64, u64>::new(); // block_leader -> latency
+    let mut latency_map = HashMap::<u64, Latency>::new(); // ret_address -> latency
+    let mut condensed_formulas = CondensedFormulas::default();
 
     // condense the graph
     let condensed_graph = condensate_graph(
         graph.clone(),
+        &loop_forest,
         &mut condensed_entry_node_latency,
+        &mut condensed_formulas,
         &blocks,
         &recursive_functions,
         &mut latency_map,
         &mut fictious_map,
     );
 
-    let mut dot_file = std::fs::File::create("condensed_graph.dot").expect("Unable to create file");
+    let mut dot_file = create_output_file("condensed_graph.dot");
     let digraph = condensed_graph.to_dot_graph();
     dot_file
         .write_all(digraph.as_bytes())
@@ -282,31 +595,142 @@ fn main() {
         .filter(|node| condensed_graph.edges_directed(node, Incoming).is_empty())
         .collect::<Vec<_>>();
 
-    let mut wcet: u32 = 0;
-    let mut recursive_delay: u32 = 0;
+    let mut wcet: u64 = 0;
+    let mut infeasible_wcet: u64 = 0; // with the infeasible paths
+    let mut bcet: Option<u64> = None;
+    let mut recursive_delay: u64 = 0;
+    // the wcet as a formula of the parameters of the loop bounds, the infeasible paths are not removed from it
+    let parameters = parameters_from_env();
+    let mut wcet_formula = Formula::zero();
+    let mut recursive_delay_formula = Formula::zero();
+    // the longest paths from all the entry nodes at once
+    let longest_path_formulas = condensed_graph.longest_path_formulas(&condensed_formulas.edges);
     for entry_node in entry_nodes.clone() {
         let entry_node_latency = match condensed_entry_node_latency.get(&entry_node[0].leader) {
             Some(latency) => *latency,
-            None => entry_node[0].get_latency(),
+            None => u64::from(entry_node[0].get_latency()),
         };
 
-        let max_path_latency = condensed_graph.longest_path(entry_node).unwrap() as u32;
-        println!("Entry node latency: {entry_node_latency}");
+        let max_path_latency = condensed_graph.longest_path(entry_node).unwrap();
+        let max_feasible_path_latency =
+            longest_feasible_path(&condensed_graph, entry_node, &infeasible_paths);
+        if max_feasible_path_latency.is_none() {
+            printwarning!(
+                "Every path from the entry node 0x{:x}{} is infeasible -> the infeasible paths are not \
+                considered for the wcet calculation",
+                entry_node[0].leader,
+                source_suffix(entry_node[0].leader)
+            );
+        }
+        let max_feasible_path_latency = max_feasible_path_latency.unwrap_or(max_path_latency);
+        printline!("Entry node latency: {entry_node_latency}");
 
         if let Some(ret_address) = recursive_functions.get(&entry_node[0].leader) {
-            recursive_delay += *latency_map.get(ret_address).unwrap();
+            let delay = latency_map.get(ret_address).unwrap();
+            recursive_delay = add_cycles(recursive_delay, delay.cycles);
+            recursive_delay_formula = recursive_delay_formula.add(&delay.formula);
         } else {
             //calculating the wcet only if the entry node is not a recursive function
-            wcet = wcet.max(entry_node_latency + max_path_latency);
+            wcet = wcet.max(add_cycles(entry_node_latency, max_feasible_path_latency));
+            infeasible_wcet = infeasible_wcet.max(add_cycles(entry_node_latency, max_path_latency));
+
+            let entry_node_formula = match condensed_formulas.entries.get(&entry_node[0].leader) {
+                Some(formula) => formula.clone(),
+                None => Formula::constant(entry_node_latency as f64),
+            };
+            wcet_formula = wcet_formula
+                .max(&entry_node_formula.add(&longest_path_formulas[&entry_node[0].leader]));
+
+            if let Some(min_path_latency) = graph.best_case_path(&entry_node[0]) {
+                bcet = Some(bcet.map_or(min_path_latency, |bcet| bcet.min(min_path_latency)));
+            }
+        }
+    }
+
+    wcet = add_cycles(wcet, recursive_delay);
+    wcet_formula = wcet_formula.add(&recursive_delay_formula);
+
+    if !infeasible_paths.is_empty() {
+        printline!(
+            "Infeasible paths: {} (infeasible.txt), {} clock cycles removed from the wcet",
+            infeasible_paths.len(),
+            infeasible_wcet.saturating_sub(wcet)
+        );
+    }
+
+    if first_miss_penalty > 0 {
+        printline!("Instruction cache first misses: {first_miss_penalty} clock cycles");
+        wcet = add_cycles(wcet, u64::from(first_miss_penalty));
+        wcet_formula = wcet_formula.add(&Formula::constant(first_miss_penalty as f64));
+    }
+
+    if memory_report.first_miss_penalty > 0 {
+        printline!(
+            "Data cache first misses: {} clock cycles",
+            memory_report.first_miss_penalty
+        );
+        wcet = add_cycles(wcet, u64::from(memory_report.first_miss_penalty));
+        wcet_formula =
+            wcet_formula.add(&Formula::constant(memory_report.first_miss_penalty as f64));
+    }
+
+    write_output(&memory_report.to_string());
+    write_output(&external_call_report.to_string());
+
+    // with symbolic loop bounds the value needs all the parameters
+    let missing_parameters = wcet_formula
+        .parameters()
+        .into_iter()
+        .filter(|name| !parameters.contains_key(name))
+        .map(|name| format!("PARAM_{name}"))
+        .collect::<Vec<_>>();
+    if !wcet_formula.parameters().is_empty() {
+        if infeasible_paths.is_empty() {
+            printline!("WCET formula: {wcet_formula} clock cycles");
+        } else {
+            printline!(
+                "WCET formula: {wcet_formula} clock cycles (the infeasible paths are not removed from the formula)"
+            );
         }
     }
 
-    wcet += recursive_delay;
+    if let Some(simulation) = &simulation {
+        if missing_parameters.is_empty() && simulation.returned && simulation.cycles > wcet {
+            printline!(
+                "ERROR: The simulation takes {} clock cycles, more than the WCET -> the analysis is unsound",
+                simulation.cycles
+            );
+        }
+    }
 
-    println!("WCET: {wcet} clock cycles");
+    if !missing_parameters.is_empty() {
+        printline!(
+            "WCET: set the env vars {} for a value",
+            missing_parameters.join(", ")
+        );
+    } else if hybrid {
+        printline!("Hybrid WCET: {wcet} clock cycles");
+    } else {
+        printline!("WCET: {wcet} clock cycles");
+    }
+    if let Some(bcet) = bcet {
+        printline!("BCET: {bcet} clock cycles");
+    }
 
+    let summary = AnalysisSummary {
+        wcet: missing_parameters.is_empty().then_some(wcet),
+        bcet,
+        formula: (!wcet_formula.parameters().is_empty()).then(|| wcet_formula.to_string()),
+        hybrid,
+        cached: false,
+    };
+    if let Some((cache, key)) = &analysis_cache {
+        cache.store(*key, &summary, &take_recording());
+    }
+    summary
 }
 
+#[allow(clippy::too_many_arguments)]
 fn duplicate(
     blocks: &mut BTreeMap<u64, Block>,
     source: &mut Block,
@@ -320,10 +744,18 @@ fn duplicate(
     visited_nodes.insert(source.leader, fictious_address);
     fictious_map.insert(fictious_address, source.leader);
     let source_fictious_address = fictious_address;
-    let mut fictious_address = fictious_address << 1 + 1;
+    let first_fictious_address = fictious_address << (1 + 1);
+
+    // the return of a conditional return goes back to the caller, only its not taken path is duplicated
+    let ret_target = source.exit_jump.as_ref().and_then(ExitJump::ret_target);
 
     //duplicate and add to blocks all targets of the source block until a return is found
-    for target in source.get_targets() {
+    for (fictious_address, target) in (first_fictious_address..).zip(
+        source
+            .get_targets()
+            .into_iter()
+            .filter(|target| Some(*target) != ret_target),
+    ) {
         if let Some(target_block) = blocks.clone().get(&target) {
             //to modify one target of the source block with the new fictious address of the duplicated target block
             source.modify_targets(fictious_address, target);
@@ -350,6 +782,9 @@ fn duplicate(
                     } //else {
                     new_block.leader = fictious_address;
                     new_block.modify_targets(*visited_nodes.get(x).unwrap(), *x);
+                    if let Some(exit_jump) = new_block.exit_jump.as_mut() {
+                        exit_jump.set_ret_target(ret_address);
+                    }
                     blocks.insert(new_block.leader, new_block.clone());
                     //  }
                 } else {
@@ -366,9 +801,78 @@ fn duplicate(
                 }
             }
         }
-
-        fictious_address = fictious_address + 1;
+    }
+    if let Some(exit_jump) = source.exit_jump.as_mut() {
+        exit_jump.set_ret_target(ret_address);
     }
     source.leader = source_fictious_address;
     blocks.insert(source.leader, source.clone());
 }
+
+// warn about the mnemonics without a latency rule, or stop the analysis if STRICT_LATENCIES=true
+fn report_default_latencies(blocks: &BTreeMap<u64, Block>) {
+    let mut default_latencies = BTreeMap::<String, Vec<&Instruction>>::new(); // mnemonic -> instructions
+    let mut reported = HashSet::new();
+    for instruction in blocks.values().flat_map(|block| block.instructions.iter()) {
+        // the duplicated blocks keep the addresses of their instructions
+        if instruction.default_latency && reported.insert(instruction.address) {
+            default_latencies
+                .entry(instruction.mnemonic.clone())
+                .or_default()
+                .push(instruction);
+        }
+    }
+
+    let strict = std::env::var("STRICT_LATENCIES").is_ok_and(|value| value == "true");
+
+    for (mnemonic, instructions) in &default_latencies {
+        let examples = instructions
+            .iter()
+            .take(3)
+            .map(|instruction| {
+                format!(
+                    "0x{:x}{}",
+                    instruction.address,
+                    source_suffix(instruction.address)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mnemonic_instructions = format!(
+            "the mnemonic {mnemonic} ({} instructions: {examples}{})",
+            instructions.len(),
+            if instructions.len() > 3 { ", ..." } else { "" }
+        );
+        let env_var_key = instructions[0].arch_mnemonic_str();
+        if strict {
+            printline!("ERROR: No latency for {mnemonic_instructions}, please set the env var {env_var_key}");
+        } else {
+            printwarning!(
+                "No latency for {mnemonic_instructions} -> {DEFAULT_LATENCY} clock cycle considered for the wcet calculation. \
+                If you want to change the value, please set the env var {env_var_key}"
+            );
+        }
+    }
+
+    if strict && !default_latencies.is_empty() {
+        panic!(
+            "{} mnemonics without latency with STRICT_LATENCIES=true",
+            default_latencies.len()
+        );
+    }
+}
+
+fn retain_reachable_blocks(blocks: &mut BTreeMap<u64, Block>, entry_address: u64) {
+    let mut reachable = HashSet::new();
+    let mut to_visit = vec![entry_address];
+
+    while let Some(address) = to_visit.pop() {
+        if let Some(block) = blocks.get(&address) {
+            if reachable.insert(address) {
+                to_visit.extend(block.get_targets());
+            }
+        }
+    }
+
+    blocks.retain(|leader, _| reachable.contains(leader));
+}
@@ -0,0 +1,468 @@
+use std::collections::BTreeMap;
+
+use crate::block::Block;
+use crate::cache::{
+    analyze_cache, first_miss_penalty, CacheAnalysis, CacheClassification, CacheConfig,
+};
+use crate::graph::MappedGraph;
+use crate::instruction::{Instruction, Operand};
+use crate::timing::add_latency;
+use crate::value::{Value, ValueAnalysis};
+
+// env variable format: MEMORY_REGION_NAME=start-end:read_latency:write_latency[:cached]
+// e.g. MEMORY_REGION_FLASH=0x08000000-0x08100000:5:5:cached
+// MEMORY_STACK=NAME is the region that contains the stack
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub name: String,
+    pub start: u64,
+    pub end: u64, // exclusive
+    pub read_latency: u32,
+    pub write_latency: u32,
+    pub cached: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryAccessKind {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessedAddress {
+    Exact(u64),
+    Range(u64, u64), // inclusive
+    Stack,
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub instruction_address: u64,
+    pub kind: MemoryAccessKind,
+    pub address: AccessedAddress,
+}
+
+#[derive(Debug, Clone)]
+pub struct MemoryMap {
+    pub regions: Vec<MemoryRegion>,
+    pub stack_region: Option<usize>,
+}
+
+impl MemoryMap {
+    pub fn from_env() -> Option<Self> {
+        let mut regions = std::env::vars()
+            .filter_map(|(key, value)| {
+                let name = key.strip_prefix("MEMORY_REGION_")?;
+                Some(parse_region(name, &key, &value))
+            })
+            .collect::<Vec<_>>();
+
+        if regions.is_empty() {
+            return None;
+        }
+        regions.sort_by_key(|region| region.start);
+
+        let stack_region = match std::env::var("MEMORY_STACK") {
+            Ok(name) => match regions.iter().position(|region| region.name == name) {
+                Some(index) => Some(index),
+                None => panic!("The stack region {name} set in MEMORY_STACK is not defined"),
+            },
+            Err(_) => None,
+        };
+
+        Some(MemoryMap {
+            regions,
+            stack_region,
+        })
+    }
+
+    // the regions the access may touch, all of them if the address is unknown
+    pub fn get_regions(&self, address: AccessedAddress) -> Vec<&MemoryRegion> {
+        let region = match address {
+            AccessedAddress::Exact(address) => self
+                .regions
+                .iter()
+                .find(|region| address >= region.start && address < region.end),
+            AccessedAddress::Range(start, end) => self
+                .regions
+                .iter()
+                .find(|region| start >= region.start && end < region.end),
+            AccessedAddress::Stack => self.stack_region.map(|index| &self.regions[index]),
+            AccessedAddress::Unknown => None,
+        };
+
+        match region {
+            Some(region) => vec![region],
+            None => self.regions.iter().collect(),
+        }
+    }
+
+    // the worst latency of the access among the regions it may touch
+    pub fn get_latency(&self, access: &MemoryAccess) -> u32 {
+        self.get_regions(access.address)
+            .iter()
+            .map(|region| match access.kind {
+                MemoryAccessKind::Read => region.read_latency,
+                MemoryAccessKind::Write => region.write_latency,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+fn parse_region(name: &str, env_var_key: &str, value: &str) -> MemoryRegion {
+    let invalid = || -> ! {
+        panic!("The environment variable {env_var_key} is not a valid memory region, use start-end:read_latency:write_latency[:cached]")
+    };
+    let parse_number = |number: &str| {
+        let number = number.trim();
+        match number.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).unwrap_or_else(|_| invalid()),
+            None => number.parse::<u64>().unwrap_or_else(|_| invalid()),
+        }
+    };
+
+    let fields = value.split(':').collect::<Vec<_>>();
+    if fields.len() < 3 || fields.len() > 4 {
+        invalid();
+    }
+    let Some((start, end)) = fields[0].split_once('-') else {
+        invalid();
+    };
+
+    MemoryRegion {
+        name: name.to_string(),
+        start: parse_number(start),
+        end: parse_number(end),
+        read_latency: u32::try_from(parse_number(fields[1])).unwrap_or_else(|_| invalid()),
+        write_latency: u32::try_from(parse_number(fields[2])).unwrap_or_else(|_| invalid()),
+        cached: match fields.get(3) {
+            Some(&"cached") => true,
+            Some(_) => invalid(),
+            None => false,
+        },
+    }
+}
+
+// only the stack pointer itself: a frame pointer register can hold any address when frame pointers are omitted
+const STACK_POINTERS: &[&str] = &["rsp", "esp", "sp", "wsp"];
+
+// data memory accesses of the block, with the addresses known from the value analysis
+pub fn get_memory_accesses(block: &Block, values: &ValueAnalysis) -> Vec<MemoryAccess> {
+    let mut accesses = Vec::new();
+
+    for instruction in &block.instructions {
+        for (kind, operand) in get_memory_operands(instruction) {
+            let address = match operand {
+                Some(operand @ Operand::Mem { base, .. }) => {
+                    match values.address_before(block.leader, instruction, operand) {
+                        Some(Value::Number(interval)) if interval.as_constant().is_some() => {
+                            AccessedAddress::Exact(interval.min as u64)
+                        }
+                        Some(Value::Number(interval))
+                            if interval.is_bounded() && interval.min >= 0 =>
+                        {
+                            AccessedAddress::Range(interval.min as u64, interval.max as u64)
+                        }
+                        Some(Value::StackAddress(_)) => AccessedAddress::Stack,
+                        _ if base
+                            .as_deref()
+                            .is_some_and(|base| STACK_POINTERS.contains(&base)) =>
+                        {
+                            AccessedAddress::Stack
+                        }
+                        _ => AccessedAddress::Unknown,
+                    }
+                }
+                // implicit stack accesses (push, pop, call, ret)
+                _ => AccessedAddress::Stack,
+            };
+
+            accesses.push(MemoryAccess {
+                instruction_address: instruction.address,
+                kind,
+                address,
+            });
+        }
+    }
+
+    accesses
+}
+
+// memory operands read or written by the instruction, None for implicit stack accesses
+pub fn get_memory_operands(instruction: &Instruction) -> Vec<(MemoryAccessKind, Option<&Operand>)> {
+    let mnemonic = instruction.mnemonic.trim_start_matches("c.");
+
+    let mut memory_operands = Vec::new();
+    match mnemonic {
+        m if m.starts_with("push") || m.starts_with("call") => {
+            memory_operands.push((MemoryAccessKind::Write, None))
+        }
+        m if m.starts_with("pop") || m.starts_with("ret") => {
+            memory_operands.push((MemoryAccessKind::Read, None))
+        }
+        _ => {}
+    }
+
+    if mnemonic.starts_with("lea")
+        || mnemonic.starts_with("nop")
+        || mnemonic.starts_with("prefetch")
+    {
+        return memory_operands;
+    }
+
+    let is_store = mnemonic.starts_with("st") || matches!(mnemonic, "sb" | "sh" | "sw" | "sd");
+
+    for (i, operand) in instruction.operands.iter().enumerate() {
+        if !matches!(operand, Operand::Mem { .. }) {
+            continue;
+        }
+        let is_destination = i == 0 && instruction.operands.len() > 1;
+        if is_store || is_destination && mnemonic.starts_with("mov") || mnemonic.starts_with("set")
+        {
+            memory_operands.push((MemoryAccessKind::Write, Some(operand)));
+        } else if is_destination && !matches!(mnemonic, "cmp" | "test" | "bt")
+            || matches!(mnemonic, "inc" | "dec" | "neg" | "not")
+        {
+            // x86 read-modify-write of the destination (e.g. add dword ptr [rbp - 4], 1)
+            memory_operands.push((MemoryAccessKind::Read, Some(operand)));
+            memory_operands.push((MemoryAccessKind::Write, Some(operand)));
+        } else {
+            memory_operands.push((MemoryAccessKind::Read, Some(operand)));
+        }
+    }
+
+    memory_operands
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MemoryReport {
+    pub unknown_accesses: Vec<u64>, // instruction addresses
+    pub dcache_analysis: Option<CacheAnalysis>,
+    pub first_miss_penalty: u32,
+}
+
+// add the latency of the memory accesses to the instructions, using the data cache if there is one
+pub fn apply_memory_latencies(
+    blocks: &mut BTreeMap<u64, Block>,
+    graph: &MappedGraph,
+    memory_map: &MemoryMap,
+    dcache_config: Option<&CacheConfig>,
+    values: &ValueAnalysis,
+) -> MemoryReport {
+    let mut report = MemoryReport::default();
+
+    // only the reads of cached regions go through the data cache (write-through, no write allocate)
+    let cached_line = |access: &MemoryAccess, config: &CacheConfig| -> Option<Option<u64>> {
+        if access.kind != MemoryAccessKind::Read {
+            return None;
+        }
+        let regions = memory_map.get_regions(access.address);
+        if !regions.iter().any(|region| region.cached) {
+            return None;
+        }
+        match access.address {
+            AccessedAddress::Exact(address) => Some(Some(config.line(address))),
+            _ => Some(None),
+        }
+    };
+
+    let dcache_analysis = dcache_config.map(|config| {
+        let accesses = |block: &Block| {
+            get_memory_accesses(block, values)
+                .iter()
+                .filter_map(|access| {
+                    cached_line(access, config).map(|line| (line, access.instruction_address))
+                })
+                .collect::<Vec<_>>()
+        };
+        analyze_cache(graph, config, &accesses)
+    });
+
+    for block in blocks.values_mut() {
+        let leader = block.leader;
+        for access in get_memory_accesses(block, values) {
+            if access.address == AccessedAddress::Unknown {
+                report.unknown_accesses.push(access.instruction_address);
+            }
+
+            let mut latency = memory_map.get_latency(&access);
+
+            if let (Some(config), Some(dcache_analysis)) = (dcache_config, &dcache_analysis) {
+                if cached_line(&access, config).is_some() {
+                    let classification = dcache_analysis
+                        .classifications
+                        .get(&(leader, access.instruction_address))
+                        .copied()
+                        .unwrap_or(CacheClassification::NotClassified);
+                    latency = match classification {
+                        CacheClassification::AlwaysHit | CacheClassification::FirstMiss => 0,
+                        CacheClassification::AlwaysMiss | CacheClassification::NotClassified => {
+                            add_latency(latency, config.miss_penalty)
+                        }
+                    };
+                }
+            }
+
+            if let Some(instruction) = block
+                .instructions
+                .iter_mut()
+                .find(|i| i.address == access.instruction_address)
+            {
+                instruction.add_latency(latency);
+            }
+        }
+    }
+
+    // every first-miss line is loaded at most once, from the slowest cached region
+    if let (Some(config), Some(dcache_analysis)) = (dcache_config, &dcache_analysis) {
+        let worst_cached_latency = memory_map
+            .regions
+            .iter()
+            .filter(|region| region.cached)
+            .map(|region| region.read_latency)
+            .max()
+            .unwrap_or(0);
+        report.first_miss_penalty = first_miss_penalty(
+            dcache_analysis.first_miss_lines.len(),
+            add_latency(worst_cached_latency, config.miss_penalty),
+        );
+    }
+
+    report.unknown_accesses.sort();
+    report.unknown_accesses.dedup();
+    report.dcache_analysis = dcache_analysis;
+
+    report
+}
+
+impl std::fmt::Display for MemoryReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if !self.unknown_accesses.is_empty() {
+            let addresses = self
+                .unknown_accesses
+                .iter()
+                .map(|address| format!("0x{address:x}"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            writeln!(
+                f,
+                "WARNING: Unknown memory region for the accesses at {addresses} -> the slowest region is considered for the wcet calculation"
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{block, disassemble, graph, x86_64};
+    use crate::value::analyze_values;
+
+    #[test]
+    fn parse_regions() {
+        let region = parse_region("FLASH", "MEMORY_REGION_FLASH", "0x2000-0x3000:5:6:cached");
+        assert_eq!(
+            region,
+            MemoryRegion {
+                name: "FLASH".to_string(),
+                start: 0x2000,
+                end: 0x3000,
+                read_latency: 5,
+                write_latency: 6,
+                cached: true,
+            }
+        );
+        assert!(!parse_region("RAM", "MEMORY_REGION_RAM", "4096-8192:2:3").cached);
+        assert!(std::panic::catch_unwind(|| parse_region("RAM", "RAM", "0x0-0x10:2")).is_err());
+        assert!(std::panic::catch_unwind(|| parse_region("RAM", "RAM", "0x0:2:3")).is_err());
+    }
+
+    #[test]
+    fn latencies_of_the_accesses_by_region_and_data_cache() {
+        let region = |name: &str, start, end, read_latency, write_latency, cached| MemoryRegion {
+            name: name.to_string(),
+            start,
+            end,
+            read_latency,
+            write_latency,
+            cached,
+        };
+        let memory_map = MemoryMap {
+            regions: vec![
+                region("FLASH", 0x2000, 0x3000, 5, 5, true),
+                region("RAM", 0x3000, 0x4000, 2, 3, false),
+                region("STACK", 0x7000, 0x8000, 1, 1, false),
+            ],
+            stack_region: Some(2),
+        };
+        let dcache_config = CacheConfig {
+            size: 64,
+            associativity: 2,
+            line_size: 16,
+            miss_penalty: 10,
+        };
+
+        // mov eax, dword ptr [0x2000]; mov dword ptr [rsp + 8], eax; mov ecx, dword ptr [rdi];
+        // mov ebx, 0x3000; mov edx, dword ptr [rbx + 4]; mov eax, dword ptr [0x2000]; push rbp
+        let code = [
+            0x8b, 0x04, 0x25, 0x00, 0x20, 0x00, 0x00, 0x89, 0x44, 0x24, 0x08, 0x8b, 0x0f, 0xbb,
+            0x00, 0x30, 0x00, 0x00, 0x8b, 0x53, 0x04, 0x8b, 0x04, 0x25, 0x00, 0x20, 0x00, 0x00,
+            0x55,
+        ];
+        let block = block(disassemble(&x86_64(), 0x1000, &code), None);
+        let graph = graph(std::slice::from_ref(&block));
+        let values = analyze_values(&graph, &x86_64());
+
+        let addresses = get_memory_accesses(&block, &values)
+            .into_iter()
+            .map(|access| (access.instruction_address, access.kind, access.address))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            addresses,
+            [
+                (
+                    0x1000,
+                    MemoryAccessKind::Read,
+                    AccessedAddress::Exact(0x2000)
+                ),
+                (0x1007, MemoryAccessKind::Write, AccessedAddress::Stack),
+                (0x100b, MemoryAccessKind::Read, AccessedAddress::Unknown),
+                (
+                    0x1012,
+                    MemoryAccessKind::Read,
+                    AccessedAddress::Exact(0x3004)
+                ),
+                (
+                    0x1015,
+                    MemoryAccessKind::Read,
+                    AccessedAddress::Exact(0x2000)
+                ),
+                (0x101c, MemoryAccessKind::Write, AccessedAddress::Stack),
+            ]
+        );
+
+        let mut blocks = BTreeMap::from([(block.leader, block.clone())]);
+        let report = apply_memory_latencies(
+            &mut blocks,
+            &graph,
+            &memory_map,
+            Some(&dcache_config),
+            &values,
+        );
+        let added_latencies = blocks[&0x1000]
+            .instructions
+            .iter()
+            .zip(&block.instructions)
+            .map(|(instruction, original)| instruction.latency - original.latency)
+            .collect::<Vec<_>>();
+        // the first read of the cached line misses, the second one hits: the unknown read between them
+        // ages the line by one of the 2 ways, it takes the slowest region and may miss in the data cache
+        assert_eq!(added_latencies, [15, 1, 15, 0, 2, 0, 1]);
+        assert_eq!(report.unknown_accesses, [0x100b]);
+        assert_eq!(report.first_miss_penalty, 0);
+    }
+}
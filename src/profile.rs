@@ -0,0 +1,163 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::instruction::Instruction;
+
+// env variable format: CPU_PROFILE=name or name@version, e.g. CPU_PROFILE=cortex-m4
+// the variables already set (in the .env file or in the environment) override the profile ones
+// CPU_PROFILE_REPORT=true prints the mnemonics of the binary missing from the profile
+
+pub struct CpuProfile {
+    pub name: &'static str,
+    pub version: u32,
+    pub latencies: &'static str, // same format as the .env file
+}
+
+pub const CPU_PROFILES: &[CpuProfile] = &[
+    CpuProfile {
+        name: "cortex-m0plus",
+        version: 1,
+        latencies: include_str!("../profiles/cortex-m0plus.env"),
+    },
+    CpuProfile {
+        name: "cortex-m3",
+        version: 1,
+        latencies: include_str!("../profiles/cortex-m3.env"),
+    },
+    CpuProfile {
+        name: "cortex-m4",
+        version: 1,
+        latencies: include_str!("../profiles/cortex-m4.env"),
+    },
+    CpuProfile {
+        name: "cortex-m7",
+        version: 1,
+        latencies: include_str!("../profiles/cortex-m7.env"),
+    },
+    CpuProfile {
+        name: "sifive-e31",
+        version: 1,
+        latencies: include_str!("../profiles/sifive-e31.env"),
+    },
+    CpuProfile {
+        name: "x86-64",
+        version: 1,
+        latencies: include_str!("../profiles/x86-64.env"),
+    },
+];
+
+impl CpuProfile {
+    pub fn from_env() -> Option<&'static CpuProfile> {
+        let value = std::env::var("CPU_PROFILE").ok()?;
+        let (name, version) = match value.split_once('@') {
+            Some((name, version)) => match version.parse::<u32>() {
+                Ok(version) => (name, Some(version)),
+                Err(_) => panic!("The environment variable CPU_PROFILE has an invalid version"),
+            },
+            None => (value.as_str(), None),
+        };
+
+        let Some(profile) = CPU_PROFILES.iter().find(|profile| profile.name == name) else {
+            let names = CPU_PROFILES
+                .iter()
+                .map(|profile| profile.name)
+                .collect::<Vec<_>>()
+                .join(", ");
+            panic!("Unknown CPU profile {name}, use one of {names}");
+        };
+
+        if version.is_some_and(|version| version != profile.version) {
+            panic!(
+                "The CPU profile {name} is at version {}, not {}",
+                profile.version,
+                version.unwrap()
+            );
+        }
+
+        Some(profile)
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (&'static str, &'static str)> {
+        self.latencies
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| line.split_once('='))
+    }
+
+    // set the variables of the profile that are not already set
+    pub fn load(&self) {
+        for (key, value) in self.entries() {
+            if std::env::var(key).is_err() {
+                std::env::set_var(key, value);
+            }
+        }
+    }
+
+    // the profile has a rule for the mnemonic, for any operands or direction
+    pub fn contains(&self, arch_mnemonic_str: &str) -> bool {
+        let operands_prefix = format!("{arch_mnemonic_str}_");
+        self.entries()
+            .any(|(key, _)| key == arch_mnemonic_str || key.starts_with(&operands_prefix))
+    }
+
+    // mnemonic -> addresses of the instructions whose mnemonic is not in the profile, an instruction
+    // of more than one block (e.g. of a duplicated function) is counted once
+    pub fn missing_mnemonics(
+        &self,
+        instructions: &[Instruction],
+    ) -> BTreeMap<String, BTreeSet<u64>> {
+        let mut missing = BTreeMap::<String, BTreeSet<u64>>::new();
+        let mut covered = BTreeSet::<String>::new();
+
+        for instruction in instructions {
+            let key = instruction.arch_mnemonic_str();
+            if covered.contains(&key) {
+                continue;
+            }
+            if self.contains(&key) {
+                covered.insert(key);
+            } else {
+                missing
+                    .entry(instruction.mnemonic.clone())
+                    .or_default()
+                    .insert(instruction.address);
+            }
+        }
+
+        missing
+    }
+}
+
+impl std::fmt::Display for CpuProfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} v{}", self.name, self.version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{disassemble, x86_64};
+
+    #[test]
+    fn missing_mnemonics_are_counted_by_address() {
+        let profile = CpuProfile {
+            name: "test",
+            version: 1,
+            latencies: "# comment\nX86_ADD=1\nX86_IMUL_REG_IMM=3\n",
+        };
+        // add rcx, 1; mov rax, qword ptr [rbx]; imul rax, rbx; mov rax, qword ptr [rbx]
+        let code = [
+            0x48, 0x83, 0xc1, 0x01, 0x48, 0x8b, 0x03, 0x48, 0x0f, 0xaf, 0xc3, 0x48, 0x8b, 0x03,
+        ];
+        let instructions = disassemble(&x86_64(), 0x1000, &code);
+        // the instructions of a duplicated block come again
+        let mut analyzed = instructions.clone();
+        analyzed.extend(instructions.iter().cloned());
+
+        assert_eq!(
+            profile.missing_mnemonics(&analyzed),
+            BTreeMap::from([("mov".to_string(), BTreeSet::from([0x1004, 0x100b]))])
+        );
+    }
+}
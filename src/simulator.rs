@@ -0,0 +1,521 @@
+use std::collections::{BTreeMap, HashMap};
+
+use capstone::{Arch, Mode};
+
+use crate::arch::ArchMode;
+use crate::binary::CodeSection;
+use crate::block::Block;
+use crate::instruction::{Instruction, Operand};
+use crate::jump::ExitJump;
+
+// env variable format: SIMULATE_FUNCTION=symbol or address of the function to execute (RV32I/RV64I with M)
+// SIMULATE_REG_A0=5 sets the initial value of a register, SIMULATE_MEM_0x20000000=1,2,3 writes 32-bit words
+// SIMULATE_MAX_STEPS=1000000 stops an execution that doesn't terminate
+
+const RETURN_ADDRESS: u64 = 0xffff_fff0; // the simulation ends when the function returns here
+const STACK_POINTER: u64 = 0x7fff_fff0;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimulationResult {
+    pub function: u64,
+    pub cycles: u64,
+    pub instructions: u64,
+    pub returned: bool,
+    pub unsupported: Option<Instruction>, // the instruction that stopped the simulation
+}
+
+struct Machine<'a> {
+    xlen: u32,
+    pc: u64,
+    registers: HashMap<String, u64>,
+    memory: HashMap<u64, u8>,
+    instructions: HashMap<u64, &'a Instruction>,
+    call_targets: HashMap<u64, u64>, // call_insn_address -> resolved target
+    external_calls: &'a HashMap<u64, String>,
+}
+
+pub fn simulate(
+    function: u64,
+    blocks: &BTreeMap<u64, Block>,
+    code_sections: &[CodeSection],
+    external_calls: &HashMap<u64, String>,
+    arch_mode: &ArchMode,
+) -> SimulationResult {
+    if arch_mode.arch != Arch::RISCV {
+        panic!(
+            "The simulator supports only RISC-V, not {:?}",
+            arch_mode.arch
+        );
+    }
+
+    let mut machine = Machine {
+        xlen: if arch_mode.mode == Mode::RiscV32 {
+            32
+        } else {
+            64
+        },
+        pc: function,
+        registers: HashMap::new(),
+        memory: HashMap::new(),
+        instructions: HashMap::new(),
+        call_targets: HashMap::new(),
+        external_calls,
+    };
+
+    for block in blocks.values() {
+        for instruction in &block.instructions {
+            machine
+                .instructions
+                .insert(instruction.address, instruction);
+        }
+        // the calls of a relocatable object are resolved only in the exit jumps
+        if let (Some(ExitJump::Call(target, _)), Some(last)) =
+            (&block.exit_jump, block.instructions.last())
+        {
+            machine.call_targets.insert(last.address, *target);
+        }
+    }
+
+    for section in code_sections {
+        for (i, byte) in section.data.iter().enumerate() {
+            machine.memory.insert(section.address + i as u64, *byte);
+        }
+    }
+
+    machine.write_register("ra", RETURN_ADDRESS);
+    machine.write_register("sp", STACK_POINTER);
+    machine.load_state_from_env();
+
+    let max_steps = match std::env::var("SIMULATE_MAX_STEPS") {
+        Ok(value) => match value.parse::<u64>() {
+            Ok(value) => value,
+            Err(_) => panic!("The environment variable SIMULATE_MAX_STEPS is not a valid number"),
+        },
+        Err(_) => 1_000_000,
+    };
+
+    let mut result = SimulationResult {
+        function,
+        cycles: 0,
+        instructions: 0,
+        returned: false,
+        unsupported: None,
+    };
+
+    while result.instructions < max_steps {
+        if machine.pc == RETURN_ADDRESS {
+            result.returned = true;
+            break;
+        }
+        let Some(instruction) = machine.instructions.get(&machine.pc).copied() else {
+            break;
+        };
+
+        match machine.step(instruction) {
+            Some(taken) => {
+                result.cycles += instruction.latency as u64;
+                result.cycles += match taken {
+                    Some(true) => instruction.taken_penalty as u64,
+                    Some(false) => instruction.not_taken_penalty as u64,
+                    None => 0,
+                };
+                result.instructions += 1;
+            }
+            None => {
+                result.unsupported = Some(instruction.clone());
+                break;
+            }
+        }
+    }
+
+    result
+}
+
+impl Machine<'_> {
+    fn load_state_from_env(&mut self) {
+        let parse_number = |env_var_key: &str, value: &str| {
+            let value = value.trim();
+            let parsed = match value.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16),
+                None => value.parse::<i64>().map(|value| value as u64),
+            };
+            match parsed {
+                Ok(value) => value,
+                Err(_) => panic!("The environment variable {env_var_key} is not a valid number"),
+            }
+        };
+
+        for (key, value) in std::env::vars() {
+            if let Some(register) = key.strip_prefix("SIMULATE_REG_") {
+                let value = parse_number(&key, &value);
+                self.write_register(&register.to_lowercase(), value);
+            } else if let Some(address) = key.strip_prefix("SIMULATE_MEM_") {
+                let address = parse_number(&key, address);
+                for (i, word) in value.split(',').enumerate() {
+                    let word = parse_number(&key, word);
+                    self.store(address + 4 * i as u64, word, 4);
+                }
+            }
+        }
+    }
+
+    fn mask(&self, value: u64) -> u64 {
+        if self.xlen == 32 {
+            value as u32 as u64
+        } else {
+            value
+        }
+    }
+
+    fn signed(&self, value: u64) -> i64 {
+        if self.xlen == 32 {
+            value as u32 as i32 as i64
+        } else {
+            value as i64
+        }
+    }
+
+    fn read_register(&self, register: &str) -> u64 {
+        self.registers.get(register).copied().unwrap_or(0)
+    }
+
+    fn write_register(&mut self, register: &str, value: u64) {
+        if register != "zero" && register != "x0" {
+            let value = self.mask(value);
+            self.registers.insert(register.to_string(), value);
+        }
+    }
+
+    fn load(&self, address: u64, size: u64) -> u64 {
+        (0..size).fold(0, |value, i| {
+            let byte = self.memory.get(&(address + i)).copied().unwrap_or(0) as u64;
+            value | byte << (8 * i)
+        })
+    }
+
+    fn store(&mut self, address: u64, value: u64, size: u64) {
+        for i in 0..size {
+            self.memory.insert(address + i, (value >> (8 * i)) as u8);
+        }
+    }
+
+    // executes the instruction, returns None if it is not supported,
+    // otherwise if it is a conditional branch whether it is taken
+    fn step(&mut self, instruction: &Instruction) -> Option<Option<bool>> {
+        let mnemonic = instruction.mnemonic.trim_start_matches("c.");
+        let next_pc = instruction.address + instruction.size as u64;
+        let pc = instruction.address;
+
+        let reg = |i: usize| match instruction.operands.get(i) {
+            Some(Operand::Reg(reg)) => Some(reg.as_str()),
+            _ => None,
+        };
+        let imm = |i: usize| match instruction.operands.get(i) {
+            Some(Operand::Imm(imm)) => Some(*imm),
+            _ => None,
+        };
+        let value = |machine: &Self, i: usize| match instruction.operands.get(i) {
+            Some(Operand::Reg(reg)) => Some(machine.read_register(reg)),
+            Some(Operand::Imm(imm)) => Some(*imm as u64),
+            _ => None,
+        };
+        let mem_address = |machine: &Self| {
+            instruction.operands.iter().find_map(|op| match op {
+                Operand::Mem { base, disp, .. } => Some(
+                    base.as_deref()
+                        .map_or(0, |base| machine.read_register(base))
+                        .wrapping_add(*disp as u64),
+                ),
+                _ => None,
+            })
+        };
+
+        let mut new_pc = next_pc;
+        let mut taken = None;
+
+        match mnemonic {
+            "nop" => {}
+            "li" => self.write_register(reg(0)?, imm(1)? as u64),
+            "mv" => self.write_register(reg(0)?, value(self, 1)?),
+            "not" => self.write_register(reg(0)?, !value(self, 1)?),
+            "neg" => self.write_register(reg(0)?, value(self, 1)?.wrapping_neg()),
+            "negw" => self.write_register(reg(0)?, sext32(value(self, 1)?.wrapping_neg())),
+            "sext.w" => self.write_register(reg(0)?, sext32(value(self, 1)?)),
+            "seqz" => self.write_register(reg(0)?, (value(self, 1)? == 0) as u64),
+            "snez" => self.write_register(reg(0)?, (value(self, 1)? != 0) as u64),
+            "sltz" => self.write_register(reg(0)?, (self.signed(value(self, 1)?) < 0) as u64),
+            "sgtz" => self.write_register(reg(0)?, (self.signed(value(self, 1)?) > 0) as u64),
+            "lui" => self.write_register(reg(0)?, sext32((imm(1)? as u64) << 12)),
+            "auipc" => {
+                self.write_register(reg(0)?, pc.wrapping_add(sext32((imm(1)? as u64) << 12)))
+            }
+            "add" | "addi" | "sub" | "and" | "andi" | "or" | "ori" | "xor" | "xori" | "sll"
+            | "slli" | "srl" | "srli" | "sra" | "srai" | "slt" | "slti" | "sltu" | "sltiu"
+            | "mul" | "mulh" | "mulhu" | "div" | "divu" | "rem" | "remu" => {
+                let (a, b) = (value(self, 1)?, value(self, 2)?);
+                let result = self.alu(mnemonic.trim_end_matches('i'), a, b)?;
+                self.write_register(reg(0)?, result);
+            }
+            "addw" | "addiw" | "subw" | "sllw" | "slliw" | "srlw" | "srliw" | "sraw" | "sraiw"
+            | "mulw" | "divw" | "divuw" | "remw" | "remuw" => {
+                let (a, b) = (value(self, 1)?, value(self, 2)?);
+                let result = alu32(mnemonic.trim_end_matches('w').trim_end_matches('i'), a, b)?;
+                self.write_register(reg(0)?, result);
+            }
+            "lb" | "lh" | "lw" | "ld" | "lbu" | "lhu" | "lwu" => {
+                let size = match &mnemonic[1..2] {
+                    "b" => 1,
+                    "h" => 2,
+                    "w" => 4,
+                    _ => 8,
+                };
+                let address = mem_address(self)?;
+                let mut loaded = self.load(address, size);
+                if !mnemonic.ends_with('u') && size < 8 {
+                    let shift = 64 - 8 * size;
+                    loaded = (((loaded << shift) as i64) >> shift) as u64;
+                }
+                self.write_register(reg(0)?, loaded);
+            }
+            "sb" | "sh" | "sw" | "sd" => {
+                let size = match mnemonic {
+                    "sb" => 1,
+                    "sh" => 2,
+                    "sw" => 4,
+                    _ => 8,
+                };
+                let address = mem_address(self)?;
+                let stored = self.read_register(reg(0)?);
+                self.store(address, stored, size);
+            }
+            "beq" | "bne" | "blt" | "bge" | "bltu" | "bgeu" | "bgt" | "ble" | "bgtu" | "bleu"
+            | "beqz" | "bnez" | "blez" | "bgez" | "bltz" | "bgtz" => {
+                let a = value(self, 0)?;
+                let (b, offset) = match (reg(1), imm(1)) {
+                    (Some(_), _) => (value(self, 1)?, imm(2)?),
+                    (None, Some(offset)) => (0, offset),
+                    _ => return None,
+                };
+                let (sa, sb) = (self.signed(a), self.signed(b));
+                let is_taken = match mnemonic.trim_end_matches('z') {
+                    "beq" => a == b,
+                    "bne" => a != b,
+                    "blt" => sa < sb,
+                    "bge" => sa >= sb,
+                    "bgt" => sa > sb,
+                    "ble" => sa <= sb,
+                    "bltu" => a < b,
+                    "bgeu" => a >= b,
+                    "bgtu" => a > b,
+                    "bleu" => a <= b,
+                    _ => return None,
+                };
+                if is_taken {
+                    new_pc = pc.wrapping_add(offset as u64);
+                }
+                taken = Some(is_taken);
+            }
+            "j" => new_pc = pc.wrapping_add(imm(0)? as u64),
+            "jal" | "call" | "tail" => {
+                // "jal offset" links in ra, "jal rd, offset" in rd
+                let (link, offset) = match reg(0) {
+                    Some(link) => (Some(link), imm(1)?),
+                    None if mnemonic == "tail" => (None, imm(0)?),
+                    None => (Some("ra"), imm(0)?),
+                };
+                new_pc = self.call_target(instruction, pc.wrapping_add(offset as u64));
+                if let Some(link) = link {
+                    self.write_register(link, next_pc);
+                }
+            }
+            "jr" | "jalr" | "ret" => {
+                let target = match (mnemonic, mem_address(self)) {
+                    ("ret", _) => self.read_register("ra"),
+                    (_, Some(address)) => address,
+                    _ => {
+                        let base = instruction.operands.iter().rev().find_map(|op| match op {
+                            Operand::Reg(reg) => Some(reg.as_str()),
+                            _ => None,
+                        })?;
+                        let offset = instruction.operands.iter().find_map(|op| match op {
+                            Operand::Imm(imm) => Some(*imm),
+                            _ => None,
+                        });
+                        self.read_register(base)
+                            .wrapping_add(offset.unwrap_or(0) as u64)
+                    }
+                };
+                new_pc = self.call_target(instruction, target & !1);
+                // "jalr rs" links in ra, "jalr rd, rs, offset" and "jalr rd, offset(rs)" in rd
+                if mnemonic == "jalr" {
+                    let registers = instruction
+                        .operands
+                        .iter()
+                        .filter(|op| matches!(op, Operand::Reg(_)))
+                        .count();
+                    let explicit_link =
+                        registers >= 2 || mem_address(self).is_some() && registers == 1;
+                    let link = if explicit_link { reg(0)? } else { "ra" };
+                    self.write_register(link, next_pc);
+                }
+            }
+            "fence" | "fence.i" | "ecall" | "ebreak" | "wfi" => {}
+            _ => return None,
+        }
+
+        self.pc = self.mask(new_pc);
+        Some(taken)
+    }
+
+    // a call of a relocatable object is resolved with its exit jump, an external call is skipped
+    fn call_target(&self, instruction: &Instruction, target: u64) -> u64 {
+        if self.external_calls.contains_key(&instruction.address) {
+            instruction.address + instruction.size as u64
+        } else {
+            self.call_targets
+                .get(&instruction.address)
+                .copied()
+                .unwrap_or(target)
+        }
+    }
+
+    fn alu(&self, operation: &str, a: u64, b: u64) -> Option<u64> {
+        let shift = (b & (self.xlen as u64 - 1)) as u32;
+        let (sa, sb) = (self.signed(a), self.signed(b));
+        let (ua, ub) = (self.mask(a), self.mask(b));
+        let result = match operation {
+            "add" | "addi" => a.wrapping_add(b),
+            "sub" => a.wrapping_sub(b),
+            "and" | "andi" => a & b,
+            "or" | "ori" => a | b,
+            "xor" | "xori" => a ^ b,
+            "sll" | "slli" => a << shift,
+            "srl" | "srli" => ua >> shift,
+            "sra" | "srai" => (sa >> shift) as u64,
+            "slt" | "slti" => (sa < sb) as u64,
+            "sltu" | "sltiu" => (ua < ub) as u64,
+            "mul" => a.wrapping_mul(b),
+            "mulh" => ((sa as i128 * sb as i128) >> self.xlen) as u64,
+            "mulhu" => ((ua as u128 * ub as u128) >> self.xlen) as u64,
+            "div" if sb == 0 => u64::MAX,
+            "div" => sa.wrapping_div(sb) as u64,
+            "divu" if ub == 0 => u64::MAX,
+            "divu" => ua / ub,
+            "rem" if sb == 0 => a,
+            "rem" => sa.wrapping_rem(sb) as u64,
+            "remu" if ub == 0 => a,
+            "remu" => ua % ub,
+            _ => return None,
+        };
+        Some(result)
+    }
+}
+
+fn sext32(value: u64) -> u64 {
+    value as u32 as i32 as i64 as u64
+}
+
+// the RV64 word instructions work on the low 32 bits and sign-extend the result
+fn alu32(operation: &str, a: u64, b: u64) -> Option<u64> {
+    let (a, b) = (a as u32, b as u32);
+    let shift = b & 31;
+    let result = match operation {
+        "add" | "addi" => a.wrapping_add(b),
+        "sub" => a.wrapping_sub(b),
+        "sll" => a << shift,
+        "srl" => a >> shift,
+        "sra" => ((a as i32) >> shift) as u32,
+        "mul" => a.wrapping_mul(b),
+        "div" if b == 0 => u32::MAX,
+        "div" => (a as i32).wrapping_div(b as i32) as u32,
+        "divu" if b == 0 => u32::MAX,
+        "divu" => a / b,
+        "rem" if b == 0 => a,
+        "rem" => (a as i32).wrapping_rem(b as i32) as u32,
+        "remu" if b == 0 => a,
+        "remu" => a % b,
+        _ => return None,
+    };
+    Some(sext32(result as u64))
+}
+
+impl std::fmt::Display for SimulationResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Simulation of 0x{:x}: {} instructions, {} clock cycles",
+            self.function, self.instructions, self.cycles
+        )?;
+        if let Some(instruction) = &self.unsupported {
+            write!(f, ", stopped at the unsupported instruction {instruction}")?;
+        } else if !self.returned {
+            write!(f, ", stopped before the function returned")?;
+        }
+        writeln!(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{block, disassemble, riscv32};
+
+    #[test]
+    fn rv32_loop_with_memory_and_32_bit_wrap() {
+        // li a0, 0; li a1, 5; loop: add a0, a0, a1; addi a1, a1, -1; bnez a1, loop;
+        // sw a0, 12(sp); lw a2, 12(sp); bne a0, a2, loop;
+        // lui a3, 0x80000; add a3, a3, a3; bnez a3, loop; ret
+        let code = [
+            0x13, 0x05, 0x00, 0x00, 0x93, 0x05, 0x50, 0x00, 0x33, 0x05, 0xb5, 0x00, 0x93, 0x85,
+            0xf5, 0xff, 0xe3, 0x9c, 0x05, 0xfe, 0x23, 0x26, 0xa1, 0x00, 0x03, 0x26, 0xc1, 0x00,
+            0xe3, 0x16, 0xc5, 0xfe, 0xb7, 0x06, 0x00, 0x80, 0xb3, 0x86, 0xd6, 0x00, 0xe3, 0x90,
+            0x06, 0xfe, 0x67, 0x80, 0x00, 0x00,
+        ];
+        let instructions = disassemble(&riscv32(), 0, &code);
+        let blocks = BTreeMap::from([(0, block(instructions.clone(), None))]);
+        let result = simulate(0, &blocks, &[], &HashMap::new(), &riscv32());
+
+        // the loop runs 5 times, the stored value is loaded back and 0x80000000 + 0x80000000 wraps to 0
+        let executions = [1, 1, 5, 5, 5, 1, 1, 1, 1, 1, 1, 1];
+        let branch = |address: u64| {
+            instructions
+                .iter()
+                .find(|instruction| instruction.address == address)
+                .unwrap()
+        };
+        let cycles = instructions
+            .iter()
+            .zip(executions)
+            .map(|(instruction, executions)| u64::from(instruction.latency) * executions)
+            .sum::<u64>()
+            + 4 * u64::from(branch(0x10).taken_penalty)
+            + u64::from(branch(0x10).not_taken_penalty)
+            + u64::from(branch(0x1c).not_taken_penalty)
+            + u64::from(branch(0x28).not_taken_penalty);
+        assert_eq!(
+            result,
+            SimulationResult {
+                function: 0,
+                cycles,
+                instructions: 24,
+                returned: true,
+                unsupported: None,
+            }
+        );
+    }
+
+    #[test]
+    fn unsupported_instruction_stops_the_simulation() {
+        // li a0, 1; csrr a1, mcycle; ret
+        let code = [
+            0x13, 0x05, 0x10, 0x00, 0xf3, 0x25, 0x00, 0xb0, 0x67, 0x80, 0x00, 0x00,
+        ];
+        let instructions = disassemble(&riscv32(), 0x100, &code);
+        let blocks = BTreeMap::from([(0x100, block(instructions.clone(), None))]);
+        let result = simulate(0x100, &blocks, &[], &HashMap::new(), &riscv32());
+
+        assert!(!result.returned);
+        assert_eq!(result.instructions, 1);
+        assert_eq!(result.unsupported, Some(instructions[1].clone()));
+    }
+}
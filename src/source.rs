@@ -0,0 +1,493 @@
+// Source locations of the instructions, from the DWARF debug info of the input (.debug_line and .debug_info).
+// The warnings, the dot labels and the reports show filter.c:42 next to the addresses when the input has them.
+// The debug sections of a relocatable object are relocated to the addresses of the joined code sections.
+// On RISC-V the linker relaxation can shorten the code, so the debug info of an object has the differences
+// of two code addresses as pairs of ADD and SUB relocations: they are applied as well.
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use gimli::{AttributeValue, EndianSlice, RunTimeEndian};
+use object::{
+    elf, Architecture, Object, ObjectKind, ObjectSection, ObjectSymbol, RelocationKind,
+    RelocationTarget,
+};
+
+use crate::binary::Binary;
+use crate::printwarning;
+
+// the references followed to name a subprogram (abstract origin, specification)
+const MAX_NAME_REFERENCES: usize = 8;
+
+type DwarfReader<'data> = EndianSlice<'data, RunTimeEndian>;
+
+thread_local! {
+    static SOURCE_MAP: RefCell<Option<SourceMap>> = const { RefCell::new(None) };
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLocation {
+    pub file: String, // without the directory
+    pub line: u64,
+    pub function: Option<String>,
+}
+
+impl std::fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)?;
+        if let Some(function) = &self.function {
+            write!(f, " in {function}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    // address -> file and line of the instructions from the address on, None after the end of a sequence
+    lines: BTreeMap<u64, Option<(String, u64)>>,
+    functions: BTreeMap<u64, (u64, String)>, // low_pc -> (high_pc, name)
+}
+
+impl SourceMap {
+    // None if the input has no line table
+    pub fn new(obj_file: &object::File, binary: &Binary) -> Option<Self> {
+        obj_file.section_by_name(".debug_line")?;
+
+        match load_source_map(obj_file, binary) {
+            Ok(source_map) => Some(source_map),
+            Err(error) => {
+                printwarning!(
+                    "The debug info can't be read ({error}) -> the source locations are not shown"
+                );
+                None
+            }
+        }
+    }
+
+    pub fn location(&self, address: u64) -> Option<SourceLocation> {
+        let (_, row) = self.lines.range(..=address).next_back()?;
+        let (file, line) = row.as_ref()?;
+        let function = self
+            .functions
+            .range(..=address)
+            .next_back()
+            .filter(|(_, (high_pc, _))| address < *high_pc)
+            .map(|(_, (_, name))| name.clone());
+
+        Some(SourceLocation {
+            file: file.clone(),
+            line: *line,
+            function,
+        })
+    }
+
+    pub fn lines_len(&self) -> usize {
+        self.lines.values().filter(|row| row.is_some()).count()
+    }
+
+    pub fn functions_len(&self) -> usize {
+        self.functions.len()
+    }
+}
+
+// the source map of the analysis running on the current thread
+pub fn set_source_map(source_map: Option<SourceMap>) {
+    SOURCE_MAP.with(|current| *current.borrow_mut() = source_map);
+}
+
+pub fn source_location(address: u64) -> Option<SourceLocation> {
+    SOURCE_MAP.with(|source_map| source_map.borrow().as_ref()?.location(address))
+}
+
+// the source location to write after an address, e.g. " (filter.c:42 in filter)", empty without debug info
+pub fn source_suffix(address: u64) -> String {
+    source_location(address).map_or(String::new(), |location| format!(" ({location})"))
+}
+
+// why the debug info can't be read
+#[derive(Debug)]
+enum DebugInfoError {
+    Dwarf(gimli::Error),
+    UnsupportedRelocation {
+        section: String,
+        kind: RelocationKind,
+    },
+}
+
+impl From<gimli::Error> for DebugInfoError {
+    fn from(error: gimli::Error) -> Self {
+        DebugInfoError::Dwarf(error)
+    }
+}
+
+impl std::fmt::Display for DebugInfoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DebugInfoError::Dwarf(error) => write!(f, "{error}"),
+            DebugInfoError::UnsupportedRelocation { section, kind } => {
+                write!(f, "unsupported relocation {kind:?} in {section}")
+            }
+        }
+    }
+}
+
+fn load_source_map(obj_file: &object::File, binary: &Binary) -> Result<SourceMap, DebugInfoError> {
+    let endian = if obj_file.is_little_endian() {
+        RunTimeEndian::Little
+    } else {
+        RunTimeEndian::Big
+    };
+
+    let dwarf_sections = gimli::Dwarf::load(|id| load_section(obj_file, binary, id.name()))?;
+    let dwarf = dwarf_sections.borrow(|section| EndianSlice::new(section, endian));
+    Ok(read_source_map(&dwarf)?)
+}
+
+// the line table and the subprograms of every unit
+fn read_source_map(dwarf: &gimli::Dwarf<DwarfReader>) -> Result<SourceMap, gimli::Error> {
+    let mut source_map = SourceMap::default();
+    let mut units = dwarf.units();
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+
+        if let Some(program) = unit.line_program.clone() {
+            let mut rows = program.rows();
+            while let Some((header, row)) = rows.next_row()? {
+                // the next sequence can start where this one ends
+                if row.end_sequence() {
+                    source_map.lines.entry(row.address()).or_insert(None);
+                    continue;
+                }
+                let (Some(line), Some(file)) = (row.line(), row.file(header)) else {
+                    continue;
+                };
+                let path = dwarf.attr_string(&unit, file.path_name())?;
+                let path = path.to_string_lossy();
+                let file_name = Path::new(path.as_ref())
+                    .file_name()
+                    .map_or(path.to_string(), |name| name.to_string_lossy().to_string());
+                source_map
+                    .lines
+                    .insert(row.address(), Some((file_name, line.get())));
+            }
+        }
+
+        let mut entries = unit.entries();
+        while let Some((_, entry)) = entries.next_dfs()? {
+            if entry.tag() != gimli::DW_TAG_subprogram {
+                continue;
+            }
+            let Some(name) = subprogram_name(dwarf, &unit, entry, MAX_NAME_REFERENCES)? else {
+                continue;
+            };
+            let mut ranges = dwarf.die_ranges(&unit, entry)?;
+            while let Some(range) = ranges.next()? {
+                if range.begin < range.end {
+                    source_map
+                        .functions
+                        .insert(range.begin, (range.end, name.clone()));
+                }
+            }
+        }
+    }
+
+    Ok(source_map)
+}
+
+// the name of a subprogram, or of the declaration or the abstract instance it refers to
+// (e.g. the out-of-line copy of an inline function, the definition of a C++ method)
+fn subprogram_name(
+    dwarf: &gimli::Dwarf<DwarfReader>,
+    unit: &gimli::Unit<DwarfReader>,
+    entry: &gimli::DebuggingInformationEntry<DwarfReader>,
+    references: usize,
+) -> gimli::Result<Option<String>> {
+    if let Some(name) = entry.attr_value(gimli::DW_AT_name)? {
+        let name = dwarf.attr_string(unit, name)?;
+        return Ok(Some(name.to_string_lossy().to_string()));
+    }
+    if references == 0 {
+        return Ok(None);
+    }
+
+    for attribute in [gimli::DW_AT_abstract_origin, gimli::DW_AT_specification] {
+        match entry.attr_value(attribute)? {
+            Some(AttributeValue::UnitRef(offset)) => {
+                let entry = unit.entry(offset)?;
+                return subprogram_name(dwarf, unit, &entry, references - 1);
+            }
+            Some(AttributeValue::DebugInfoRef(offset)) => {
+                // the reference can be to another unit
+                let mut units = dwarf.units();
+                while let Some(header) = units.next()? {
+                    let Some(unit_offset) = offset.to_unit_offset(&header) else {
+                        continue;
+                    };
+                    let unit = dwarf.unit(header)?;
+                    let entry = unit.entry(unit_offset)?;
+                    return subprogram_name(dwarf, &unit, &entry, references - 1);
+                }
+                return Ok(None);
+            }
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+// the data of a debug section, with the absolute relocations of a relocatable object applied
+// and the RISC-V relocations of the address differences (the other ones are left as they are)
+fn load_section<'data>(
+    obj_file: &object::File<'data>,
+    binary: &Binary,
+    name: &str,
+) -> Result<Cow<'data, [u8]>, DebugInfoError> {
+    let Some(section) = obj_file.section_by_name(name) else {
+        return Ok(Cow::Borrowed(&[]));
+    };
+    let Ok(data) = section.uncompressed_data() else {
+        return Ok(Cow::Borrowed(&[]));
+    };
+    if binary.kind != ObjectKind::Relocatable {
+        return Ok(data);
+    }
+    let is_riscv = matches!(
+        obj_file.architecture(),
+        Architecture::Riscv32 | Architecture::Riscv64
+    );
+
+    let mut data = data.into_owned();
+    for (offset, relocation) in section.relocations() {
+        let riscv_relocation = match relocation.kind() {
+            RelocationKind::Absolute => None,
+            RelocationKind::Elf(elf::R_RISCV_RELAX) if is_riscv => continue,
+            RelocationKind::Elf(r_type) if is_riscv => match riscv_relocation(r_type) {
+                Some(riscv_relocation) => Some(riscv_relocation),
+                // e.g. the ULEB128 pairs: the addresses of the section would be wrong
+                None => {
+                    return Err(DebugInfoError::UnsupportedRelocation {
+                        section: name.to_string(),
+                        kind: relocation.kind(),
+                    })
+                }
+            },
+            kind if is_riscv => {
+                return Err(DebugInfoError::UnsupportedRelocation {
+                    section: name.to_string(),
+                    kind,
+                })
+            }
+            _ => continue,
+        };
+        // the code sections are at their address in the joined section, the other ones at 0
+        let target_address = match relocation.target() {
+            RelocationTarget::Symbol(index) => {
+                let Ok(symbol) = obj_file.symbol_by_index(index) else {
+                    continue;
+                };
+                let section_address = symbol
+                    .section_index()
+                    .and_then(|index| binary.section_addresses.get(&index))
+                    .copied()
+                    .unwrap_or(0);
+                section_address + symbol.address()
+            }
+            RelocationTarget::Section(index) => {
+                binary.section_addresses.get(&index).copied().unwrap_or(0)
+            }
+            _ => continue,
+        };
+
+        let bits = match riscv_relocation {
+            Some((_, bits)) => bits,
+            None => u32::from(relocation.size()),
+        };
+        let size = bits.div_ceil(8) as usize;
+        let Some(bytes) = usize::try_from(offset)
+            .ok()
+            .and_then(|offset| data.get_mut(offset..offset + size))
+        else {
+            continue;
+        };
+        let little_endian = obj_file.is_little_endian();
+        let mut value = target_address.wrapping_add(relocation.addend() as u64);
+        match riscv_relocation {
+            // only the low bits are relocated (6 of a byte for SUB6 and SET6)
+            Some((operation, bits)) => {
+                let mask = u64::MAX >> (64 - bits);
+                let old_value = read_value(bytes, little_endian);
+                let new_value = match operation {
+                    RiscvOperation::Add => old_value.wrapping_add(value),
+                    RiscvOperation::Sub => old_value.wrapping_sub(value),
+                    RiscvOperation::Set => value,
+                };
+                value = (old_value & !mask) | (new_value & mask);
+            }
+            None if relocation.has_implicit_addend() => {
+                value = value.wrapping_add(read_value(bytes, little_endian));
+            }
+            None => {}
+        }
+        write_value(bytes, value, little_endian);
+    }
+    Ok(Cow::Owned(data))
+}
+
+#[derive(Debug, Clone, Copy)]
+enum RiscvOperation {
+    Add,
+    Sub,
+    Set,
+}
+
+// the operation and the relocated bits of the RISC-V relocations of the address differences
+fn riscv_relocation(r_type: u32) -> Option<(RiscvOperation, u32)> {
+    match r_type {
+        elf::R_RISCV_ADD8 => Some((RiscvOperation::Add, 8)),
+        elf::R_RISCV_ADD16 => Some((RiscvOperation::Add, 16)),
+        elf::R_RISCV_ADD32 => Some((RiscvOperation::Add, 32)),
+        elf::R_RISCV_ADD64 => Some((RiscvOperation::Add, 64)),
+        elf::R_RISCV_SUB6 => Some((RiscvOperation::Sub, 6)),
+        elf::R_RISCV_SUB8 => Some((RiscvOperation::Sub, 8)),
+        elf::R_RISCV_SUB16 => Some((RiscvOperation::Sub, 16)),
+        elf::R_RISCV_SUB32 => Some((RiscvOperation::Sub, 32)),
+        elf::R_RISCV_SUB64 => Some((RiscvOperation::Sub, 64)),
+        elf::R_RISCV_SET6 => Some((RiscvOperation::Set, 6)),
+        elf::R_RISCV_SET8 => Some((RiscvOperation::Set, 8)),
+        elf::R_RISCV_SET16 => Some((RiscvOperation::Set, 16)),
+        elf::R_RISCV_SET32 => Some((RiscvOperation::Set, 32)),
+        _ => None,
+    }
+}
+
+fn read_value(bytes: &[u8], little_endian: bool) -> u64 {
+    let mut value = 0;
+    for i in 0..bytes.len() {
+        let byte = if little_endian {
+            bytes[bytes.len() - 1 - i]
+        } else {
+            bytes[i]
+        };
+        value = (value << 8) | u64::from(byte);
+    }
+    value
+}
+
+fn write_value(bytes: &mut [u8], value: u64, little_endian: bool) {
+    let len = bytes.len();
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        let shift = if little_endian { i } else { len - 1 - i };
+        *byte = (value >> (8 * shift)) as u8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use gimli::write::{self, Address, DwarfUnit, EndianVec, LineProgram, LineString, Sections};
+    use gimli::{Encoding, Format, LineEncoding, SectionId};
+
+    use super::*;
+
+    // the debug sections of one unit with two line sequences, 0x6000-0x6010 and 0x6020-0x6028,
+    // and the subprogram filter over the first one
+    fn debug_sections() -> HashMap<SectionId, Vec<u8>> {
+        let encoding = Encoding {
+            format: Format::Dwarf32,
+            version: 4,
+            address_size: 8,
+        };
+        let mut dwarf = DwarfUnit::new(encoding);
+        let mut program = LineProgram::new(
+            encoding,
+            LineEncoding::default(),
+            LineString::String(b"/src".to_vec()),
+            LineString::String(b"filter.c".to_vec()),
+            None,
+        );
+        let directory = program.default_directory();
+        let file = program.add_file(LineString::String(b"filter.c".to_vec()), directory, None);
+
+        for (start, rows, end) in [
+            (0x6000, &[(0, 10), (4, 12), (0xa, 15)][..], 0x10),
+            (0x6020, &[(0, 30)][..], 0x8),
+        ] {
+            program.begin_sequence(Some(Address::Constant(start)));
+            for (address_offset, line) in rows {
+                program.row().file = file;
+                program.row().address_offset = *address_offset;
+                program.row().line = *line;
+                program.generate_row();
+            }
+            program.end_sequence(end);
+        }
+        dwarf.unit.line_program = program;
+
+        let root = dwarf.unit.root();
+        let subprogram = dwarf.unit.add(root, gimli::DW_TAG_subprogram);
+        let entry = dwarf.unit.get_mut(subprogram);
+        entry.set(
+            gimli::DW_AT_name,
+            write::AttributeValue::String(b"filter".to_vec()),
+        );
+        entry.set(
+            gimli::DW_AT_low_pc,
+            write::AttributeValue::Address(Address::Constant(0x6000)),
+        );
+        entry.set(gimli::DW_AT_high_pc, write::AttributeValue::Udata(0x10));
+
+        let mut sections = Sections::new(EndianVec::new(RunTimeEndian::Little));
+        dwarf.write(&mut sections).unwrap();
+        let mut debug_sections = HashMap::new();
+        sections
+            .for_each(|id, data| {
+                debug_sections.insert(id, data.slice().to_vec());
+                Ok::<(), ()>(())
+            })
+            .unwrap();
+        debug_sections
+    }
+
+    #[test]
+    fn debug_line_lookup() {
+        let sections = debug_sections();
+        let dwarf_sections = gimli::Dwarf::load(|id| {
+            Ok::<_, gimli::Error>(sections.get(&id).map_or(&[][..], Vec::as_slice))
+        })
+        .unwrap();
+        let dwarf =
+            dwarf_sections.borrow(|section| EndianSlice::new(section, RunTimeEndian::Little));
+        let source_map = read_source_map(&dwarf).unwrap();
+
+        assert_eq!(source_map.lines_len(), 4);
+        assert_eq!(source_map.functions_len(), 1);
+        let line = |address| source_map.location(address).map(|location| location.line);
+        assert_eq!(line(0x5fff), None);
+        assert_eq!(line(0x6000), Some(10));
+        assert_eq!(line(0x6007), Some(12));
+        assert_eq!(line(0x600f), Some(15));
+        // after the end of a sequence until the next one
+        assert_eq!(line(0x6010), None);
+        assert_eq!(line(0x601f), None);
+        assert_eq!(line(0x6027), Some(30));
+        assert_eq!(line(0x6028), None);
+
+        assert_eq!(
+            source_map.location(0x6004),
+            Some(SourceLocation {
+                file: "filter.c".to_string(),
+                line: 12,
+                function: Some("filter".to_string()),
+            })
+        );
+        assert_eq!(source_map.location(0x6020).unwrap().function, None);
+
+        set_source_map(Some(source_map));
+        assert_eq!(source_suffix(0x6004), " (filter.c:12 in filter)");
+        assert_eq!(source_suffix(0x6018), "");
+        set_source_map(None);
+    }
+}
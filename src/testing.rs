@@ -0,0 +1,60 @@
+// helpers of the unit tests: blocks and graphs built by hand from machine code
+
+use std::collections::BTreeMap;
+
+use capstone::{Arch, Capstone, Mode, NO_EXTRA_MODE};
+
+use crate::arch::ArchMode;
+use crate::block::Block;
+use crate::graph::MappedGraph;
+use crate::instruction::Instruction;
+use crate::jump::ExitJump;
+use crate::CURRENT_ARCH;
+
+pub fn x86_64() -> ArchMode {
+    ArchMode {
+        arch: Arch::X86,
+        mode: Mode::Mode64,
+    }
+}
+
+pub fn riscv32() -> ArchMode {
+    ArchMode {
+        arch: Arch::RISCV,
+        mode: Mode::RiscV32,
+    }
+}
+
+// the instructions of the code at the address, with the operands and registers given by Capstone
+pub fn disassemble(arch_mode: &ArchMode, address: u64, code: &[u8]) -> Vec<Instruction> {
+    CURRENT_ARCH.with(|current_arch| *current_arch.borrow_mut() = Some(arch_mode.clone()));
+    let mut cs = Capstone::new_raw(arch_mode.arch, arch_mode.mode, NO_EXTRA_MODE, None).unwrap();
+    cs.set_detail(true).unwrap();
+    let insns = cs.disasm_all(code, address).unwrap();
+    insns
+        .iter()
+        .map(|insn| Instruction::new(insn, &cs))
+        .collect()
+}
+
+pub fn block(instructions: Vec<Instruction>, exit_jump: Option<ExitJump>) -> Block {
+    Block {
+        leader: instructions[0].address,
+        instructions,
+        exit_jump,
+    }
+}
+
+// a block of one nop at the leader, for the tests of the graph algorithms
+pub fn nop_block(leader: u64, exit_jump: Option<ExitJump>) -> Block {
+    block(disassemble(&x86_64(), leader, &[0x90]), exit_jump)
+}
+
+// one node for each block and one edge for each jump between the blocks
+pub fn graph(blocks: &[Block]) -> MappedGraph {
+    let blocks = blocks
+        .iter()
+        .map(|block| (block.leader, block.clone()))
+        .collect::<BTreeMap<_, _>>();
+    MappedGraph::from_blocks(&blocks)
+}
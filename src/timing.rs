@@ -0,0 +1,355 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::block::Block;
+use crate::instruction::{Instruction, Operand};
+use crate::jump::ExitJump;
+
+// env variable format: TIMING_MODEL=serial|pipeline
+// the pipeline model is configured with PIPELINE_STAGES, PIPELINE_BRANCH_PENALTY and PIPELINE_LOAD_USE_PENALTY
+// BRANCH_PREDICTOR=none|taken|not-taken|btfn with BRANCH_MISPREDICTION_PENALTY (in cycles)
+
+// the latencies are u32: an overflow stops the analysis instead of giving a wrong wcet
+pub fn add_latency(a: u32, b: u32) -> u32 {
+    a.checked_add(b)
+        .unwrap_or_else(|| panic!("The latency overflows u32: {a} + {b} clock cycles"))
+}
+
+pub trait TimingModel {
+    // clock cycles to execute the block after the predecessor, None if the block is entered with an empty pipeline
+    fn block_latency(&self, block: &Block, predecessor: Option<&Block>) -> u32;
+
+    // clock cycles of the jump from the predecessor to the block, the model charges a single branch cost
+    fn edge_latency(&self, predecessor: &Block, block: &Block, branch_model: &BranchModel) -> u32 {
+        branch_model.edge_penalty(predecessor, block).unwrap_or(0)
+    }
+}
+
+// every instruction waits for the previous one to complete
+pub struct SerialModel;
+
+impl TimingModel for SerialModel {
+    fn block_latency(&self, block: &Block, _predecessor: Option<&Block>) -> u32 {
+        block
+            .instructions
+            .iter()
+            .fold(0, |latency, i| add_latency(latency, i.latency))
+    }
+}
+
+// scalar in-order pipeline: one instruction issues per cycle, in program order, once its operands are ready.
+// The execute units are pipelined, so the independent instructions overlap: a result is forwarded after the
+// latency of its instruction (a load also waits the load-use penalty) and the block ends when all its
+// instructions have completed. Taken branches flush the pipeline (unless the branch model costs them)
+pub struct InOrderPipelineModel {
+    pub stages: u32,
+    pub branch_penalty: u32,
+    pub load_use_penalty: u32,
+}
+
+impl InOrderPipelineModel {
+    // returns the cycles spent in the block and the cycle, relative to the end of the block,
+    // at which every register written in the block is ready
+    fn simulate(
+        &self,
+        block: &Block,
+        entry_state: &HashMap<String, i64>,
+    ) -> (u32, HashMap<String, i64>) {
+        let mut ready = entry_state.clone();
+        let mut issue: i64 = 0; // first cycle in which an instruction can issue
+        let mut end: i64 = 0; // cycle in which every instruction issued so far has completed
+
+        for instruction in &block.instructions {
+            let operands_ready = instruction
+                .regs_read
+                .iter()
+                .filter_map(|reg| ready.get(reg))
+                .max()
+                .copied()
+                .unwrap_or(0);
+
+            let start = issue.max(operands_ready);
+            let latency = instruction.latency as i64;
+            issue = start + latency.min(1);
+            end = end.max(start + latency);
+
+            let result_ready = if is_load(instruction) {
+                start + latency + self.load_use_penalty as i64
+            } else {
+                start + latency
+            };
+            for reg in &instruction.regs_write {
+                ready.insert(reg.clone(), result_ready);
+            }
+        }
+
+        let exit_state = ready
+            .into_iter()
+            .map(|(reg, ready)| (reg, ready - end))
+            .filter(|(_, ready)| *ready > 0)
+            .collect();
+
+        let cycles = u32::try_from(end).unwrap_or_else(|_| {
+            panic!(
+                "The latency of the block at 0x{:x} overflows u32: {end} clock cycles",
+                block.leader
+            )
+        });
+        (cycles, exit_state)
+    }
+}
+
+impl TimingModel for InOrderPipelineModel {
+    fn block_latency(&self, block: &Block, predecessor: Option<&Block>) -> u32 {
+        match predecessor {
+            None => {
+                let (cycles, _) = self.simulate(block, &HashMap::new());
+                // the pipeline has to be filled before the first instruction completes
+                add_latency(cycles, self.stages.saturating_sub(1))
+            }
+            Some(predecessor) => {
+                let (_, pipeline_state) = self.simulate(predecessor, &HashMap::new());
+                let (cycles, _) = self.simulate(block, &pipeline_state);
+                cycles
+            }
+        }
+    }
+
+    // the costs of the branch model replace the flush of the conditional jumps it models
+    fn edge_latency(&self, predecessor: &Block, block: &Block, branch_model: &BranchModel) -> u32 {
+        match branch_model.edge_penalty(predecessor, block) {
+            Some(penalty) => penalty,
+            None if is_taken_edge(predecessor, block) => self.branch_penalty,
+            None => 0,
+        }
+    }
+}
+
+fn is_load(instruction: &Instruction) -> bool {
+    !instruction.regs_write.is_empty()
+        && !instruction.mnemonic.starts_with("lea")
+        && instruction
+            .operands
+            .iter()
+            .any(|op| matches!(op, Operand::Mem { .. }))
+}
+
+// the block is reached by a jump and not by falling through the predecessor
+fn is_taken_edge(predecessor: &Block, block: &Block) -> bool {
+    match &predecessor.exit_jump {
+        Some(ExitJump::ConditionalRelative { not_taken, .. })
+        | Some(ExitJump::ConditionalAbsolute { not_taken, .. })
+        | Some(ExitJump::ConditionalRet { not_taken, .. }) => *not_taken != block.leader,
+        Some(ExitJump::Next(_)) | None => false,
+        Some(_) => true,
+    }
+}
+
+// static prediction of the conditional jumps
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BranchPredictor {
+    None,
+    Taken,
+    NotTaken,
+    BackwardTakenForwardNotTaken,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BranchModel {
+    pub predictor: BranchPredictor,
+    pub misprediction_penalty: u32,
+}
+
+impl BranchModel {
+    // clock cycles of the edge that depend on the direction of the conditional jump ending the predecessor,
+    // None if the predecessor doesn't end with a conditional jump or the model has no cost for it
+    pub fn edge_penalty(&self, predecessor: &Block, block: &Block) -> Option<u32> {
+        let (Some(branch), Some(exit_jump)) =
+            (predecessor.instructions.last(), &predecessor.exit_jump)
+        else {
+            return None;
+        };
+        let (taken, not_taken) = match exit_jump {
+            ExitJump::ConditionalRelative { taken, not_taken }
+            | ExitJump::ConditionalAbsolute { taken, not_taken }
+            | ExitJump::ConditionalRet {
+                ret: taken,
+                not_taken,
+            } => (*taken == block.leader, *not_taken == block.leader),
+            _ => return None,
+        };
+        let has_direction_penalty = branch.taken_penalty > 0 || branch.not_taken_penalty > 0;
+        if self.predictor == BranchPredictor::None && !has_direction_penalty {
+            return None;
+        }
+
+        // the duplicated blocks have fictious leaders, but the instructions keep their addresses
+        let is_backward = block
+            .instructions
+            .first()
+            .is_some_and(|target| target.address <= branch.address);
+        let predicted_taken = match self.predictor {
+            BranchPredictor::None => None,
+            BranchPredictor::Taken => Some(true),
+            BranchPredictor::NotTaken => Some(false),
+            BranchPredictor::BackwardTakenForwardNotTaken => Some(is_backward),
+        };
+        let is_predictable = !matches!(exit_jump, ExitJump::ConditionalRet { .. });
+
+        // a jump to the next instruction reaches the block in both directions
+        [(taken, true), (not_taken, false)]
+            .into_iter()
+            .filter(|(reaches_block, _)| *reaches_block)
+            .map(|(_, is_taken)| {
+                let direction_penalty = if is_taken {
+                    branch.taken_penalty
+                } else {
+                    branch.not_taken_penalty
+                };
+                let misprediction_penalty = match predicted_taken {
+                    Some(predicted_taken) if is_predictable && predicted_taken != is_taken => {
+                        self.misprediction_penalty
+                    }
+                    _ => 0,
+                };
+                add_latency(direction_penalty, misprediction_penalty)
+            })
+            .max()
+    }
+}
+
+thread_local! {
+    static TIMING_MODEL: RefCell<Box<dyn TimingModel>> = RefCell::new(Box::new(SerialModel));
+    static BRANCH_MODEL: RefCell<BranchModel> = const {
+        RefCell::new(BranchModel {
+            predictor: BranchPredictor::None,
+            misprediction_penalty: 0,
+        })
+    };
+}
+
+pub fn set_timing_model(model: Box<dyn TimingModel>) {
+    TIMING_MODEL.with(|timing_model| *timing_model.borrow_mut() = model);
+}
+
+pub fn set_branch_model(model: BranchModel) {
+    BRANCH_MODEL.with(|branch_model| *branch_model.borrow_mut() = model);
+}
+
+pub fn block_latency(block: &Block, predecessor: Option<&Block>) -> u32 {
+    TIMING_MODEL.with(|timing_model| {
+        let timing_model = timing_model.borrow();
+        let latency = timing_model.block_latency(block, predecessor);
+
+        match predecessor {
+            Some(predecessor) => {
+                let branch_model = BRANCH_MODEL.with(|branch_model| *branch_model.borrow());
+                add_latency(
+                    latency,
+                    timing_model.edge_latency(predecessor, block, &branch_model),
+                )
+            }
+            None => latency,
+        }
+    })
+}
+
+pub fn get_timing_model_from_env() -> Box<dyn TimingModel> {
+    let get_var = |key: &str, default: u32| match std::env::var(key) {
+        Ok(value) => match value.parse::<u32>() {
+            Ok(value) => value,
+            Err(_) => panic!("The environment variable {key} is not a valid number"),
+        },
+        Err(_) => default,
+    };
+
+    match std::env::var("TIMING_MODEL").as_deref() {
+        Ok("pipeline") => Box::new(InOrderPipelineModel {
+            stages: get_var("PIPELINE_STAGES", 5),
+            branch_penalty: get_var("PIPELINE_BRANCH_PENALTY", 2),
+            load_use_penalty: get_var("PIPELINE_LOAD_USE_PENALTY", 1),
+        }),
+        Ok("serial") | Err(_) => Box::new(SerialModel),
+        Ok(model) => panic!("Unknown timing model {model}, use serial or pipeline"),
+    }
+}
+
+pub fn get_branch_model_from_env() -> BranchModel {
+    let predictor = match std::env::var("BRANCH_PREDICTOR").as_deref() {
+        Ok("none") | Err(_) => BranchPredictor::None,
+        Ok("taken") => BranchPredictor::Taken,
+        Ok("not-taken") => BranchPredictor::NotTaken,
+        Ok("btfn") => BranchPredictor::BackwardTakenForwardNotTaken,
+        Ok(predictor) => {
+            panic!("Unknown branch predictor {predictor}, use none, taken, not-taken or btfn")
+        }
+    };
+
+    let misprediction_penalty = match std::env::var("BRANCH_MISPREDICTION_PENALTY") {
+        Ok(value) => {
+            match value.parse::<u32>() {
+                Ok(value) => value,
+                Err(_) => {
+                    panic!("The environment variable BRANCH_MISPREDICTION_PENALTY is not a valid number")
+                }
+            }
+        }
+        Err(_) => 0,
+    };
+
+    BranchModel {
+        predictor,
+        misprediction_penalty,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{block, disassemble, x86_64};
+
+    fn pipeline() -> InOrderPipelineModel {
+        InOrderPipelineModel {
+            stages: 5,
+            branch_penalty: 2,
+            load_use_penalty: 1,
+        }
+    }
+
+    // the block of the code, the first instruction with the given latency
+    fn block_of(code: &[u8], first_latency: u32) -> Block {
+        let mut instructions = disassemble(&x86_64(), 0x1000, code);
+        instructions[0].latency = first_latency;
+        block(instructions, None)
+    }
+
+    #[test]
+    fn independent_instructions_overlap() {
+        // imul rax, rbx; add rcx, 1
+        let independent = block_of(&[0x48, 0x0f, 0xaf, 0xc3, 0x48, 0x83, 0xc1, 0x01], 3);
+        // imul rax, rbx; add rax, 1
+        let dependent = block_of(&[0x48, 0x0f, 0xaf, 0xc3, 0x48, 0x83, 0xc0, 0x01], 3);
+
+        assert_eq!(SerialModel.block_latency(&independent, None), 4);
+        assert_eq!(SerialModel.block_latency(&dependent, None), 4);
+        // the pipeline is filled in 4 cycles before the first instruction completes
+        assert_eq!(pipeline().block_latency(&independent, None), 3 + 4);
+        assert_eq!(pipeline().block_latency(&dependent, None), 4 + 4);
+    }
+
+    #[test]
+    fn load_use_stalls_across_blocks() {
+        // mov rax, qword ptr [rbx]; add rcx, rax
+        let load_use = block_of(&[0x48, 0x8b, 0x03, 0x48, 0x01, 0xc1], 1);
+        assert_eq!(pipeline().block_latency(&load_use, None), 3 + 4);
+
+        // the blocks end when their instructions have completed, only the load-use penalty reaches the successor
+        let load = block_of(&[0x48, 0x8b, 0x03], 1);
+        let multiply = block_of(&[0x48, 0x0f, 0xaf, 0xc3], 3);
+        // add rcx, rax
+        let mut user = block_of(&[0x48, 0x01, 0xc1], 1);
+        user.leader = 0x2000;
+        assert_eq!(pipeline().block_latency(&user, Some(&load)), 2);
+        assert_eq!(pipeline().block_latency(&user, Some(&multiply)), 1);
+    }
+}
@@ -0,0 +1,419 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::block::Block;
+use crate::cycle::bound_from_env;
+use crate::formula::{parameters_from_env, Formula};
+use crate::graph::MappedGraph;
+use crate::jump::ExitJump;
+use crate::loops::LoopForest;
+use crate::timing::{BranchModel, TimingModel};
+
+// env variable format: TRACE_FILE=path of an execution trace, TRACE_OFFSET=load address of the code (default 0)
+// the trace is a QEMU log (-d exec,nochain) or a list of "address [timestamp]" lines, e.g. from a hardware trace unit
+// the iterations observed in the trace are checked against the CYCLE_ and CYCLE_TOTAL_ bounds with the PARAM_ values
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct TraceEntry {
+    address: u64,
+    timestamp: Option<u64>, // clock cycles
+}
+
+// the iterations of a loop in the trace and their bound
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObservedBound {
+    pub variable: String, // CYCLE_0x<header> or CYCLE_TOTAL_0x<header>
+    pub observed_iterations: u32,
+    pub annotated_iterations: Formula,
+    pub annotated_value: Option<u64>, // None if a parameter of the bound has no value
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TraceReport {
+    pub visits: BTreeMap<u64, u32>,       // block_leader -> executions
+    pub block_cycles: BTreeMap<u64, u32>, // block_leader -> max observed clock cycles
+    pub bounds: Vec<ObservedBound>,
+    pub unmapped_entries: u32,
+}
+
+pub fn read_trace_from_env(blocks: &BTreeMap<u64, Block>) -> Option<TraceReport> {
+    let path = std::env::var("TRACE_FILE").ok()?;
+    let content = match std::fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) => panic!("Unable to read the trace file {path}: {e}"),
+    };
+
+    let offset = match std::env::var("TRACE_OFFSET") {
+        Ok(value) => match parse_address(&value) {
+            Some(offset) => offset,
+            None => panic!("The environment variable TRACE_OFFSET is not a valid address"),
+        },
+        Err(_) => 0,
+    };
+
+    let entries = content
+        .lines()
+        .filter_map(parse_trace_line)
+        .map(|entry| TraceEntry {
+            address: entry.address.wrapping_sub(offset),
+            ..entry
+        })
+        .collect::<Vec<_>>();
+
+    Some(analyze_trace(blocks, &entries, &parameters_from_env()))
+}
+
+fn parse_address(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let hex = value.strip_prefix("0x").unwrap_or(value);
+    u64::from_str_radix(hex, 16).ok()
+}
+
+// "Trace 0: 0x7f5c [00000000/0000000000401126/00000000/00000000] main" (older QEMU: "Trace 0x7f5c [0000000000401126] main")
+// or "0x401126 1234"
+fn parse_trace_line(line: &str) -> Option<TraceEntry> {
+    let line = line.trim();
+
+    if line.starts_with("Trace") {
+        let fields = line.split_once('[')?.1.split_once(']')?.0;
+        let fields = fields.split('/').collect::<Vec<_>>();
+        let pc = if fields.len() >= 2 {
+            fields[1]
+        } else {
+            fields[0]
+        };
+        return Some(TraceEntry {
+            address: parse_address(pc)?,
+            timestamp: None,
+        });
+    }
+
+    let mut fields = line.split_whitespace();
+    let address = parse_address(fields.next()?)?;
+    let timestamp = fields
+        .next()
+        .and_then(|timestamp| timestamp.parse::<u64>().ok());
+
+    Some(TraceEntry { address, timestamp })
+}
+
+fn analyze_trace(
+    blocks: &BTreeMap<u64, Block>,
+    entries: &[TraceEntry],
+    parameters: &BTreeMap<String, f64>,
+) -> TraceReport {
+    let mut report = TraceReport::default();
+
+    // the leaders of the blocks executed, in order, with the clock cycles when they are known
+    let mut executed = Vec::<(u64, Option<u32>)>::new();
+
+    for (i, entry) in entries.iter().enumerate() {
+        let Some(block) = find_block(blocks, entry.address) else {
+            report.unmapped_entries += 1;
+            continue;
+        };
+
+        // a trace entry starts a straight-line sequence: the following blocks, split only
+        // because they are jump targets, run without a new entry
+        let mut sequence = vec![block.leader];
+        let mut current = block;
+        while let Some(ExitJump::Next(next)) = current.exit_jump {
+            let Some(next_block) = blocks.get(&next) else {
+                break;
+            };
+            if entries.get(i + 1).is_some_and(|e| e.address == next) {
+                break;
+            }
+            sequence.push(next);
+            current = next_block;
+        }
+
+        // the time until the next entry is attributed to the block only if it is alone in the sequence
+        let cycles = match (
+            entry.timestamp,
+            entries.get(i + 1).and_then(|e| e.timestamp),
+        ) {
+            (Some(start), Some(end)) if sequence.len() == 1 && end >= start => {
+                Some(u32::try_from(end - start).unwrap_or_else(|_| {
+                    panic!(
+                        "The measured cycles of the block at 0x{:x} overflow u32: {}",
+                        entry.address,
+                        end - start
+                    )
+                }))
+            }
+            _ => None,
+        };
+
+        for leader in sequence {
+            executed.push((leader, cycles));
+        }
+    }
+
+    for (leader, cycles) in &executed {
+        *report.visits.entry(*leader).or_default() += 1;
+        if let Some(cycles) = cycles {
+            let max_cycles = report.block_cycles.entry(*leader).or_default();
+            *max_cycles = (*max_cycles).max(*cycles);
+        }
+    }
+
+    let executed_leaders = executed
+        .iter()
+        .map(|(leader, _)| *leader)
+        .collect::<Vec<_>>();
+    let bound = |variable: String, observed_iterations: u32, annotated_iterations: Formula| {
+        let annotated_value = annotated_iterations
+            .evaluate(parameters)
+            .map(|_| annotated_iterations.value(parameters));
+        ObservedBound {
+            variable,
+            observed_iterations,
+            annotated_iterations,
+            annotated_value,
+        }
+    };
+    let loop_forest = find_loops(blocks);
+    for natural_loop in loop_forest.loops.values() {
+        let header = natural_loop.header;
+        let variable = format!("CYCLE_0x{header:x}");
+        let annotated_iterations = bound_from_env(&variable).unwrap_or(Formula::constant(1.0));
+        let observed_iterations =
+            count_iterations(&executed_leaders, header, &natural_loop.blocks, None);
+        report
+            .bounds
+            .push(bound(variable, observed_iterations, annotated_iterations));
+
+        // the total iterations for each entry of the outer loop
+        let variable = format!("CYCLE_TOTAL_0x{header:x}");
+        if let (Some(parent), Some(annotated_iterations)) =
+            (natural_loop.parent, bound_from_env(&variable))
+        {
+            let outer_blocks = &loop_forest.loops[&parent].blocks;
+            let observed_iterations = count_iterations(
+                &executed_leaders,
+                header,
+                &natural_loop.blocks,
+                Some(outer_blocks),
+            );
+            report
+                .bounds
+                .push(bound(variable, observed_iterations, annotated_iterations));
+        }
+    }
+
+    report
+}
+
+fn find_block(blocks: &BTreeMap<u64, Block>, address: u64) -> Option<&Block> {
+    let (_, block) = blocks.range(..=address).next_back()?;
+    let last = block.instructions.last()?;
+    (address < last.address + last.size as u64).then_some(block)
+}
+
+// the loops of the blocks, with the headers of the CYCLE_ env vars. The irreducible cycles are split
+// only later by the analysis, they have no header and are not checked
+fn find_loops(blocks: &BTreeMap<u64, Block>) -> LoopForest {
+    let mut graph = MappedGraph::new();
+    for block in blocks.values() {
+        graph.add_node(block.clone());
+        // a call continues at the return address and a return ends the path, the recursions are not loops
+        let targets = match block.exit_jump {
+            Some(ExitJump::Call(_, ret)) => vec![ret],
+            Some(ExitJump::Ret(_)) => vec![],
+            Some(ExitJump::ConditionalRet { not_taken, .. }) => vec![not_taken],
+            _ => block.get_targets(),
+        };
+        for target in targets {
+            if let Some(target_block) = blocks.get(&target) {
+                graph.add_edge(block.clone(), target_block.clone(), 0);
+            }
+        }
+    }
+
+    LoopForest::from_graph(&graph)
+}
+
+// max number of times the header is re-entered without leaving the loop, summed over
+// the entries of the loop during one entry of the outer loop if given
+fn count_iterations(
+    executed: &[u64],
+    header: u64,
+    members: &BTreeSet<u64>,
+    outer_members: Option<&BTreeSet<u64>>,
+) -> u32 {
+    let mut max_iterations = 0;
+    let mut iterations = 0;
+    let mut header_visits = 0;
+
+    for leader in executed {
+        if !outer_members
+            .map_or(members, |outer| outer)
+            .contains(leader)
+        {
+            iterations = 0;
+        }
+        if !members.contains(leader) {
+            header_visits = 0;
+        } else if *leader == header {
+            // the first visit of the header enters the loop, the last one exits it
+            if header_visits > 0 {
+                iterations += 1;
+                max_iterations = max_iterations.max(iterations);
+            }
+            header_visits += 1;
+        }
+    }
+
+    max_iterations
+}
+
+// the observed clock cycles of the blocks replace the ones of the static model
+pub struct MeasuredModel {
+    pub block_cycles: HashMap<u64, u32>, // real block leader -> max observed clock cycles
+    pub fallback: Box<dyn TimingModel>,
+}
+
+impl TimingModel for MeasuredModel {
+    fn block_latency(&self, block: &Block, predecessor: Option<&Block>) -> u32 {
+        // the duplicated blocks have fictious leaders, but the instructions keep their addresses
+        let real_leader = block.instructions.first().map(|i| i.address);
+        match real_leader.and_then(|leader| self.block_cycles.get(&leader)) {
+            Some(cycles) => *cycles,
+            None => self.fallback.block_latency(block, predecessor),
+        }
+    }
+
+    // the measured clock cycles of the predecessor run until the block is entered, they include the jump
+    fn edge_latency(&self, predecessor: &Block, block: &Block, branch_model: &BranchModel) -> u32 {
+        let real_leader = predecessor.instructions.first().map(|i| i.address);
+        match real_leader.and_then(|leader| self.block_cycles.get(&leader)) {
+            Some(_) => 0,
+            None => self.fallback.edge_latency(predecessor, block, branch_model),
+        }
+    }
+}
+
+impl std::fmt::Display for TraceReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Trace: {} blocks executed, {} with measured cycles, {} entries outside the code",
+            self.visits.len(),
+            self.block_cycles.len(),
+            self.unmapped_entries
+        )?;
+        for bound in &self.bounds {
+            let ObservedBound {
+                variable,
+                observed_iterations,
+                annotated_iterations,
+                annotated_value,
+            } = bound;
+            match annotated_value {
+                Some(value) if u64::from(*observed_iterations) > *value => writeln!(
+                    f,
+                    "WARNING: {variable} observed with {observed_iterations} iterations, more than the {annotated_iterations} annotated"
+                )?,
+                _ => writeln!(
+                    f,
+                    "{variable}: observed {observed_iterations} iterations, annotated {annotated_iterations}"
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{block, disassemble, x86_64};
+
+    #[test]
+    fn parse_trace_lines() {
+        let entry = |address, timestamp| Some(TraceEntry { address, timestamp });
+        assert_eq!(
+            parse_trace_line("Trace 0: 0x7f5c [00000000/0000000000401126/00000000/00000000] main"),
+            entry(0x401126, None)
+        );
+        assert_eq!(
+            parse_trace_line("Trace 0x7f5c [0000000000401126] main"),
+            entry(0x401126, None)
+        );
+        assert_eq!(
+            parse_trace_line("  0x401126 1234"),
+            entry(0x401126, Some(1234))
+        );
+        assert_eq!(parse_trace_line("401126"), entry(0x401126, None));
+        assert_eq!(parse_trace_line("0x401126 late"), entry(0x401126, None));
+        assert_eq!(parse_trace_line("IN: main"), None);
+        assert_eq!(parse_trace_line(""), None);
+    }
+
+    #[test]
+    fn nested_loop_iterations_and_totals() {
+        // entry -> outer header -> inner header <-> inner body, inner header -> outer latch -> outer header -> exit
+        let nop = |address| disassemble(&x86_64(), address, &[0x90]);
+        let conditional =
+            |taken, not_taken| Some(ExitJump::ConditionalRelative { taken, not_taken });
+        let blocks = [
+            block(nop(0x7000), Some(ExitJump::Next(0x7010))),
+            block(nop(0x7010), conditional(0x7020, 0x7050)),
+            block(nop(0x7020), conditional(0x7030, 0x7040)),
+            block(nop(0x7030), Some(ExitJump::UnconditionalRelative(0x7020))),
+            block(nop(0x7040), Some(ExitJump::UnconditionalRelative(0x7010))),
+            block(nop(0x7050), None),
+        ]
+        .into_iter()
+        .map(|block| (block.leader, block))
+        .collect::<BTreeMap<_, _>>();
+
+        // 2 outer iterations with 2 and 3 inner iterations
+        let trace = "0x7000 0\n0x7010 4\n0x7020 5\n0x7030 6\n0x7020\n0x7030\n0x7020\n0x7040\n\
+            0x7010\n0x7020\n0x7030\n0x7020\n0x7030\n0x7020\n0x7030\n0x7020\n0x7040\n0x7010\n0x7050\n0x9000";
+        let entries = trace
+            .lines()
+            .filter_map(parse_trace_line)
+            .collect::<Vec<_>>();
+
+        std::env::set_var("CYCLE_0x7020", "n");
+        std::env::set_var("CYCLE_TOTAL_0x7020", "n + 1");
+        let parameters = BTreeMap::from([("n".to_string(), 3.0)]);
+        let report = analyze_trace(&blocks, &entries, &parameters);
+
+        assert_eq!(report.unmapped_entries, 1);
+        assert_eq!(report.visits[&0x7020], 7);
+        assert_eq!(
+            report.block_cycles,
+            BTreeMap::from([(0x7000, 4), (0x7010, 1), (0x7020, 1)])
+        );
+        let bounds = report
+            .bounds
+            .iter()
+            .map(|bound| {
+                (
+                    bound.variable.as_str(),
+                    bound.observed_iterations,
+                    bound.annotated_value,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            bounds,
+            [
+                ("CYCLE_0x7010", 2, Some(1)),
+                ("CYCLE_0x7020", 3, Some(3)),
+                ("CYCLE_TOTAL_0x7020", 5, Some(4)),
+            ]
+        );
+
+        let output = report.to_string();
+        assert!(output.contains("WARNING: CYCLE_0x7010 observed with 2 iterations"));
+        assert!(output.contains("CYCLE_0x7020: observed 3 iterations, annotated n"));
+        assert!(output.contains("WARNING: CYCLE_TOTAL_0x7020 observed with 5 iterations"));
+
+        // a bound with a parameter without value is not checked
+        let report = analyze_trace(&blocks, &entries, &BTreeMap::new());
+        assert_eq!(report.bounds[1].annotated_value, None);
+    }
+}
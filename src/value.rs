@@ -0,0 +1,1230 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use capstone::{Arch, Mode};
+use petgraph::Direction::{Incoming, Outgoing};
+
+use crate::arch::ArchMode;
+use crate::block::Block;
+use crate::graph::MappedGraph;
+use crate::instruction::{Instruction, Operand};
+use crate::jump::ExitJump;
+use crate::memory::{get_memory_operands, MemoryAccessKind};
+
+// interval analysis of the registers and stack slots (x86 and RISC-V, the other architectures only lose
+// the registers they write). The values are signed numbers or offsets from the stack pointer at the
+// analysis entry. The 32-bit results (RISC-V *w instructions, RV32) keep signed 32-bit values, a result
+// that would wrap around is unknown. The x86-64 32-bit writes clear the upper half of the register, the
+// full register keeps the zero-extended value and the 32-bit reads give it back signed.
+// The called functions follow the calling convention: they keep the stack pointer, the callee-saved
+// registers and the frame of the caller, unless it passes them a stack address.
+// The values before every instruction are written in values.txt
+
+const WIDENING_DELAY: u32 = 3; // joins at a loop head before widening
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interval {
+    pub min: i64, // i64::MIN is unbounded
+    pub max: i64, // i64::MAX is unbounded
+}
+
+impl Interval {
+    pub const TOP: Interval = Interval {
+        min: i64::MIN,
+        max: i64::MAX,
+    };
+
+    pub fn new(min: i64, max: i64) -> Self {
+        Interval { min, max }
+    }
+
+    pub fn constant(value: i64) -> Self {
+        Interval::new(value, value)
+    }
+
+    pub fn as_constant(&self) -> Option<i64> {
+        (self.min == self.max).then_some(self.min)
+    }
+
+    pub fn is_bounded(&self) -> bool {
+        self.min != i64::MIN && self.max != i64::MAX
+    }
+
+    fn is_non_negative(&self) -> bool {
+        self.min >= 0
+    }
+
+    fn join(&self, other: &Interval) -> Self {
+        Interval::new(self.min.min(other.min), self.max.max(other.max))
+    }
+
+    // the bounds still moving are dropped
+    fn widen(&self, other: &Interval) -> Self {
+        Interval::new(
+            if other.min < self.min {
+                i64::MIN
+            } else {
+                self.min
+            },
+            if other.max > self.max {
+                i64::MAX
+            } else {
+                self.max
+            },
+        )
+    }
+
+    fn add(&self, other: &Interval) -> Self {
+        Interval::new(
+            add_bound(self.min, other.min, i64::MIN),
+            add_bound(self.max, other.max, i64::MAX),
+        )
+    }
+
+    fn neg(&self) -> Self {
+        Interval::new(neg_bound(self.max), neg_bound(self.min))
+    }
+
+    fn sub(&self, other: &Interval) -> Self {
+        self.add(&other.neg())
+    }
+
+    fn mul(&self, other: &Interval) -> Self {
+        if self.as_constant() == Some(0) || other.as_constant() == Some(0) {
+            return Interval::constant(0);
+        }
+        if !self.is_bounded() || !other.is_bounded() {
+            return Interval::TOP;
+        }
+
+        let products = [
+            self.min as i128 * other.min as i128,
+            self.min as i128 * other.max as i128,
+            self.max as i128 * other.min as i128,
+            self.max as i128 * other.max as i128,
+        ];
+        let min = products.iter().min().unwrap();
+        let max = products.iter().max().unwrap();
+        Interval::new(
+            i64::try_from(*min).unwrap_or(i64::MIN),
+            i64::try_from(*max).unwrap_or(i64::MAX),
+        )
+    }
+
+    fn shift_left(&self, amount: i64) -> Self {
+        match amount {
+            0..=62 => self.mul(&Interval::constant(1 << amount)),
+            _ => Interval::TOP,
+        }
+    }
+
+    // arithmetic shift, monotone like the division
+    fn shift_right(&self, amount: i64) -> Self {
+        let shift = |bound: i64| match bound {
+            i64::MIN | i64::MAX => bound,
+            _ => bound >> amount,
+        };
+        match amount {
+            0..=63 => Interval::new(shift(self.min), shift(self.max)),
+            _ => Interval::TOP,
+        }
+    }
+}
+
+fn add_bound(a: i64, b: i64, infinity: i64) -> i64 {
+    if a == infinity || b == infinity {
+        infinity
+    } else {
+        a.checked_add(b).unwrap_or(infinity)
+    }
+}
+
+fn neg_bound(bound: i64) -> i64 {
+    match bound {
+        i64::MIN => i64::MAX,
+        i64::MAX => i64::MIN,
+        _ => -bound,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Value {
+    Number(Interval),
+    StackAddress(Interval), // offset from the stack pointer at the analysis entry
+}
+
+impl Value {
+    pub const TOP: Value = Value::Number(Interval::TOP);
+
+    pub fn constant(value: i64) -> Self {
+        Value::Number(Interval::constant(value))
+    }
+
+    fn join(&self, other: &Value) -> Value {
+        match (self, other) {
+            (Value::Number(a), Value::Number(b)) => Value::Number(a.join(b)),
+            (Value::StackAddress(a), Value::StackAddress(b)) => Value::StackAddress(a.join(b)),
+            _ => Value::TOP,
+        }
+    }
+
+    fn widen(&self, other: &Value) -> Value {
+        match (self, other) {
+            (Value::Number(a), Value::Number(b)) => Value::Number(a.widen(b)),
+            (Value::StackAddress(a), Value::StackAddress(b)) => Value::StackAddress(a.widen(b)),
+            _ => Value::TOP,
+        }
+    }
+
+    pub fn add(&self, other: &Value) -> Value {
+        match (self, other) {
+            (Value::Number(a), Value::Number(b)) => Value::Number(a.add(b)),
+            (Value::StackAddress(a), Value::Number(b))
+            | (Value::Number(b), Value::StackAddress(a)) => Value::StackAddress(a.add(b)),
+            _ => Value::TOP,
+        }
+    }
+
+    pub fn sub(&self, other: &Value) -> Value {
+        match (self, other) {
+            (Value::Number(a), Value::Number(b)) => Value::Number(a.sub(b)),
+            (Value::StackAddress(a), Value::Number(b)) => Value::StackAddress(a.sub(b)),
+            (Value::StackAddress(a), Value::StackAddress(b)) => Value::Number(a.sub(b)),
+            _ => Value::TOP,
+        }
+    }
+
+    fn map_number(&self, f: impl Fn(&Interval) -> Interval) -> Value {
+        match self {
+            Value::Number(a) => Value::Number(f(a)),
+            Value::StackAddress(_) => Value::TOP,
+        }
+    }
+
+    fn mul(&self, other: &Value) -> Value {
+        match (self, other) {
+            (Value::Number(a), Value::Number(b)) => Value::Number(a.mul(b)),
+            _ => Value::TOP,
+        }
+    }
+
+    fn and(&self, other: &Value) -> Value {
+        match (self, other) {
+            (Value::Number(a), Value::Number(b)) => {
+                if let (Some(a), Some(b)) = (a.as_constant(), b.as_constant()) {
+                    return Value::constant(a & b);
+                }
+                // a non-negative operand bounds the result
+                let bound = [a, b]
+                    .iter()
+                    .filter(|operand| operand.is_non_negative())
+                    .map(|operand| operand.max)
+                    .min();
+                match bound {
+                    Some(bound) => Value::Number(Interval::new(0, bound)),
+                    None => Value::TOP,
+                }
+            }
+            // stack alignment, e.g. and rsp, -16
+            (Value::StackAddress(a), Value::Number(mask))
+            | (Value::Number(mask), Value::StackAddress(a)) => match mask.as_constant() {
+                Some(mask) if mask < 0 => {
+                    Value::StackAddress(Interval::new(add_bound(a.min, mask + 1, i64::MIN), a.max))
+                }
+                _ => Value::TOP,
+            },
+            _ => Value::TOP,
+        }
+    }
+
+    // zero extension of the low bits
+    fn zero_extend(&self, bits: u64) -> Value {
+        let max = if bits >= 63 {
+            i64::MAX
+        } else {
+            (1 << bits) - 1
+        };
+        match self {
+            Value::Number(a) if a.is_non_negative() && a.max <= max => *self,
+            _ => Value::Number(Interval::new(0, max)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Location {
+    Register(String),
+    StackSlot { offset: i64, size: u64 }, // offset from the stack pointer at the analysis entry, bytes
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ValueState {
+    pub values: BTreeMap<Location, Value>, // the missing locations are unknown
+}
+
+impl ValueState {
+    pub fn get(&self, location: &Location) -> Value {
+        self.values.get(location).copied().unwrap_or(Value::TOP)
+    }
+
+    fn set(&mut self, location: Location, value: Value) {
+        if value == Value::TOP {
+            self.values.remove(&location);
+        } else {
+            self.values.insert(location, value);
+        }
+    }
+
+    fn combine(&self, other: &ValueState, f: impl Fn(&Value, &Value) -> Value) -> ValueState {
+        let mut state = ValueState::default();
+        for (location, value) in &self.values {
+            if let Some(other_value) = other.values.get(location) {
+                state.set(location.clone(), f(value, other_value));
+            }
+        }
+        state
+    }
+
+    fn join(&self, other: &ValueState) -> ValueState {
+        self.combine(other, Value::join)
+    }
+
+    fn widen(&self, other: &ValueState) -> ValueState {
+        self.combine(other, Value::widen)
+    }
+
+    // forget the stack slots overlapping the bytes from start to end (exclusive)
+    fn forget_stack(&mut self, start: i64, end: i64) {
+        self.values.retain(|location, _| match location {
+            Location::StackSlot { offset, size } => {
+                offset.saturating_add(*size as i64) <= start || *offset >= end
+            }
+            Location::Register(_) => true,
+        });
+    }
+}
+
+// the transfer functions of an architecture
+#[derive(Debug, Clone, Copy)]
+struct Interpreter {
+    arch: Arch,
+    word_size: u64, // bytes
+}
+
+const X86_CALLER_SAVED: &[&str] = &["rax", "rcx", "rdx", "rsi", "rdi", "r8", "r9", "r10", "r11"];
+const X86_ARGUMENTS: &[&str] = &["rdi", "rsi", "rdx", "rcx", "r8", "r9"];
+const RISCV_CALLER_SAVED: &[&str] = &[
+    "ra", "t0", "t1", "t2", "t3", "t4", "t5", "t6", "a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7",
+];
+const RISCV_ARGUMENTS: &[&str] = &["a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7"];
+
+// 64-bit register, 32-bit, 16-bit and 8-bit parts
+const X86_LEGACY_REGISTERS: &[(&str, &str, &str, &[&str])] = &[
+    ("rax", "eax", "ax", &["al", "ah"]),
+    ("rbx", "ebx", "bx", &["bl", "bh"]),
+    ("rcx", "ecx", "cx", &["cl", "ch"]),
+    ("rdx", "edx", "dx", &["dl", "dh"]),
+    ("rsi", "esi", "si", &["sil"]),
+    ("rdi", "edi", "di", &["dil"]),
+    ("rbp", "ebp", "bp", &["bpl"]),
+    ("rsp", "esp", "sp", &["spl"]),
+];
+
+impl Interpreter {
+    fn new(arch_mode: &ArchMode) -> Self {
+        let word_size = match (arch_mode.arch, arch_mode.mode) {
+            (Arch::ARM64, _) | (_, Mode::Mode64) | (_, Mode::RiscV64) => 8,
+            _ => 4,
+        };
+        Interpreter {
+            arch: arch_mode.arch,
+            word_size,
+        }
+    }
+
+    fn stack_pointer(&self) -> &'static str {
+        match self.arch {
+            Arch::X86 => "rsp",
+            _ => "sp",
+        }
+    }
+
+    // the full register and the bits accessed
+    fn register(&self, reg: &str) -> (String, u64) {
+        if self.arch != Arch::X86 {
+            return (reg.to_string(), 64);
+        }
+
+        for (r64, r32, r16, r8) in X86_LEGACY_REGISTERS {
+            if reg == *r64 {
+                return (r64.to_string(), 64);
+            } else if reg == *r32 {
+                return (r64.to_string(), 32);
+            } else if reg == *r16 {
+                return (r64.to_string(), 16);
+            } else if r8.contains(&reg) {
+                return (r64.to_string(), 8);
+            }
+        }
+
+        // r8d, r8w, r8b
+        if let Some(number) = reg.strip_prefix('r') {
+            for (suffix, bits) in [("d", 32), ("w", 16), ("b", 8)] {
+                if let Some(number) = number.strip_suffix(suffix) {
+                    if number.parse::<u32>().is_ok() {
+                        return (format!("r{number}"), bits);
+                    }
+                }
+            }
+        }
+
+        (reg.to_string(), 64)
+    }
+
+    fn read_register(&self, state: &ValueState, reg: &str, instruction: &Instruction) -> Value {
+        match reg {
+            "zero" | "x0" => return Value::constant(0),
+            // pc relative addresses are relative to the next instruction
+            "rip" => {
+                return Value::constant((instruction.address + instruction.size as u64) as i64)
+            }
+            _ => {}
+        }
+
+        let (reg, bits) = self.register(reg);
+        let value = state.get(&Location::Register(reg));
+        match value {
+            _ if bits == 64 => value,
+            Value::Number(a) if a.is_non_negative() && a.max < 1 << (bits - 1) => value,
+            Value::Number(a)
+                if bits == 32 && a.min >= i32::MIN as i64 && a.max <= i32::MAX as i64 =>
+            {
+                value
+            }
+            // a zero-extended negative 32-bit value
+            Value::Number(a)
+                if bits == 32 && a.min > i32::MAX as i64 && a.max <= u32::MAX as i64 =>
+            {
+                Value::Number(a.sub(&Interval::constant(1 << 32)))
+            }
+            _ => Value::TOP,
+        }
+    }
+
+    fn write_register(&self, state: &mut ValueState, reg: &str, value: Value) {
+        if matches!(reg, "zero" | "x0") {
+            return;
+        }
+        let (reg, bits) = self.register(reg);
+        let value = match bits {
+            // the 32-bit writes of x86-64 zero-extend the low 32 bits of the result
+            32 if self.arch == Arch::X86 && self.word_size == 8 => match value {
+                Value::Number(a) if a.min >= -(1 << 32) && a.max < 0 => {
+                    Value::Number(a.add(&Interval::constant(1 << 32)))
+                }
+                _ => value.zero_extend(32),
+            },
+            // the writes of 8 and 16 bits keep the other bits of the register
+            8 | 16 => Value::TOP,
+            _ => self.fit(value, bits.min(8 * self.word_size)),
+        };
+        state.set(Location::Register(reg), value);
+    }
+
+    // the value written to the given bits, TOP if it is out of their signed range and would wrap around
+    fn fit(&self, value: Value, bits: u64) -> Value {
+        match value {
+            _ if bits >= 64 => value,
+            Value::Number(a) if a.min >= -(1 << (bits - 1)) && a.max < 1 << (bits - 1) => value,
+            Value::StackAddress(_) if bits >= 8 * self.word_size => value,
+            _ => Value::TOP,
+        }
+    }
+
+    fn address(&self, state: &ValueState, instruction: &Instruction, operand: &Operand) -> Value {
+        let Operand::Mem {
+            base,
+            index,
+            scale,
+            disp,
+        } = operand
+        else {
+            return Value::TOP;
+        };
+
+        let base = match base {
+            Some(base) => self.read_register(state, base, instruction),
+            None => Value::constant(0),
+        };
+        let index = match index {
+            Some(index) => self
+                .read_register(state, index, instruction)
+                .mul(&Value::constant(*scale as i64)),
+            None => Value::constant(0),
+        };
+        base.add(&index).add(&Value::constant(*disp))
+    }
+
+    fn load(&self, state: &ValueState, address: Value, size: u64) -> Value {
+        match address {
+            Value::StackAddress(offset) => match offset.as_constant() {
+                Some(offset) => state.get(&Location::StackSlot { offset, size }),
+                None => Value::TOP,
+            },
+            Value::Number(_) => Value::TOP,
+        }
+    }
+
+    fn store(&self, state: &mut ValueState, address: Value, value: Value, size: u64) {
+        let value = self.fit(value, 8 * size);
+        match address {
+            Value::StackAddress(offset) => {
+                state.forget_stack(offset.min, add_bound(offset.max, size as i64, i64::MAX));
+                if let Some(offset) = offset.as_constant() {
+                    state.set(Location::StackSlot { offset, size }, value);
+                }
+            }
+            // a known address is not in the stack, an unknown one can be anywhere
+            Value::Number(address) if address.is_bounded() => {}
+            Value::Number(_) => state.forget_stack(i64::MIN, i64::MAX),
+        }
+    }
+
+    fn read(
+        &self,
+        state: &ValueState,
+        instruction: &Instruction,
+        operand: &Operand,
+        size: u64,
+    ) -> Value {
+        match operand {
+            Operand::Reg(reg) => self.read_register(state, reg, instruction),
+            Operand::Imm(imm) => Value::constant(*imm),
+            Operand::Mem { .. } => {
+                let address = self.address(state, instruction, operand);
+                self.load(state, address, size)
+            }
+        }
+    }
+
+    fn write(
+        &self,
+        state: &mut ValueState,
+        instruction: &Instruction,
+        operand: &Operand,
+        value: Value,
+        size: u64,
+    ) {
+        match operand {
+            Operand::Reg(reg) => self.write_register(state, reg, value),
+            Operand::Imm(_) => {}
+            Operand::Mem { .. } => {
+                let address = self.address(state, instruction, operand);
+                self.store(state, address, value, size);
+            }
+        }
+    }
+
+    fn push(&self, state: &mut ValueState, instruction: &Instruction, value: Value) {
+        let stack_pointer = self.stack_pointer();
+        let address = self
+            .read_register(state, stack_pointer, instruction)
+            .sub(&Value::constant(self.word_size as i64));
+        self.write_register(state, stack_pointer, address);
+        self.store(state, address, value, self.word_size);
+    }
+
+    fn pop(&self, state: &mut ValueState, instruction: &Instruction) -> Value {
+        let stack_pointer = self.stack_pointer();
+        let address = self.read_register(state, stack_pointer, instruction);
+        let value = self.load(state, address, self.word_size);
+        self.write_register(
+            state,
+            stack_pointer,
+            address.add(&Value::constant(self.word_size as i64)),
+        );
+        value
+    }
+
+    // a call to code outside the analysis loses the caller-saved registers and the stack below the
+    // stack pointer, or all the stack if an argument points to it
+    fn external_call(&self, state: &mut ValueState, instruction: &Instruction) {
+        let arguments = match self.arch {
+            Arch::X86 => X86_ARGUMENTS,
+            Arch::RISCV => RISCV_ARGUMENTS,
+            _ => &[],
+        };
+
+        let stack_argument = arguments.iter().any(|reg| {
+            matches!(
+                self.read_register(state, reg, instruction),
+                Value::StackAddress(_)
+            )
+        });
+        match self.read_register(state, self.stack_pointer(), instruction) {
+            Value::StackAddress(offset) if !stack_argument => {
+                state.forget_stack(i64::MIN, offset.min)
+            }
+            _ => state.forget_stack(i64::MIN, i64::MAX),
+        }
+
+        for reg in self.caller_saved() {
+            self.write_register(state, reg, Value::TOP);
+        }
+    }
+
+    fn caller_saved(&self) -> &'static [&'static str] {
+        match self.arch {
+            Arch::X86 => X86_CALLER_SAVED,
+            Arch::RISCV => RISCV_CALLER_SAVED,
+            _ => &[],
+        }
+    }
+
+    // the state after a call to code of the graph: the callee gives the caller-saved registers,
+    // the rest is kept from the call site as the calling convention requires
+    fn returned(
+        &self,
+        caller: &ValueState,
+        callee: &ValueState,
+        instruction: &Instruction,
+    ) -> ValueState {
+        let mut state = caller.clone();
+        self.external_call(&mut state, instruction);
+        for reg in self.caller_saved() {
+            let location = Location::Register(reg.to_string());
+            state.set(location.clone(), callee.get(&location));
+        }
+        state
+    }
+
+    // the registers and memory operands written by the instruction become unknown
+    fn clobber(&self, state: &mut ValueState, instruction: &Instruction) {
+        for (kind, operand) in get_memory_operands(instruction) {
+            if let (MemoryAccessKind::Write, Some(operand)) = (kind, operand) {
+                let address = self.address(state, instruction, operand);
+                self.store(state, address, Value::TOP, self.word_size);
+            }
+        }
+        for reg in &instruction.regs_write {
+            self.write_register(state, reg, Value::TOP);
+        }
+    }
+
+    // internal_call: the instruction calls code of the graph, that continues the analysis
+    fn step(&self, state: &mut ValueState, instruction: &Instruction, internal_call: bool) {
+        match self.arch {
+            Arch::X86 => self.step_x86(state, instruction, internal_call),
+            Arch::RISCV => self.step_riscv(state, instruction, internal_call),
+            _ => self.clobber(state, instruction),
+        }
+    }
+
+    fn step_x86(&self, state: &mut ValueState, instruction: &Instruction, internal_call: bool) {
+        let next_address = (instruction.address + instruction.size as u64) as i64;
+        let size = x86_memory_size(&instruction.op_str).unwrap_or(self.word_size);
+        let read =
+            |state: &ValueState, operand: &Operand| self.read(state, instruction, operand, size);
+
+        match (
+            instruction.mnemonic.as_str(),
+            instruction.operands.as_slice(),
+        ) {
+            ("mov" | "movabs" | "movsx" | "movsxd", [destination, source]) => {
+                let value = read(state, source);
+                self.write(state, instruction, destination, value, size);
+            }
+            ("movzx", [destination, source]) => {
+                let bits = match source {
+                    Operand::Reg(reg) => self.register(reg).1,
+                    _ => size * 8,
+                };
+                let value = read(state, source).zero_extend(bits);
+                self.write(state, instruction, destination, value, size);
+            }
+            ("lea", [destination, source]) => {
+                let value = self.address(state, instruction, source);
+                self.write(state, instruction, destination, value, size);
+            }
+            ("xor" | "sub", [destination, source]) if destination == source => {
+                self.write(state, instruction, destination, Value::constant(0), size);
+            }
+            (
+                "add" | "sub" | "imul" | "and" | "shl" | "sal" | "sar" | "shr",
+                [destination, source],
+            ) => {
+                let a = read(state, destination);
+                let b = read(state, source);
+                let value = binary_operation(&instruction.mnemonic, &a, &b);
+                self.write(state, instruction, destination, value, size);
+            }
+            ("imul", [destination, a, b]) => {
+                let value = read(state, a).mul(&read(state, b));
+                self.write(state, instruction, destination, value, size);
+            }
+            ("inc" | "dec" | "neg", [destination]) => {
+                let a = read(state, destination);
+                let value = match instruction.mnemonic.as_str() {
+                    "inc" => a.add(&Value::constant(1)),
+                    "dec" => a.sub(&Value::constant(1)),
+                    _ => a.map_number(Interval::neg),
+                };
+                self.write(state, instruction, destination, value, size);
+            }
+            ("xchg", [a, b]) => {
+                let (value_a, value_b) = (read(state, a), read(state, b));
+                self.write(state, instruction, a, value_b, size);
+                self.write(state, instruction, b, value_a, size);
+            }
+            ("push", [source]) => {
+                let value = self.read(state, instruction, source, self.word_size);
+                self.push(state, instruction, value);
+            }
+            ("pop", [destination]) => {
+                let value = self.pop(state, instruction);
+                self.write(state, instruction, destination, value, self.word_size);
+            }
+            ("call", _) if internal_call => {
+                self.push(state, instruction, Value::constant(next_address));
+            }
+            ("call", _) => self.external_call(state, instruction),
+            ("ret", operands) => {
+                self.pop(state, instruction);
+                if let [Operand::Imm(bytes)] = operands {
+                    let stack_pointer = self.read_register(state, "rsp", instruction);
+                    self.write_register(state, "rsp", stack_pointer.add(&Value::constant(*bytes)));
+                }
+            }
+            ("leave", []) => {
+                let frame_pointer = self.read_register(state, "rbp", instruction);
+                self.write_register(state, "rsp", frame_pointer);
+                let value = self.pop(state, instruction);
+                self.write_register(state, "rbp", value);
+            }
+            // sign extension of the low half of rax
+            ("cdqe" | "cwde", []) => {
+                let (source, destination) = match instruction.mnemonic.as_str() {
+                    "cdqe" => ("eax", "rax"),
+                    _ => ("ax", "eax"),
+                };
+                let value = self.read_register(state, source, instruction);
+                self.write_register(state, destination, value);
+            }
+            ("cdq" | "cqo", []) => {
+                let (source, destination) = match instruction.mnemonic.as_str() {
+                    "cdq" => ("eax", "edx"),
+                    _ => ("rax", "rdx"),
+                };
+                let sign = match self.read_register(state, source, instruction) {
+                    Value::Number(a) if a.is_non_negative() => Value::constant(0),
+                    Value::Number(a) if a.max < 0 => Value::constant(-1),
+                    _ => Value::Number(Interval::new(-1, 0)),
+                };
+                self.write_register(state, destination, sign);
+            }
+            _ => self.clobber(state, instruction),
+        }
+    }
+
+    fn step_riscv(&self, state: &mut ValueState, instruction: &Instruction, internal_call: bool) {
+        let mnemonic = instruction.mnemonic.trim_start_matches("c.");
+        let read = |state: &ValueState, operand: &Operand| {
+            self.read(state, instruction, operand, self.word_size)
+        };
+        let sext32 = |value: i64| value as i32 as i64;
+        // the *w instructions compute 32-bit results of RV64
+        let fit_word = |value: Value| {
+            if mnemonic.ends_with('w') {
+                self.fit(value, 32)
+            } else {
+                value
+            }
+        };
+
+        match (mnemonic, instruction.operands.as_slice()) {
+            ("li", [Operand::Reg(destination), Operand::Imm(value)]) => {
+                self.write_register(state, destination, Value::constant(*value));
+            }
+            ("mv" | "sext.w", [Operand::Reg(destination), source]) => {
+                let value = fit_word(read(state, source));
+                self.write_register(state, destination, value);
+            }
+            ("neg" | "negw", [Operand::Reg(destination), source]) => {
+                let value = fit_word(read(state, source).map_number(Interval::neg));
+                self.write_register(state, destination, value);
+            }
+            ("lui", [Operand::Reg(destination), Operand::Imm(value)]) => {
+                self.write_register(state, destination, Value::constant(sext32(value << 12)));
+            }
+            ("auipc", [Operand::Reg(destination), Operand::Imm(value)]) => {
+                let address = (instruction.address as i64).wrapping_add(sext32(value << 12));
+                self.write_register(state, destination, Value::constant(address));
+            }
+            (
+                "add" | "addi" | "addw" | "addiw" | "sub" | "subw" | "mul" | "mulw" | "and"
+                | "andi" | "sll" | "slli" | "sllw" | "slliw" | "sra" | "srai" | "sraw" | "sraiw"
+                | "srl" | "srli" | "srlw" | "srliw",
+                [Operand::Reg(destination), a, b],
+            ) => {
+                let value = fit_word(binary_operation(mnemonic, &read(state, a), &read(state, b)));
+                self.write_register(state, destination, value);
+            }
+            (
+                "seqz" | "snez" | "sltz" | "sgtz" | "slt" | "slti" | "sltu" | "sltiu",
+                [Operand::Reg(destination), ..],
+            ) => {
+                self.write_register(state, destination, Value::Number(Interval::new(0, 1)));
+            }
+            (
+                "lb" | "lh" | "lw" | "ld" | "lbu" | "lhu" | "lwu",
+                [Operand::Reg(destination), source],
+            ) => {
+                let size = riscv_memory_size(mnemonic);
+                let address = self.address(state, instruction, source);
+                let mut value = self.load(state, address, size);
+                if mnemonic.ends_with('u') {
+                    value = value.zero_extend(size * 8);
+                }
+                self.write_register(state, destination, value);
+            }
+            ("sb" | "sh" | "sw" | "sd", [source, destination]) => {
+                let value = read(state, source);
+                let address = self.address(state, instruction, destination);
+                self.store(state, address, value, riscv_memory_size(mnemonic));
+            }
+            // "jal offset" and "jalr rs" link in ra, "jal rd, offset" and "jalr rd, rs, offset" in rd
+            ("call" | "jal" | "jalr", operands) => {
+                let link = match operands {
+                    [Operand::Reg(link), _, ..] => link.as_str(),
+                    _ => "ra",
+                };
+                if internal_call || link != "ra" {
+                    let return_address = (instruction.address + instruction.size as u64) as i64;
+                    self.write_register(state, link, Value::constant(return_address));
+                } else {
+                    self.external_call(state, instruction);
+                }
+            }
+            _ => self.clobber(state, instruction),
+        }
+    }
+}
+
+// add, sub, mul, and and the shifts of x86 and RISC-V
+fn binary_operation(mnemonic: &str, a: &Value, b: &Value) -> Value {
+    let operation = mnemonic.trim_end_matches('w').trim_end_matches('i');
+    let amount = match b {
+        Value::Number(b) => b.as_constant(),
+        Value::StackAddress(_) => None,
+    };
+
+    match (operation, amount) {
+        ("add", _) => a.add(b),
+        ("sub", _) => a.sub(b),
+        ("mul" | "imul", _) => a.mul(b),
+        ("and", _) => a.and(b),
+        ("sll" | "shl" | "sal", Some(amount)) => a.map_number(|a| a.shift_left(amount)),
+        ("sra" | "sar", Some(amount)) => a.map_number(|a| a.shift_right(amount)),
+        ("srl" | "shr", Some(amount)) => match a {
+            Value::Number(interval) if interval.is_non_negative() => {
+                Value::Number(interval.shift_right(amount))
+            }
+            _ => Value::TOP,
+        },
+        _ => Value::TOP,
+    }
+}
+
+fn x86_memory_size(op_str: &str) -> Option<u64> {
+    [
+        ("qword ptr", 8),
+        ("dword ptr", 4),
+        ("word ptr", 2),
+        ("byte ptr", 1),
+    ]
+    .iter()
+    .find(|(prefix, _)| op_str.contains(prefix))
+    .map(|(_, size)| *size)
+}
+
+fn riscv_memory_size(mnemonic: &str) -> u64 {
+    match mnemonic.as_bytes().get(1) {
+        Some(b'b') => 1,
+        Some(b'h') => 2,
+        Some(b'w') => 4,
+        _ => 8,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ValueAnalysis {
+    interpreter: Interpreter,
+    // (block_leader, instruction_address) -> values before the instruction, only for the reachable blocks
+    pub states: BTreeMap<(u64, u64), ValueState>,
+    pub instructions: BTreeMap<(u64, u64), String>, // (block_leader, instruction_address) -> text
+}
+
+impl ValueAnalysis {
+    pub fn state_before(&self, block_leader: u64, instruction_address: u64) -> Option<&ValueState> {
+        self.states.get(&(block_leader, instruction_address))
+    }
+
+    // the address of a memory operand of the instruction, None if the block is not reached
+    pub fn address_before(
+        &self,
+        block_leader: u64,
+        instruction: &Instruction,
+        operand: &Operand,
+    ) -> Option<Value> {
+        let state = self.state_before(block_leader, instruction.address)?;
+        Some(self.interpreter.address(state, instruction, operand))
+    }
+
+    // the value read from an operand of the instruction, None if the block is not reached
+    pub fn operand_before(
+        &self,
+        block_leader: u64,
+        instruction: &Instruction,
+        operand: &Operand,
+    ) -> Option<Value> {
+        let state = self.state_before(block_leader, instruction.address)?;
+        let size = self.memory_size(instruction);
+        Some(self.interpreter.read(state, instruction, operand, size))
+    }
+
+    // bytes accessed by the memory operand of the instruction
+    pub fn memory_size(&self, instruction: &Instruction) -> u64 {
+        match self.interpreter.arch {
+            Arch::X86 => x86_memory_size(&instruction.op_str).unwrap_or(self.interpreter.word_size),
+            Arch::RISCV => riscv_memory_size(instruction.mnemonic.trim_start_matches("c.")),
+            _ => self.interpreter.word_size,
+        }
+    }
+
+    // the register that contains a part, e.g. rax for eax
+    pub fn full_register(&self, reg: &str) -> String {
+        self.interpreter.register(reg).0
+    }
+
+    pub fn stack_pointer(&self) -> &'static str {
+        self.interpreter.stack_pointer()
+    }
+}
+
+fn internal_call(block: &Block, instruction: &Instruction) -> bool {
+    matches!(block.exit_jump, Some(ExitJump::Call(_, _)))
+        && block.instructions.last().map(|last| last.address) == Some(instruction.address)
+}
+
+// the state before the last instruction of the block
+fn before_exit(interpreter: &Interpreter, block: &Block, state: &ValueState) -> ValueState {
+    let mut state = state.clone();
+    if let Some((_, instructions)) = block.instructions.split_last() {
+        for instruction in instructions {
+            interpreter.step(&mut state, instruction, false);
+        }
+    }
+    state
+}
+
+fn transfer(interpreter: &Interpreter, block: &Block, state: &ValueState) -> ValueState {
+    let mut state = state.clone();
+    for instruction in &block.instructions {
+        interpreter.step(&mut state, instruction, internal_call(block, instruction));
+    }
+    state
+}
+
+// fixpoint of the values over the graph, widened at the loop heads
+pub fn analyze_values(graph: &MappedGraph, arch_mode: &ArchMode) -> ValueAnalysis {
+    let interpreter = Interpreter::new(arch_mode);
+    let loop_headers = graph.loop_headers();
+
+    let mut entry_state = ValueState::default();
+    entry_state.set(
+        Location::Register(interpreter.stack_pointer().to_string()),
+        Value::StackAddress(Interval::constant(0)),
+    );
+
+    let mut in_states = HashMap::<u64, ValueState>::new(); // block_leader -> state at the block entry
+    let mut out_states = HashMap::<u64, ValueState>::new();
+    let mut joins = HashMap::<u64, u32>::new(); // loop header -> joins
+
+    let nodes = graph.get_nodes();
+    let mut worklist = nodes.iter().cloned().collect::<VecDeque<Block>>();
+
+    let mut call_sites = HashMap::<u64, Block>::new(); // return block_leader -> call block
+    for block in &nodes {
+        if let Some(ExitJump::Call(_, ret)) = block.exit_jump {
+            call_sites.insert(ret, block.clone());
+        }
+    }
+
+    while let Some(block) = worklist.pop_front() {
+        let predecessors = graph.neighbors_directed(&block, Incoming);
+        let in_state = if predecessors.is_empty() {
+            Some(entry_state.clone())
+        } else {
+            predecessors
+                .iter()
+                .filter_map(|predecessor| out_states.get(&predecessor.leader))
+                .fold(None::<ValueState>, |state, out_state| match state {
+                    None => Some(out_state.clone()),
+                    Some(state) => Some(state.join(out_state)),
+                })
+        };
+        // not reached yet
+        let Some(mut in_state) = in_state else {
+            continue;
+        };
+
+        // the return of a call continues from the call site
+        if let Some(call_block) = call_sites.get(&block.leader) {
+            let (Some(call_state), Some(call)) = (
+                in_states.get(&call_block.leader),
+                call_block.instructions.last(),
+            ) else {
+                continue;
+            };
+            let caller = before_exit(&interpreter, call_block, call_state);
+            in_state = interpreter.returned(&caller, &in_state, call);
+        }
+
+        if let Some(old_state) = in_states.get(&block.leader) {
+            if loop_headers.contains(&block.leader) {
+                let count = joins.entry(block.leader).or_default();
+                *count += 1;
+                if *count > WIDENING_DELAY {
+                    in_state = old_state.widen(&old_state.join(&in_state));
+                }
+            }
+        }
+
+        let out_state = transfer(&interpreter, &block, &in_state);
+        in_states.insert(block.leader, in_state);
+
+        if out_states.get(&block.leader) != Some(&out_state) {
+            out_states.insert(block.leader, out_state);
+            let mut successors = graph.neighbors_directed(&block, Outgoing);
+            if let Some(ExitJump::Call(_, ret)) = block.exit_jump {
+                successors.extend(nodes.iter().find(|b| b.leader == ret).cloned());
+            }
+            for successor in successors {
+                if !worklist.iter().any(|b| b.leader == successor.leader) {
+                    worklist.push_back(successor);
+                }
+            }
+        }
+    }
+
+    let mut analysis = ValueAnalysis {
+        interpreter,
+        states: BTreeMap::new(),
+        instructions: BTreeMap::new(),
+    };
+    for block in &nodes {
+        let Some(in_state) = in_states.get(&block.leader) else {
+            continue;
+        };
+        let mut state = in_state.clone();
+        for instruction in &block.instructions {
+            let key = (block.leader, instruction.address);
+            analysis.states.insert(key, state.clone());
+            analysis.instructions.insert(
+                key,
+                format!("{} {}", instruction.mnemonic, instruction.op_str),
+            );
+            interpreter.step(&mut state, instruction, internal_call(block, instruction));
+        }
+    }
+
+    analysis
+}
+
+impl std::fmt::Display for Interval {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(value) = self.as_constant() {
+            return write!(f, "{value}");
+        }
+        let min = match self.min {
+            i64::MIN => "-inf".to_string(),
+            min => min.to_string(),
+        };
+        let max = match self.max {
+            i64::MAX => "+inf".to_string(),
+            max => max.to_string(),
+        };
+        write!(f, "[{min}, {max}]")
+    }
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Number(interval) => write!(f, "{interval}"),
+            Value::StackAddress(offset) => match offset.as_constant() {
+                Some(0) => write!(f, "sp"),
+                Some(offset) if offset < 0 => write!(f, "sp-{}", offset.unsigned_abs()),
+                Some(offset) => write!(f, "sp+{offset}"),
+                None => write!(f, "sp+{offset}"),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Location::Register(reg) => write!(f, "{reg}"),
+            Location::StackSlot { offset, size } => {
+                let address = Value::StackAddress(Interval::constant(*offset));
+                write!(f, "[{address}]:{size}")
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for ValueState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let values = self
+            .values
+            .iter()
+            .map(|(location, value)| format!("{location}={value}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        write!(f, "{values}")
+    }
+}
+
+impl std::fmt::Display for ValueAnalysis {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut current_leader = None;
+        for ((leader, address), state) in &self.states {
+            if current_leader != Some(*leader) {
+                writeln!(f, "Block 0x{leader:x}")?;
+                current_leader = Some(*leader);
+            }
+            let instruction = &self.instructions[&(*leader, *address)];
+            writeln!(f, "  0x{address:x} {instruction:<32} {state}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{block, disassemble, graph, x86_64};
+
+    fn register(state: &ValueState, reg: &str) -> Value {
+        state.get(&Location::Register(reg.to_string()))
+    }
+
+    #[test]
+    fn join_and_widening() {
+        let a = Interval::new(0, 3);
+        assert_eq!(a.join(&Interval::new(5, 7)), Interval::new(0, 7));
+        assert_eq!(a.widen(&Interval::new(0, 3)), a);
+        assert_eq!(a.widen(&Interval::new(0, 4)), Interval::new(0, i64::MAX));
+        assert_eq!(a.widen(&Interval::new(-1, 3)), Interval::new(i64::MIN, 3));
+
+        let stack = Value::StackAddress(Interval::constant(-8));
+        assert_eq!(stack.join(&Value::constant(1)), Value::TOP);
+        assert_eq!(
+            stack.join(&Value::StackAddress(Interval::constant(-16))),
+            Value::StackAddress(Interval::new(-16, -8))
+        );
+    }
+
+    // the states after each instruction
+    fn run(instructions: &[Instruction]) -> Vec<ValueState> {
+        let interpreter = Interpreter::new(&x86_64());
+        let mut state = ValueState::default();
+        instructions
+            .iter()
+            .map(|instruction| {
+                interpreter.step(&mut state, instruction, false);
+                state.clone()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn x86_64_32_bit_writes_are_zero_extended() {
+        // mov eax, -1; mov rbx, rax; cdqe; movsxd rcx, ebx; mov edx, -1; sub edx, 7; mov rsi, rdx; cdq
+        let code = [
+            0xb8, 0xff, 0xff, 0xff, 0xff, 0x48, 0x89, 0xc3, 0x48, 0x98, 0x48, 0x63, 0xcb, 0xba,
+            0xff, 0xff, 0xff, 0xff, 0x83, 0xea, 0x07, 0x48, 0x89, 0xd6, 0x99,
+        ];
+        let instructions = disassemble(&x86_64(), 0x1000, &code);
+        let states = run(&instructions);
+
+        assert_eq!(register(&states[0], "rax"), Value::constant(0xffff_ffff));
+        assert_eq!(register(&states[1], "rbx"), Value::constant(0xffff_ffff));
+        assert_eq!(register(&states[2], "rax"), Value::constant(-1));
+        assert_eq!(register(&states[3], "rcx"), Value::constant(-1));
+        assert_eq!(register(&states[5], "rdx"), Value::constant(0xffff_fff8));
+        let interpreter = Interpreter::new(&x86_64());
+        assert_eq!(
+            interpreter.read_register(&states[5], "edx", &instructions[6]),
+            Value::constant(-8)
+        );
+        assert_eq!(register(&states[6], "rsi"), Value::constant(0xffff_fff8));
+        // the sign of eax in edx
+        assert_eq!(register(&states[7], "rdx"), Value::constant(0xffff_ffff));
+    }
+
+    #[test]
+    fn x86_64_32_bit_overflow_wraps_in_the_zero_extension() {
+        // mov ecx, 0x7fffffff; add ecx, 1; mov edx, ecx; add edx, ecx
+        let code = [
+            0xb9, 0xff, 0xff, 0xff, 0x7f, 0x83, 0xc1, 0x01, 0x89, 0xca, 0x01, 0xca,
+        ];
+        let instructions = disassemble(&x86_64(), 0x1000, &code);
+        let states = run(&instructions);
+
+        assert_eq!(register(&states[1], "rcx"), Value::constant(0x8000_0000));
+        let interpreter = Interpreter::new(&x86_64());
+        assert_eq!(
+            interpreter.read_register(&states[2], "edx", &instructions[3]),
+            Value::constant(i32::MIN as i64)
+        );
+        // -2^31 + -2^31 wraps around to 0
+        assert_eq!(register(&states[3], "rdx"), Value::constant(0));
+    }
+
+    #[test]
+    fn loop_counter_is_widened_and_bounded_by_the_32_bit_write() {
+        // mov ecx, 0; loop: add ecx, 1; cmp ecx, 10; jl loop; ret
+        let code = [
+            0xb9, 0x00, 0x00, 0x00, 0x00, 0x83, 0xc1, 0x01, 0x83, 0xf9, 0x0a, 0x7c, 0xf8, 0xc3,
+        ];
+        let instructions = disassemble(&x86_64(), 0x0, &code);
+        let blocks = [
+            block(instructions[..1].to_vec(), Some(ExitJump::Next(0x5))),
+            block(
+                instructions[1..4].to_vec(),
+                Some(ExitJump::ConditionalRelative {
+                    taken: 0x5,
+                    not_taken: 0xd,
+                }),
+            ),
+            block(instructions[4..].to_vec(), None),
+        ];
+        let analysis = analyze_values(&graph(&blocks), &x86_64());
+
+        let header = analysis.state_before(0x5, 0x5).unwrap();
+        assert_eq!(
+            register(header, "rcx"),
+            Value::Number(Interval::new(0, i64::MAX))
+        );
+        let exit = analysis.state_before(0xd, 0xd).unwrap();
+        assert_eq!(
+            register(exit, "rcx"),
+            Value::Number(Interval::new(0, u32::MAX as i64))
+        );
+        assert_eq!(
+            register(exit, "rsp"),
+            Value::StackAddress(Interval::constant(0))
+        );
+    }
+}